@@ -1,17 +1,25 @@
-use crate::{login, vfs};
+use crate::{health, login, vfs};
 use anyhow::{Context as _, Result};
 use libc::{gid_t, mode_t, uid_t};
+use onedrive_api::{resource::DriveId, DriveLocation};
 use serde::{de::Deserializer, Deserialize};
-use std::{path::Path, time::Duration};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 const DEFAULT_CONFIG: &str = include_str!("../config.default.toml");
+const ENV_PREFIX: &str = "ONEDRIVE_FUSE";
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
+    pub drive: DriveConfig,
+    pub mount: MountConfig,
     pub permission: PermissionConfig,
     pub vfs: vfs::Config,
     pub relogin: login::ReloginConfig,
     pub net: NetConfig,
+    pub health: health::Config,
 }
 
 #[derive(Debug, Deserialize)]
@@ -20,25 +28,232 @@ pub struct NetConfig {
     pub connect_timeout: Duration,
     #[serde(deserialize_with = "de_duration_sec")]
     pub request_timeout: Duration,
+    #[serde(default)]
+    pub rate_limit: login::RateLimitConfig,
+    #[serde(default)]
+    pub proxy: Option<String>,
 }
 
 impl Config {
+    /// Check values which are well-typed but make no sense, naming the offending field.
+    pub fn validate(&self) -> Result<()> {
+        self.mount.validate()?;
+        self.net.validate()?;
+        self.relogin.validate()?;
+        self.health.validate()?;
+        self.vfs.validate()
+    }
+
+    /// Merge settings with precedence from low to high: the default config, the config file,
+    /// environment variables, then `options`.
+    ///
+    /// Environment variables are named after the field path with prefix `ONEDRIVE_FUSE_`, and
+    /// `__` separating path components, like `ONEDRIVE_FUSE_VFS__FILE__MAX_OPEN_HANDLES` for
+    /// `vfs.file.max_open_handles`.
     pub fn merge_from_default(config_path: Option<&Path>, options: &[String]) -> Result<Self> {
-        use config::{File, FileFormat};
+        Self::merge_raw(config_path, options, true)?
+            .try_deserialize()
+            .context("Failed to load configuration")
+    }
+
+    /// Merge the default configuration with `options`, ignoring environment variables, so tests
+    /// don't depend on `ONEDRIVE_FUSE_*` of the environment running them.
+    #[cfg(test)]
+    pub fn merge_for_test(options: &[String]) -> Result<Self> {
+        Self::merge_raw(None, options, false)?
+            .try_deserialize()
+            .context("Failed to load configuration")
+    }
+
+    /// Render the settings merged by `merge_from_default` as TOML, with secrets redacted.
+    pub fn merged_toml(config_path: Option<&Path>, options: &[String]) -> Result<String> {
+        let mut value: toml::Value = Self::merge_raw(config_path, options, true)?
+            .try_deserialize()
+            .context("Failed to load configuration")?;
+        if let Some(proxy) = value.get_mut("net").and_then(|net| net.get_mut("proxy")) {
+            if let Some(url) = proxy.as_str() {
+                *proxy = toml::Value::String(redact_proxy_url(url));
+            }
+        }
+        Ok(toml::to_string_pretty(&value)?)
+    }
+
+    fn merge_raw(
+        config_path: Option<&Path>,
+        options: &[String],
+        env: bool,
+    ) -> Result<config::Config> {
+        use config::{Environment, File, FileFormat};
 
         let mut builder = config::Config::builder();
         builder = builder.add_source(File::from_str(DEFAULT_CONFIG, FileFormat::Toml));
         if let Some(path) = config_path {
             builder = builder.add_source(File::from(path).format(FileFormat::Toml));
         }
+        if env {
+            builder = builder.add_source(
+                Environment::with_prefix(ENV_PREFIX)
+                    .prefix_separator("_")
+                    .separator("__")
+                    .try_parsing(true),
+            );
+        }
         for opt in options {
             // Kind of tricky. Toml can parse option format `a.b="foo"` as expected.
             builder = builder.add_source(File::from_str(opt, FileFormat::Toml));
         }
-        builder
-            .build()
-            .and_then(|conf| conf.try_deserialize())
-            .context("Failed to load configuration")
+        builder.build().context("Failed to load configuration")
+    }
+}
+
+impl NetConfig {
+    fn validate(&self) -> Result<()> {
+        anyhow::ensure!(
+            !self.connect_timeout.is_zero(),
+            "`net.connect_timeout` must be positive",
+        );
+        anyhow::ensure!(
+            !self.request_timeout.is_zero(),
+            "`net.request_timeout` must be positive",
+        );
+        self.proxy()?;
+        self.rate_limit.validate()
+    }
+
+    /// The proxy for all requests if configured. Otherwise `HTTPS_PROXY` and `NO_PROXY` are used.
+    pub fn proxy(&self) -> Result<Option<reqwest::Proxy>> {
+        let Some(url) = &self.proxy else {
+            return Ok(None);
+        };
+        let proxy = reqwest::Proxy::all(url).with_context(|| {
+            format!(
+                "`net.proxy` must be a proxy URL, got {:?}",
+                redact_proxy_url(url)
+            )
+        })?;
+        Ok(Some(proxy.no_proxy(reqwest::NoProxy::from_env())))
+    }
+
+    /// Fail early if the configured proxy is unreachable, instead of on every request.
+    pub async fn check_proxy(&self) -> Result<()> {
+        use tokio::net::{lookup_host, TcpStream};
+
+        let Some(url) = &self.proxy else {
+            return Ok(());
+        };
+        let url = url::Url::parse(url).context("Invalid `net.proxy`")?;
+        let host = url.host_str().context("Missing host in `net.proxy`")?;
+        let port = url
+            .port_or_known_default()
+            .context("Missing port in `net.proxy`")?;
+        let addr = lookup_host((host, port))
+            .await
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .with_context(|| format!("Failed to resolve proxy {}:{}", host, port))?;
+        match tokio::time::timeout(self.connect_timeout, TcpStream::connect(addr)).await {
+            Ok(ret) => {
+                ret.with_context(|| format!("Proxy {}:{} is unreachable", host, port))?;
+            }
+            Err(_) => anyhow::bail!(
+                "Proxy {}:{} is unreachable: connection timed out",
+                host,
+                port
+            ),
+        }
+        Ok(())
+    }
+
+    /// A client builder with the configured timeouts and proxy.
+    pub fn client_builder(&self) -> Result<reqwest::ClientBuilder> {
+        let mut builder = reqwest::ClientBuilder::new()
+            .https_only(true)
+            .connect_timeout(self.connect_timeout);
+        if let Some(proxy) = self.proxy()? {
+            builder = builder.proxy(proxy);
+        }
+        Ok(builder)
+    }
+}
+
+/// Hide the password in a proxy URL.
+fn redact_proxy_url(url: &str) -> String {
+    match url::Url::parse(url) {
+        Ok(mut parsed) if parsed.password().is_some() => {
+            let _ = parsed.set_password(Some("REDACTED"));
+            parsed.into()
+        }
+        _ => url.to_owned(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MountConfig {
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    pub create_mount_point: bool,
+    pub remove_created_mount_point: bool,
+    pub allow_non_empty: bool,
+    pub unmount_stale: bool,
+    pub allow_other: bool,
+    pub allow_root: bool,
+    pub default_permissions: bool,
+    pub concurrent: bool,
+}
+
+impl MountConfig {
+    pub fn validate(&self) -> Result<()> {
+        anyhow::ensure!(
+            !(self.allow_other && self.allow_root),
+            "`mount.allow_other` and `mount.allow_root` are mutually exclusive",
+        );
+        if let Some(path) = &self.path {
+            anyhow::ensure!(
+                path.is_absolute(),
+                "`mount.path` must be an absolute path, got {}",
+                path.display(),
+            );
+        }
+        if (self.allow_other || self.allow_root) && !self.default_permissions {
+            log::warn!(
+                "`mount.default_permissions` is disabled, other users can access all files in the mount"
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The drive to be mounted.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", content = "id", rename_all = "lowercase")]
+pub enum DriveConfig {
+    Me,
+    User(String),
+    Group(String),
+    Site(String),
+    Id(String),
+}
+
+impl DriveConfig {
+    pub fn to_location(&self) -> DriveLocation {
+        match self {
+            Self::Me => DriveLocation::me(),
+            Self::User(id) => DriveLocation::from_user(id.clone()),
+            Self::Group(id) => DriveLocation::from_group(id.clone()),
+            Self::Site(id) => DriveLocation::from_site(id.clone()),
+            Self::Id(id) => DriveLocation::from_id(DriveId(id.clone())),
+        }
+    }
+
+    /// Path segments of the drive in Graph API, like `["me", "drive"]`.
+    pub fn api_path_segments(&self) -> Vec<&str> {
+        match self {
+            Self::Me => vec!["me", "drive"],
+            Self::User(id) => vec!["users", id, "drive"],
+            Self::Group(id) => vec!["groups", id, "drive"],
+            Self::Site(id) => vec!["sites", id, "drive"],
+            Self::Id(id) => vec!["drives", id],
+        }
     }
 }
 
@@ -56,23 +271,38 @@ pub struct PermissionConfig {
     fmask: mode_t,
     #[serde(default)]
     dmask: mode_t,
+    #[serde(default)]
+    file_mode: Option<mode_t>,
+    #[serde(default)]
+    dir_mode: Option<mode_t>,
 }
 
 impl PermissionConfig {
     fn umask(&self) -> mode_t {
+        self.umask | self.readonly_mask()
+    }
+
+    // Write bits are always cleared in readonly mode.
+    fn readonly_mask(&self) -> mode_t {
         if self.readonly {
-            self.umask | 0o222
+            0o222
         } else {
-            self.umask
+            0
         }
     }
 
     pub fn file_permission(&self) -> mode_t {
-        0o666 & !(self.umask() | self.fmask)
+        match self.file_mode {
+            Some(mode) => mode & 0o777 & !self.readonly_mask(),
+            None => 0o666 & !(self.umask() | self.fmask),
+        }
     }
 
     pub fn dir_permission(&self) -> mode_t {
-        0o777 & !(self.umask() | self.dmask)
+        match self.dir_mode {
+            Some(mode) => mode & 0o777 & !self.readonly_mask(),
+            None => 0o777 & !(self.umask() | self.dmask),
+        }
     }
 }
 
@@ -97,3 +327,157 @@ where
 {
     u64::deserialize(de).map(Duration::from_secs)
 }
+
+pub fn de_duration_sec_opt<'de, D>(de: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<u64>::deserialize(de).map(|secs| secs.map(Duration::from_secs))
+}
+
+/// Deserialize a size in bytes, either as a plain integer or a string with an unit suffix,
+/// like `"512 KiB"` or `"16MiB"`.
+pub fn de_byte_size<'de, D, T>(de: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    use serde::de::Error;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ByteSize {
+        Bytes(u64),
+        Str(String),
+    }
+
+    let bytes = match ByteSize::deserialize(de)? {
+        ByteSize::Bytes(bytes) => bytes,
+        ByteSize::Str(s) => parse_byte_size(&s)
+            .ok_or_else(|| D::Error::custom(format!("Invalid byte size: {s:?}")))?,
+    };
+    T::try_from(bytes).map_err(|_| D::Error::custom(format!("Byte size too large: {bytes}")))
+}
+
+fn parse_byte_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let unit_pos = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(unit_pos);
+    let scale: u64 = match unit.trim_start() {
+        "" | "B" => 1,
+        "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "TB" => 1_000_000_000_000,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        "TiB" => 1 << 40,
+        _ => return None,
+    };
+    num.parse::<u64>().ok()?.checked_mul(scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(options: &[&str]) -> Result<()> {
+        let options = options.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        Config::merge_for_test(&options)?.validate()
+    }
+
+    #[test]
+    fn validate_default() {
+        validate(&[]).unwrap();
+    }
+
+    #[test]
+    fn validate_rejected() {
+        for (options, field) in [
+            (
+                &["mount.allow_other=true", "mount.allow_root=true"][..],
+                "`mount.allow_other`",
+            ),
+            (&["mount.path=\"relative\""], "`mount.path`"),
+            (&["net.connect_timeout=0"], "`net.connect_timeout`"),
+            (&["net.request_timeout=0"], "`net.request_timeout`"),
+            (&["net.proxy=\"not a url\""], "`net.proxy`"),
+            (
+                &["net.rate_limit.max_requests_per_sec=0.0"],
+                "`net.rate_limit.max_requests_per_sec`",
+            ),
+            (
+                &["relogin.enable=true", "relogin.check_period=0"],
+                "`relogin.check_period`",
+            ),
+            (&["vfs.root_path=\"Documents\""], "`vfs.root_path`"),
+            (
+                &["vfs.root_path=\"/a\"", "vfs.root_special_folder=\"photos\""],
+                "`vfs.root_special_folder`",
+            ),
+            (
+                &["vfs.root_special_folder=\"nope\""],
+                "`vfs.root_special_folder`",
+            ),
+            (&["vfs.retry.max_attempts=0"], "`vfs.retry.max_attempts`"),
+            (
+                &["vfs.retry.initial_delay=10", "vfs.retry.max_delay=1"],
+                "`vfs.retry.initial_delay`",
+            ),
+            (
+                &["vfs.file.max_open_handles=0"],
+                "`vfs.file.max_open_handles`",
+            ),
+            (
+                &["vfs.file.disk_cache.max_files=0"],
+                "`vfs.file.disk_cache.max_files`",
+            ),
+            (
+                &[
+                    "vfs.file.disk_cache.max_cached_file_size=\"2 GiB\"",
+                    "vfs.file.disk_cache.max_total_size=\"1 GiB\"",
+                ],
+                "`vfs.file.disk_cache.max_cached_file_size`",
+            ),
+            (
+                &["vfs.file.disk_cache.pinned_paths=[\"relative\"]"],
+                "`vfs.file.disk_cache.pinned_paths`",
+            ),
+            (
+                &[
+                    "vfs.file.upload.retry_delay=10",
+                    "vfs.file.upload.max_retry_delay=1",
+                ],
+                "`vfs.file.upload.retry_delay`",
+            ),
+            (
+                &["vfs.inode.escape_chars=\"/\""],
+                "`vfs.inode.escape_chars`",
+            ),
+            (
+                &["vfs.inode.symlink_suffix=\"a/b\""],
+                "`vfs.inode.symlink_suffix`",
+            ),
+            (&["vfs.tracker.period=0"], "`vfs.tracker.period`"),
+            (
+                &["vfs.thumbnail.cache_count=0"],
+                "`vfs.thumbnail.cache_count`",
+            ),
+        ] {
+            let err = validate(options).expect_err(field);
+            let msg = format!("{:#}", err);
+            assert!(msg.contains(field), "{:?}: {}", options, msg);
+        }
+    }
+
+    #[test]
+    fn byte_size() {
+        assert_eq!(parse_byte_size("42"), Some(42));
+        assert_eq!(parse_byte_size(" 2 KiB "), Some(2048));
+        assert_eq!(parse_byte_size("1MB"), Some(1_000_000));
+        assert_eq!(parse_byte_size("1 PiB"), None);
+        assert_eq!(parse_byte_size("MiB"), None);
+        assert_eq!(parse_byte_size("99999999999 TiB"), None);
+    }
+}
@@ -0,0 +1,85 @@
+//! Detaching from the terminal, and reporting readiness to the waiting parent.
+use anyhow::{Context as _, Result};
+use nix::{
+    fcntl::OFlag,
+    unistd::{dup2, fork, pipe2, setsid, ForkResult},
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::Path,
+    sync::Mutex as SyncMutex,
+};
+
+/// The pipe to the parent, which is waiting for the mount to be ready.
+static READY_PIPE: SyncMutex<Option<File>> = SyncMutex::new(None);
+
+/// Fork into background and detach from the terminal.
+///
+/// The parent process only returns when the child calls `notify_ready`, or exits with failure
+/// if the child exits before that. Outputs of the child go to `log_file`, or are discarded.
+/// It must be called before any thread is spawned.
+pub fn daemonize(log_file: Option<&Path>) -> Result<()> {
+    // Open it before forking, so errors are reported in the terminal.
+    let output = match log_file {
+        Some(path) => open_log_file(path)?,
+        None => File::options().write(true).open("/dev/null")?,
+    };
+    let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC).context("Failed to create pipe")?;
+    // SAFETY: Fds are just created and exclusively owned.
+    let (mut read_pipe, write_pipe) = unsafe {
+        (
+            File::from(OwnedFd::from_raw_fd(read_fd)),
+            File::from(OwnedFd::from_raw_fd(write_fd)),
+        )
+    };
+
+    // SAFETY: No other threads are running yet.
+    match unsafe { fork() }.context("Failed to fork")? {
+        ForkResult::Parent { child } => {
+            drop(write_pipe);
+            let mut buf = [0u8; 1];
+            // EOF if the child exits before ready.
+            if matches!(read_pipe.read(&mut buf), Ok(1)) {
+                std::process::exit(0);
+            }
+            eprintln!(
+                "Daemon process {child} exited before the filesystem is mounted. See logs for details",
+            );
+            std::process::exit(1);
+        }
+        ForkResult::Child => {
+            drop(read_pipe);
+            setsid().context("Failed to create session")?;
+            let null = File::open("/dev/null")?;
+            dup2(null.as_raw_fd(), libc::STDIN_FILENO)?;
+            dup2(output.as_raw_fd(), libc::STDOUT_FILENO)?;
+            dup2(output.as_raw_fd(), libc::STDERR_FILENO)?;
+            *READY_PIPE.lock().unwrap() = Some(write_pipe);
+            Ok(())
+        }
+    }
+}
+
+/// Write logs to `path` instead of the terminal, staying in foreground.
+pub fn redirect_stderr(path: &Path) -> Result<()> {
+    let file = open_log_file(path)?;
+    dup2(file.as_raw_fd(), libc::STDERR_FILENO)?;
+    Ok(())
+}
+
+fn open_log_file(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open log file {}", path.display()))
+}
+
+/// Tell the parent that the filesystem is mounted, if daemonized. Later calls have no effect.
+pub fn notify_ready() {
+    if let Some(mut pipe) = READY_PIPE.lock().unwrap().take() {
+        let _ = pipe.write_all(&[1]);
+    }
+}
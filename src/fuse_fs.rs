@@ -1,11 +1,18 @@
-use crate::{config::PermissionConfig, vfs};
+use crate::{config::PermissionConfig, daemon, health, vfs};
 use fuser::{
     FileAttr, FileType, KernelConfig, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
-    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, Request, TimeOrNow,
+    ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr,
+    Request, TimeOrNow,
+};
+use std::{
+    convert::TryFrom as _,
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt as _,
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+    time::{Instant, SystemTime},
 };
-use std::{convert::TryFrom as _, ffi::OsStr, sync::Arc, time::SystemTime};
 
-const GENERATION: u64 = 0;
 const NAME_LEN: u32 = 2048;
 const BLOCK_SIZE: u32 = 512;
 const FRAGMENT_SIZE: u32 = 512;
@@ -14,6 +21,7 @@ const READDIR_CHUNK_SIZE: usize = 64;
 
 pub struct Filesystem {
     inner: Arc<FilesystemInner>,
+    concurrent: bool,
 }
 
 struct FilesystemInner {
@@ -22,19 +30,33 @@ struct FilesystemInner {
 }
 
 impl Filesystem {
-    pub fn new(vfs: Arc<vfs::Vfs>, perm_config: PermissionConfig) -> Self {
+    pub fn new(vfs: Arc<vfs::Vfs>, perm_config: PermissionConfig, concurrent: bool) -> Self {
         Self {
             inner: Arc::new(FilesystemInner { vfs, perm_config }),
+            concurrent,
         }
     }
 
-    fn spawn<F, Fut>(&self, f: F)
+    /// Run a FUSE operation. API requests issued by it are logged with its `unique` id,
+    /// and its latency is logged under target `fuse`.
+    fn spawn<F, Fut>(&self, op: &'static str, req: &Request, f: F)
     where
         F: FnOnce(Arc<FilesystemInner>) -> Fut,
         Fut: std::future::Future<Output = ()> + Send + 'static,
     {
-        let inner = self.inner.clone();
-        tokio::task::spawn(f(inner));
+        let unique = req.unique();
+        let fut = f(self.inner.clone());
+        let fut = vfs::op::scope(unique, async move {
+            let start = Instant::now();
+            fut.await;
+            log::trace!(target: "fuse", "[op {}] {}: done in {:?}", unique, op, start.elapsed());
+        });
+        if self.concurrent {
+            tokio::task::spawn(fut);
+        } else {
+            // We are on the blocking thread of the FUSE session.
+            tokio::runtime::Handle::current().block_on(fut);
+        }
     }
 }
 
@@ -48,13 +70,13 @@ impl FilesystemInner {
             mtime: attr.mtime,
             ctime: attr.mtime, // No info.
             crtime: attr.crtime,
-            kind: if attr.is_directory {
-                FileType::Directory
-            } else {
-                FileType::RegularFile
-            },
-            perm: if attr.is_directory {
+            kind: file_type(&attr),
+            perm: if attr.is_symlink {
+                0o777
+            } else if attr.is_directory {
                 self.perm_config.dir_permission()
+            } else if attr.locked || attr.package.is_some() {
+                self.perm_config.file_permission() & !0o222
             } else {
                 self.perm_config.file_permission()
             } as _,
@@ -66,25 +88,88 @@ impl FilesystemInner {
             flags: 0,
         }
     }
+
+    /// Check `access(2)` mask against the presented mode and ownership.
+    fn check_access(&self, attr: &FileAttr, uid: u32, gid: u32, mask: i32) -> Result<(), i32> {
+        if mask & libc::W_OK != 0 && self.perm_config.readonly {
+            return Err(libc::EROFS);
+        }
+        let perm = u32::from(attr.perm);
+        let granted = if uid == 0 {
+            // Root can read and write anything, but only execute if any execute bit is set.
+            let exec = if attr.kind == FileType::Directory || perm & 0o111 != 0 {
+                libc::X_OK
+            } else {
+                0
+            };
+            libc::R_OK | libc::W_OK | exec
+        } else if uid == attr.uid {
+            (perm >> 6) as i32 & 0o7
+        } else if gid == attr.gid {
+            (perm >> 3) as i32 & 0o7
+        } else {
+            perm as i32 & 0o7
+        };
+        let mask = mask & (libc::R_OK | libc::W_OK | libc::X_OK);
+        if mask & !granted != 0 {
+            return Err(libc::EACCES);
+        }
+        Ok(())
+    }
+}
+
+/// Send invalidations to the kernel through the FUSE session.
+pub struct KernelInvalidator(pub fuser::Notifier);
+
+impl vfs::Invalidator for KernelInvalidator {
+    fn inval_inode(&self, ino: u64) {
+        // Inodes forgotten by the kernel meanwhile report `ENOENT`, which is fine.
+        if let Err(err) = self.0.inval_inode(ino, 0, 0) {
+            log::debug!("Failed to invalidate inode {}: {}", ino, err);
+        }
+    }
+
+    fn inval_entry(&self, parent_ino: u64, name: &str) {
+        if let Err(err) = self.0.inval_entry(parent_ino, OsStr::new(name)) {
+            log::debug!(
+                "Failed to invalidate entry {:?} of {}: {}",
+                name,
+                parent_ino,
+                err
+            );
+        }
+    }
 }
 
 impl fuser::Filesystem for Filesystem {
     fn init(
         &mut self,
         _req: &Request,
-        _config: &mut KernelConfig,
+        config: &mut KernelConfig,
     ) -> std::result::Result<(), libc::c_int> {
+        use fuser::consts::{FUSE_DO_READDIRPLUS, FUSE_READDIRPLUS_AUTO};
+
+        // Attributes are always available along with directory entries.
+        if config
+            .add_capabilities(FUSE_DO_READDIRPLUS | FUSE_READDIRPLUS_AUTO)
+            .is_err()
+        {
+            log::debug!("readdirplus is not supported by the kernel");
+        }
         log::info!("FUSE initialized");
+        health::MOUNTED.store(true, Ordering::Relaxed);
         let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]);
+        daemon::notify_ready();
         Ok(())
     }
 
     fn destroy(&mut self) {
+        health::MOUNTED.store(false, Ordering::Relaxed);
         log::info!("FUSE destroyed");
     }
 
-    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
-        self.spawn(|inner| async move {
+    fn statfs(&mut self, req: &Request, _ino: u64, reply: ReplyStatfs) {
+        self.spawn("statfs", req, |inner| async move {
             match inner.vfs.statfs().await {
                 Err(err) => reply.error(err.into_c_err()),
                 Ok(vfs::StatfsData { total, free }) => reply.statfs(
@@ -101,27 +186,37 @@ impl fuser::Filesystem for Filesystem {
         });
     }
 
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         let name = name.to_owned();
-        self.spawn(|inner| async move {
+        self.spawn("lookup", req, |inner| async move {
             match inner.vfs.lookup(parent, &name).await {
                 Err(err) => reply.error(err.into_c_err()),
-                Ok((ino, attr, ttl)) => {
+                Ok((ino, generation, attr, ttl)) => {
                     let attr = inner.cvt_attr(ino, attr);
-                    reply.entry(&ttl, &attr, GENERATION);
+                    reply.entry(&ttl, &attr, generation);
                 }
             }
         });
     }
 
-    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
-        self.spawn(|inner| async move {
+    fn forget(&mut self, req: &Request, ino: u64, nlookup: u64) {
+        self.spawn("forget", req, |inner| async move {
             inner.vfs.forget(ino, nlookup).await.unwrap();
         });
     }
 
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        self.spawn(|inner| async move {
+    fn batch_forget(&mut self, req: &Request, nodes: &[fuser::fuse_forget_one]) {
+        let nodes = nodes
+            .iter()
+            .map(|node| (node.nodeid, node.nlookup))
+            .collect::<Vec<_>>();
+        self.spawn("batch_forget", req, |inner| async move {
+            inner.vfs.batch_forget(&nodes).await.unwrap();
+        });
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        self.spawn("getattr", req, |inner| async move {
             match inner.vfs.get_attr(ino).await {
                 Err(err) => reply.error(err.into_c_err()),
                 Ok((attr, ttl)) => {
@@ -132,13 +227,65 @@ impl fuser::Filesystem for Filesystem {
         });
     }
 
-    fn access(&mut self, _req: &Request, _ino: u64, _mask: i32, reply: ReplyEmpty) {
-        reply.ok();
+    fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let name = name.to_owned();
+        self.spawn("getxattr", req, |inner| async move {
+            match inner.vfs.get_xattr(ino, &name).await {
+                Err(err) => reply.error(err.into_c_err()),
+                Ok(value) => reply_xattr(reply, size, &value),
+            }
+        });
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        _flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let name = name.to_owned();
+        let value = value.to_owned();
+        self.spawn("setxattr", req, |inner| async move {
+            match inner.vfs.set_xattr(ino, &name, &value).await {
+                Err(err) => reply.error(err.into_c_err()),
+                Ok(()) => reply.ok(),
+            }
+        });
+    }
+
+    fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        self.spawn("listxattr", req, |inner| async move {
+            match inner.vfs.list_xattr(ino).await {
+                Err(err) => reply.error(err.into_c_err()),
+                Ok(names) => reply_xattr(reply, size, &names),
+            }
+        });
     }
 
-    fn opendir(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
+    // Only called without `default_permissions`, where the kernel does not check modes itself.
+    fn access(&mut self, req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
+        let (uid, gid) = (req.uid(), req.gid());
+        self.spawn("access", req, |inner| async move {
+            match inner.vfs.get_attr(ino).await {
+                Err(err) => reply.error(err.into_c_err()),
+                Ok((attr, _)) => {
+                    let attr = inner.cvt_attr(ino, attr);
+                    match inner.check_access(&attr, uid, gid, mask) {
+                        Err(err) => reply.error(err),
+                        Ok(()) => reply.ok(),
+                    }
+                }
+            }
+        });
+    }
+
+    fn opendir(&mut self, req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
         // FIXME: Check flags?
-        self.spawn(|inner| async move {
+        self.spawn("opendir", req, |inner| async move {
             match inner.vfs.open_dir(ino).await {
                 Err(err) => reply.error(err.into_c_err()),
                 Ok(fh) => reply.opened(fh, 0),
@@ -146,8 +293,8 @@ impl fuser::Filesystem for Filesystem {
         });
     }
 
-    fn releasedir(&mut self, _req: &Request, ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
-        self.spawn(|inner| async move {
+    fn releasedir(&mut self, req: &Request, ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        self.spawn("releasedir", req, |inner| async move {
             inner.vfs.close_dir(ino, fh).await.unwrap();
             reply.ok();
         });
@@ -155,14 +302,14 @@ impl fuser::Filesystem for Filesystem {
 
     fn readdir(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         fh: u64,
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
         let offset = u64::try_from(offset).unwrap();
-        self.spawn(|inner| async move {
+        self.spawn("readdir", req, |inner| async move {
             match inner
                 .vfs
                 .read_dir(ino, fh, offset, READDIR_CHUNK_SIZE)
@@ -170,20 +317,11 @@ impl fuser::Filesystem for Filesystem {
             {
                 Err(err) => reply.error(err.into_c_err()),
                 Ok(entries) => {
-                    for (idx, entry) in entries.as_ref().iter().enumerate() {
-                        let next_offset = offset
-                            .checked_add(u64::try_from(idx).unwrap())
-                            .unwrap()
-                            .checked_add(1)
-                            .unwrap();
-                        let kind = if entry.attr.is_directory {
-                            FileType::Directory
-                        } else {
-                            FileType::RegularFile
-                        };
+                    for entry in entries.as_ref() {
+                        let kind = file_type(&entry.attr);
                         // Inode id here is useless and further `lookup` will still be called.
                         // But it still need to be not zero.
-                        if reply.add(u64::MAX, next_offset as i64, kind, &entry.name) {
+                        if reply.add(u64::MAX, entry.next_offset as i64, kind, &entry.name) {
                             break;
                         }
                     }
@@ -193,18 +331,67 @@ impl fuser::Filesystem for Filesystem {
         });
     }
 
-    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+    fn readdirplus(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        let offset = u64::try_from(offset).unwrap();
+        self.spawn("readdirplus", req, |inner| async move {
+            match inner
+                .vfs
+                .read_dir_plus(ino, fh, offset, READDIR_CHUNK_SIZE)
+                .await
+            {
+                Err(err) => reply.error(err.into_c_err()),
+                Ok(entries) => {
+                    let mut entries = entries.into_iter();
+                    for (child_ino, generation, entry, ttl) in entries.by_ref() {
+                        let attr = inner.cvt_attr(child_ino, entry.attr);
+                        if reply.add(
+                            child_ino,
+                            entry.next_offset as i64,
+                            &entry.name,
+                            &ttl,
+                            &attr,
+                            generation,
+                        ) {
+                            inner.vfs.forget(child_ino, 1).await.unwrap();
+                            break;
+                        }
+                    }
+                    // The kernel only counts lookups for entries actually sent.
+                    for (child_ino, ..) in entries {
+                        inner.vfs.forget(child_ino, 1).await.unwrap();
+                    }
+                    reply.ok();
+                }
+            }
+        });
+    }
+
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
         // Read is always allowed.
         static_assertions::const_assert_eq!(libc::O_RDONLY, 0);
         log::trace!("open flags: {:#x}", flags);
 
-        let write = (flags & libc::O_WRONLY) != 0;
+        // `O_RDWR` also needs a writable handle.
+        let write = (flags & libc::O_ACCMODE) != libc::O_RDONLY;
+        // Without `FUSE_ATOMIC_O_TRUNC`, the kernel truncates by `setattr` instead.
         assert_eq!(flags & libc::O_TRUNC, 0);
-        let ret_flags = flags & libc::O_WRONLY;
+        let mut ret_flags = (flags & libc::O_WRONLY) as u32;
+        // Reads with `O_DIRECT` skip the page cache and all our caches, to get the remote content.
+        let direct = !write && (flags & libc::O_DIRECT) != 0;
+        if direct {
+            ret_flags |= fuser::consts::FOPEN_DIRECT_IO;
+        }
 
-        self.spawn(|inner| async move {
-            match inner.vfs.open_file(ino, write).await {
-                Ok(fh) => reply.opened(fh, ret_flags as u32),
+        self.spawn("open", req, |inner| async move {
+            match inner.vfs.open_file(ino, write, direct).await {
+                Ok(fh) => reply.opened(fh, ret_flags),
                 Err(err) => reply.error(err.into_c_err()),
             }
         });
@@ -212,7 +399,7 @@ impl fuser::Filesystem for Filesystem {
 
     fn create(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         _mode: u32,
@@ -228,15 +415,15 @@ impl fuser::Filesystem for Filesystem {
         let ret_flags = flags & (libc::O_WRONLY | libc::O_EXCL | libc::O_TRUNC);
 
         let name = name.to_owned();
-        self.spawn(|inner| async move {
+        self.spawn("create", req, |inner| async move {
             match inner
                 .vfs
                 .open_create_file(parent, &name, truncate, exclusive)
                 .await
             {
-                Ok((ino, fh, attr, ttl)) => {
+                Ok((ino, generation, fh, attr, ttl)) => {
                     let attr = inner.cvt_attr(ino, attr);
-                    reply.created(&ttl, &attr, GENERATION, fh, ret_flags as u32)
+                    reply.created(&ttl, &attr, generation, fh, ret_flags as u32)
                 }
                 Err(err) => reply.error(err.into_c_err()),
             }
@@ -245,7 +432,7 @@ impl fuser::Filesystem for Filesystem {
 
     fn release(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         fh: u64,
         _flags: i32,
@@ -253,7 +440,7 @@ impl fuser::Filesystem for Filesystem {
         _flush: bool,
         reply: ReplyEmpty,
     ) {
-        self.spawn(|inner| async move {
+        self.spawn("release", req, |inner| async move {
             match inner.vfs.close_file(ino, fh).await {
                 Ok(()) => reply.ok(),
                 Err(err) => reply.error(err.into_c_err()),
@@ -263,7 +450,7 @@ impl fuser::Filesystem for Filesystem {
 
     fn read(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         fh: u64,
         offset: i64,
@@ -274,7 +461,7 @@ impl fuser::Filesystem for Filesystem {
     ) {
         let offset = u64::try_from(offset).unwrap();
         let size = usize::try_from(size).unwrap();
-        self.spawn(|inner| async move {
+        self.spawn("read", req, |inner| async move {
             match inner.vfs.read_file(ino, fh, offset, size).await {
                 Ok(data) => {
                     let data = data.as_ref();
@@ -287,7 +474,7 @@ impl fuser::Filesystem for Filesystem {
 
     fn mkdir(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         _mode: u32,
@@ -295,41 +482,80 @@ impl fuser::Filesystem for Filesystem {
         reply: ReplyEntry,
     ) {
         let name = name.to_owned();
-        self.spawn(|inner| async move {
+        self.spawn("mkdir", req, |inner| async move {
             match inner.vfs.create_dir(parent, &name).await {
-                Ok((ino, attr, ttl)) => {
+                Ok((ino, generation, attr, ttl)) => {
+                    let attr = inner.cvt_attr(ino, attr);
+                    reply.entry(&ttl, &attr, generation)
+                }
+                Err(err) => reply.error(err.into_c_err()),
+            }
+        });
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        let link_name = link_name.to_owned();
+        let target = target.as_os_str().as_bytes().to_vec();
+        self.spawn("symlink", req, |inner| async move {
+            match inner.vfs.create_symlink(parent, &link_name, &target).await {
+                Ok((ino, generation, attr, ttl)) => {
                     let attr = inner.cvt_attr(ino, attr);
-                    reply.entry(&ttl, &attr, GENERATION)
+                    reply.entry(&ttl, &attr, generation)
                 }
                 Err(err) => reply.error(err.into_c_err()),
             }
         });
     }
 
+    fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
+        self.spawn("readlink", req, |inner| async move {
+            match inner.vfs.read_symlink(ino).await {
+                Ok(target) => reply.data(&target),
+                Err(err) => reply.error(err.into_c_err()),
+            }
+        });
+    }
+
     fn rename(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         newparent: u64,
         newname: &OsStr,
-        _flags: u32,
+        flags: u32,
         reply: ReplyEmpty,
     ) {
-        // TODO: Handle flags.
+        // `RENAME_EXCHANGE` and `RENAME_WHITEOUT` are not supported.
+        if flags & !libc::RENAME_NOREPLACE != 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let no_replace = flags & libc::RENAME_NOREPLACE != 0;
         let name = name.to_owned();
         let newname = newname.to_owned();
-        self.spawn(|inner| async move {
-            match inner.vfs.rename(parent, &name, newparent, &newname).await {
+        self.spawn("rename", req, |inner| async move {
+            match inner
+                .vfs
+                .rename(parent, &name, newparent, &newname, no_replace)
+                .await
+            {
                 Ok(_) => reply.ok(),
                 Err(err) => reply.error(err.into_c_err()),
             }
         });
     }
 
-    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let name = name.to_owned();
-        self.spawn(|inner| async move {
+        self.spawn("rmdir", req, |inner| async move {
             match inner.vfs.remove_dir(parent, &name).await {
                 Ok(()) => reply.ok(),
                 Err(err) => reply.error(err.into_c_err()),
@@ -337,9 +563,9 @@ impl fuser::Filesystem for Filesystem {
         });
     }
 
-    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let name = name.to_owned();
-        self.spawn(|inner| async move {
+        self.spawn("unlink", req, |inner| async move {
             match inner.vfs.remove_file(parent, &name).await {
                 Ok(()) => reply.ok(),
                 Err(err) => reply.error(err.into_c_err()),
@@ -349,19 +575,25 @@ impl fuser::Filesystem for Filesystem {
 
     fn write(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         fh: u64,
         offset: i64,
         data: &[u8],
         _write_flags: u32,
-        _flags: i32,
+        flags: i32,
         _lock_owner: Option<u64>,
         reply: ReplyWrite,
     ) {
         let data = data.to_owned();
-        self.spawn(|inner| async move {
-            match inner.vfs.write_file(ino, fh, offset as u64, &data).await {
+        // `flags` are of the handle. The kernel's offset is based on a possibly stale size.
+        let append = flags & libc::O_APPEND != 0;
+        self.spawn("write", req, |inner| async move {
+            match inner
+                .vfs
+                .write_file(ino, fh, offset as u64, &data, append)
+                .await
+            {
                 // > Write should return exactly the number of bytes requested except on error.
                 Ok(()) => reply.written(data.len() as u32),
                 Err(err) => reply.error(err.into_c_err()),
@@ -371,7 +603,7 @@ impl fuser::Filesystem for Filesystem {
 
     fn setattr(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
         _mode: Option<u32>,
         _uid: Option<u32>,
@@ -387,7 +619,7 @@ impl fuser::Filesystem for Filesystem {
         _flags: Option<u32>,
         reply: ReplyAttr,
     ) {
-        self.spawn(|inner| async move {
+        self.spawn("setattr", req, |inner| async move {
             let mtime = mtime.map(|time| match time {
                 TimeOrNow::SpecificTime(time) => time,
                 TimeOrNow::Now => SystemTime::now(),
@@ -414,8 +646,18 @@ impl fuser::Filesystem for Filesystem {
         reply.ok();
     }
 
-    fn fsync(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
-        self.spawn(|inner| async move {
+    fn flush(&mut self, req: &Request, ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        self.spawn("flush", req, |inner| async move {
+            match inner.vfs.flush_file(ino).await {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.into_c_err()),
+            }
+        });
+    }
+
+    // Content and mtime are uploaded together, so `datasync` makes no difference.
+    fn fsync(&mut self, req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        self.spawn("fsync", req, |inner| async move {
             match inner.vfs.sync_file(ino).await {
                 Ok(()) => reply.ok(),
                 Err(err) => reply.error(err.into_c_err()),
@@ -424,8 +666,31 @@ impl fuser::Filesystem for Filesystem {
     }
 }
 
+/// The type of an item in attributes and directory entries.
+/// Packages like OneNote notebooks are presented as opaque regular files.
+fn file_type(attr: &vfs::InodeAttr) -> FileType {
+    if attr.is_directory {
+        FileType::Directory
+    } else if attr.is_symlink {
+        FileType::Symlink
+    } else {
+        FileType::RegularFile
+    }
+}
+
+/// Reply the size only if `size` is 0, or the data if it fits.
+fn reply_xattr(reply: ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() <= size as usize {
+        reply.data(data);
+    } else {
+        reply.error(libc::ERANGE);
+    }
+}
+
 fn to_blocks_ceil(bytes: u64) -> u64 {
-    (bytes + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64
+    bytes.div_ceil(BLOCK_SIZE as u64)
 }
 
 fn to_blocks_floor(bytes: u64) -> u64 {
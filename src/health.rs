@@ -0,0 +1,81 @@
+//! HTTP health and readiness probes for service managers and orchestrators.
+use crate::{login::ManagedOnedrive, vfs};
+use anyhow::{anyhow, Context as _, Result};
+use serde::Deserialize;
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    enable: bool,
+    listen: String,
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<()> {
+        if self.enable {
+            self.listen.parse::<SocketAddr>().with_context(|| {
+                format!(
+                    "`health.listen` must be an address like `127.0.0.1:8080`, got {:?}",
+                    self.listen
+                )
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the filesystem is mounted and initialized by the kernel.
+pub static MOUNTED: AtomicBool = AtomicBool::new(false);
+
+/// Serve probes in background if enabled:
+/// - `/health` always responds 200 once the process is running.
+/// - `/ready` responds 200 if mounted with a valid token, or 503 otherwise.
+///
+/// Both return the status in JSON.
+pub fn spawn_server(config: &Config, onedrive: ManagedOnedrive) -> Result<()> {
+    use tiny_http::{Header, Response, Server};
+
+    if !config.enable {
+        return Ok(());
+    }
+    let server = Server::http(&config.listen).map_err(|err| {
+        anyhow!(
+            "Failed to listen on {} for health probes: {err}",
+            config.listen
+        )
+    })?;
+    log::info!("Serving health probes at {}", config.listen);
+
+    std::thread::spawn(move || {
+        for req in server.incoming_requests() {
+            let mounted = MOUNTED.load(Ordering::Relaxed);
+            let token_valid = onedrive.token_valid();
+            let status = serde_json::json!({
+                "mounted": mounted,
+                "token_valid": token_valid,
+                "last_api_success": vfs::last_api_success()
+                    .map(|time| humantime::format_rfc3339_seconds(time).to_string()),
+                "last_sync_age_secs": vfs::last_sync()
+                    .map(|time| time.elapsed().unwrap_or_default().as_secs()),
+            });
+            let code = match req.url() {
+                "/health" => 200,
+                "/ready" if mounted && token_valid => 200,
+                "/ready" => 503,
+                _ => {
+                    let _ = req.respond(Response::empty(404));
+                    continue;
+                }
+            };
+            let header = Header::from_bytes("content-type", "application/json").unwrap();
+            let resp = Response::from_string(status.to_string())
+                .with_status_code(code)
+                .with_header(header);
+            let _ = req.respond(resp);
+        }
+    });
+    Ok(())
+}
@@ -1,18 +1,24 @@
-use crate::config::de_duration_sec;
+use crate::config::{de_duration_sec, DriveConfig};
 use anyhow::{ensure, Context as _, Result};
 use onedrive_api::{Auth, DriveLocation, OneDrive, Permission};
+use reqwest::Url;
 use serde::{Deserialize, Serialize};
 use std::{
     fs,
+    num::NonZeroUsize,
+    ops::Deref,
     path::{Path, PathBuf},
-    sync::{Arc, Weak},
-    time::{Duration, SystemTime},
+    sync::{Arc, Mutex as SyncMutex, Weak},
+    time::{Duration, Instant, SystemTime},
 };
 use tokio::{
     self,
-    sync::{RwLock, RwLockReadGuard},
+    sync::{Notify, RwLock, RwLockReadGuard, Semaphore, SemaphorePermit},
 };
 
+/// The Graph API endpoint, the same one `onedrive_api` sends requests to.
+const API_ENDPOINT: &str = "https://graph.microsoft.com/v1.0";
+
 #[derive(Debug, Deserialize)]
 pub struct ReloginConfig {
     enable: bool,
@@ -24,9 +30,117 @@ pub struct ReloginConfig {
     min_live_time: Duration,
 }
 
+#[derive(Debug, Default, Deserialize)]
+pub struct RateLimitConfig {
+    #[serde(default)]
+    max_requests_per_sec: Option<f64>,
+    #[serde(default)]
+    max_burst: Option<NonZeroUsize>,
+    #[serde(default)]
+    max_concurrent_requests: Option<NonZeroUsize>,
+}
+
+impl RateLimitConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(rate) = self.max_requests_per_sec {
+            ensure!(
+                rate.is_finite() && rate > 0.0,
+                "`net.rate_limit.max_requests_per_sec` must be positive, got {}",
+                rate,
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Client-side limits of API requests, shared by all clones of `ManagedOnedrive`.
+struct RateLimiter {
+    /// Token bucket of (available tokens, last refill time).
+    bucket: Option<SyncMutex<(f64, Instant)>>,
+    rate: f64,
+    burst: f64,
+    concurrency: Option<Semaphore>,
+}
+
+impl RateLimiter {
+    fn new(config: RateLimitConfig) -> Self {
+        let rate = config.max_requests_per_sec;
+        let burst = config.max_burst.map_or(1, NonZeroUsize::get) as f64;
+        Self {
+            bucket: rate.map(|_| SyncMutex::new((burst, Instant::now()))),
+            rate: rate.unwrap_or(0.0),
+            burst,
+            concurrency: config
+                .max_concurrent_requests
+                .map(|n| Semaphore::new(n.get())),
+        }
+    }
+
+    async fn acquire(&self) -> Option<SemaphorePermit<'_>> {
+        if let Some(bucket) = &self.bucket {
+            loop {
+                let wait = {
+                    let mut bucket = bucket.lock().unwrap();
+                    let (tokens, last) = &mut *bucket;
+                    let now = Instant::now();
+                    *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate)
+                        .min(self.burst);
+                    *last = now;
+                    if *tokens >= 1.0 {
+                        *tokens -= 1.0;
+                        break;
+                    }
+                    Duration::from_secs_f64((1.0 - *tokens) / self.rate)
+                };
+                tokio::time::sleep(wait).await;
+            }
+        }
+        match &self.concurrency {
+            Some(sema) => Some(sema.acquire().await.expect("Never closed")),
+            None => None,
+        }
+    }
+}
+
+impl ReloginConfig {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            !self.enable || !self.check_period.is_zero(),
+            "`relogin.check_period` must be positive",
+        );
+        Ok(())
+    }
+}
+
 #[derive(Clone)]
 pub struct ManagedOnedrive {
     onedrive: Arc<RwLock<OneDrive>>,
+    relogin_notify: Arc<Notify>,
+    limiter: Arc<RateLimiter>,
+    /// When the current access token expires.
+    expire_time: Arc<SyncMutex<SystemTime>>,
+    /// The API URL of the mounted drive.
+    drive_url: Arc<Url>,
+}
+
+/// The `OneDrive` client holding a permit of concurrent requests, if limited.
+pub struct OnedriveGuard<'a> {
+    onedrive: RwLockReadGuard<'a, OneDrive>,
+    _permit: Option<SemaphorePermit<'a>>,
+}
+
+impl Deref for OnedriveGuard<'_> {
+    type Target = OneDrive;
+
+    fn deref(&self) -> &OneDrive {
+        &self.onedrive
+    }
+}
+
+impl std::fmt::Debug for ManagedOnedrive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ManagedOnedrive").finish_non_exhaustive()
+    }
 }
 
 impl ManagedOnedrive {
@@ -34,6 +148,8 @@ impl ManagedOnedrive {
         client: reqwest::Client,
         credential_file: PathBuf,
         config: ReloginConfig,
+        drive: &DriveConfig,
+        rate_limit: RateLimitConfig,
         mount_readonly: bool,
     ) -> Result<Self> {
         log::info!("Logining...");
@@ -44,10 +160,12 @@ impl ManagedOnedrive {
             !cred.readonly || mount_readonly,
             "Cannot mount as read-write using read-only token. Please re-login to grant read-write permission.",
         );
-        let auth = Auth::new(
+        let auth = Auth::new_with_client(
+            client.clone(),
             cred.client_id.clone(),
             Permission::new_read()
                 .write(!cred.readonly)
+                .access_shared(cred.access_shared)
                 .offline_access(true),
             cred.redirect_uri.clone(),
         );
@@ -63,31 +181,60 @@ impl ManagedOnedrive {
         cred.save(&credential_file)?;
         log::info!("New credential saved");
 
+        let drive_url = Arc::new(drive_url(drive));
+        let drive = drive.to_location();
         let onedrive = Arc::new(RwLock::new(OneDrive::new_with_client(
-            client,
+            client.clone(),
             resp.access_token,
-            DriveLocation::me(),
+            drive.clone(),
         )));
+        let relogin_notify = Arc::new(Notify::new());
+        let expire_time = Arc::new(SyncMutex::new(
+            SystemTime::now() + Duration::from_secs(resp.expires_in_secs),
+        ));
 
         if config.enable {
             tokio::spawn(Self::relogin_thread(
                 Arc::downgrade(&onedrive),
-                auth,
-                cred,
-                credential_file,
+                relogin_notify.clone(),
+                expire_time.clone(),
+                (client, drive),
+                (auth, cred, credential_file),
                 Duration::from_secs(resp.expires_in_secs),
                 config,
             ));
         }
 
-        Ok(Self { onedrive })
+        Ok(Self {
+            onedrive,
+            relogin_notify,
+            limiter: Arc::new(RateLimiter::new(rate_limit)),
+            expire_time,
+            drive_url,
+        })
+    }
+
+    /// A client with a dummy token and without relogin, for tests never reaching the server.
+    #[cfg(test)]
+    pub fn new_for_test() -> Self {
+        Self {
+            onedrive: Arc::new(RwLock::new(OneDrive::new(
+                "token".to_owned(),
+                DriveLocation::me(),
+            ))),
+            relogin_notify: Arc::new(Notify::new()),
+            limiter: Arc::new(RateLimiter::new(RateLimitConfig::default())),
+            expire_time: Arc::new(SyncMutex::new(SystemTime::now())),
+            drive_url: Arc::new(drive_url(&DriveConfig::Me)),
+        }
     }
 
     async fn relogin_thread(
         weak: Weak<RwLock<OneDrive>>,
-        auth: Auth,
-        mut cred: Credential,
-        credential_file: PathBuf,
+        relogin_notify: Arc<Notify>,
+        expire_time: Arc<SyncMutex<SystemTime>>,
+        (client, drive): (reqwest::Client, DriveLocation),
+        (auth, mut cred, credential_file): (Auth, Credential, PathBuf),
         initial_expire_time: Duration,
         config: ReloginConfig,
     ) {
@@ -101,9 +248,23 @@ impl ManagedOnedrive {
             humantime::Timestamp::from(relogin_inst),
         );
 
+        let mut last_login_time = login_time;
         loop {
-            tokio::time::sleep(config.check_period).await;
+            let requested = tokio::time::timeout(config.check_period, relogin_notify.notified())
+                .await
+                .is_ok();
+            // Requested relogins are deferred until `min_live_time` after the last one, since
+            // requests using the old token may still be failing right after a relogin.
+            if requested {
+                relogin_inst = relogin_inst.min(last_login_time + config.min_live_time);
+            }
             if SystemTime::now() < relogin_inst {
+                if requested {
+                    log::info!(
+                        "Relogin requested, deferred until {}",
+                        humantime::Timestamp::from(relogin_inst),
+                    );
+                }
                 continue;
             }
 
@@ -124,12 +285,15 @@ impl ManagedOnedrive {
                 Ok(resp) => resp,
             };
             let login_time = SystemTime::now();
+            last_login_time = login_time;
             relogin_inst = std::cmp::max(
                 login_time + Duration::from_secs(resp.expires_in_secs) - config.time_before_expire,
                 login_time + config.min_live_time,
             );
 
-            *onedrive.write().await = OneDrive::new(resp.access_token, DriveLocation::me());
+            *onedrive.write().await =
+                OneDrive::new_with_client(client.clone(), resp.access_token, drive.clone());
+            *expire_time.lock().unwrap() = login_time + Duration::from_secs(resp.expires_in_secs);
 
             log::info!(
                 "Relogined. Next relogin will happen after {}",
@@ -147,14 +311,56 @@ impl ManagedOnedrive {
         }
     }
 
-    pub async fn get(&self) -> RwLockReadGuard<'_, OneDrive> {
-        self.onedrive.read().await
+    /// Get the client, waiting for the rate limit if configured.
+    /// The guard should be held for a single request only, and never across retries, since
+    /// it consumes a request of the rate limit and blocks relogin.
+    pub async fn get(&self) -> OnedriveGuard<'_> {
+        let permit = self.limiter.acquire().await;
+        OnedriveGuard {
+            onedrive: self.onedrive.read().await,
+            _permit: permit,
+        }
+    }
+
+    /// Wait for the rate limit without taking the client, for requests to pre-authenticated
+    /// URLs like upload sessions. The returned permit should be held until the request finishes.
+    pub async fn throttle(&self) -> Option<SemaphorePermit<'_>> {
+        self.limiter.acquire().await
+    }
+
+    /// The API URL of `segments` under the mounted drive, like `items/{id}/createLink`,
+    /// for requests not supported by `onedrive_api`. Segments are percent-encoded.
+    pub fn drive_api_url(&self, segments: &[&str]) -> Url {
+        let mut url = (*self.drive_url).clone();
+        url.path_segments_mut().unwrap().extend(segments);
+        url
     }
+
+    /// Whether the current access token is not expired yet.
+    pub fn token_valid(&self) -> bool {
+        SystemTime::now() < *self.expire_time.lock().unwrap()
+    }
+
+    /// Relogin as soon as possible, since the current token is rejected.
+    /// Concurrent requests are merged into a single relogin.
+    pub fn request_relogin(&self) {
+        self.relogin_notify.notify_one();
+    }
+}
+
+fn drive_url(drive: &DriveConfig) -> Url {
+    let mut url = Url::parse(API_ENDPOINT).unwrap();
+    url.path_segments_mut()
+        .unwrap()
+        .extend(drive.api_path_segments());
+    url
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Credential {
     pub readonly: bool,
+    #[serde(default)]
+    pub access_shared: bool,
     pub client_id: String,
     pub redirect_uri: String,
     pub refresh_token: String,
@@ -172,7 +378,7 @@ impl Credential {
         let parent = path.parent().context("Invalid credential path")?;
         fs::create_dir_all(parent)?;
 
-        let tmp_path = if path.extension().map_or(false, |ext| ext == "tmp") {
+        let tmp_path = if path.extension().is_some_and(|ext| ext == "tmp") {
             path.with_extension("_tmp")
         } else {
             path.with_extension("tmp")
@@ -1,19 +1,27 @@
 use crate::login::ManagedOnedrive;
-use anyhow::{anyhow, Context as _, Result};
+use anyhow::{anyhow, bail, Context as _, Result};
 use clap::{Args, Parser};
 use fuser::MountOption;
 use onedrive_api::{Auth, Permission, TokenResponse};
-use std::path::PathBuf;
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use url::Url;
 
 mod config;
+mod daemon;
 mod fuse_fs;
+mod health;
 mod login;
+mod mount_point;
 mod paths;
+mod signal;
 mod vfs;
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     let default_hook = std::panic::take_hook();
     std::panic::set_hook(Box::new(move |info| {
         default_hook(info);
@@ -21,13 +29,30 @@ async fn main() -> Result<()> {
         std::process::exit(101);
     }));
 
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
-
     let opt: Opt = Opt::parse();
-    match opt {
-        Opt::Login(opt) => main_login(opt).await,
-        Opt::Mount(opt) => main_mount(opt).await,
+    // Forking must be done before the runtime spawns threads.
+    if let Opt::Mount(opt) = &opt {
+        if opt.print_config {
+            return print_config(opt);
+        }
+        if opt.daemon {
+            daemon::daemonize(opt.log_file.as_deref())?;
+        } else if let Some(path) = &opt.log_file {
+            daemon::redirect_stderr(path)?;
+        }
     }
+
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    rt.block_on(async {
+        match opt {
+            Opt::Login(opt) => main_login(opt).await,
+            Opt::Mount(opt) => main_mount(opt).await,
+        }
+    })
 }
 
 const REDIRECT_URI: &str = "http://localhost:0/onedrive-fuse-login";
@@ -39,6 +64,15 @@ async fn main_login(opt: OptLogin) -> Result<()> {
         .or_else(paths::default_credential_path)
         .context("No credential file provided to save to")?;
 
+    // Only network settings are used.
+    let config = config::Config::merge_from_default(opt.config.as_deref(), &opt.option)?;
+    config.net.check_proxy().await?;
+    let client = config
+        .net
+        .client_builder()?
+        .timeout(config.net.request_timeout)
+        .build()?;
+
     let perm = if opt.read_write {
         "READONLY"
     } else {
@@ -48,15 +82,19 @@ async fn main_login(opt: OptLogin) -> Result<()> {
 
     let perm = Permission::new_read()
         .write(opt.read_write)
+        .access_shared(opt.access_shared)
         .offline_access(true);
 
     let tokens = if let Some(code) = &opt.code {
         eprintln!("Logining...");
-        let auth = Auth::new(opt.client_id.clone(), perm, REDIRECT_URI.to_owned());
+        let auth =
+            Auth::new_with_client(client, opt.client_id.clone(), perm, REDIRECT_URI.to_owned());
         auth.login_with_code(code, None).await?
+    } else if opt.device_code {
+        login_with_device_code(&client, &opt.client_id, opt.read_write, opt.access_shared).await?
     } else {
         let client_id = opt.client_id.clone();
-        tokio::task::spawn_blocking(|| login_with_http_server(client_id, perm)).await??
+        tokio::task::spawn_blocking(|| login_with_http_server(client, client_id, perm)).await??
     };
 
     let refresh_token = tokens.refresh_token.expect("Missing refresh token");
@@ -65,6 +103,7 @@ async fn main_login(opt: OptLogin) -> Result<()> {
 
     login::Credential {
         readonly: !opt.read_write,
+        access_shared: opt.access_shared,
         client_id: opt.client_id,
         redirect_uri: REDIRECT_URI.to_owned(),
         refresh_token,
@@ -75,7 +114,101 @@ async fn main_login(opt: OptLogin) -> Result<()> {
     Ok(())
 }
 
-fn login_with_http_server(client_id: String, perm: Permission) -> Result<TokenResponse> {
+/// Login on another device by entering a code shown here, polling until it's done.
+/// See: https://learn.microsoft.com/en-us/entra/identity-platform/v2-oauth2-device-code
+async fn login_with_device_code(
+    client: &reqwest::Client,
+    client_id: &str,
+    write: bool,
+    access_shared: bool,
+) -> Result<TokenResponse> {
+    const DEVICE_CODE_URL: &str = "https://login.microsoftonline.com/common/oauth2/v2.0/devicecode";
+    const TOKEN_URL: &str = "https://login.microsoftonline.com/common/oauth2/v2.0/token";
+
+    #[derive(Deserialize)]
+    struct DeviceCodeResponse {
+        device_code: String,
+        message: String,
+        expires_in: u64,
+        interval: u64,
+    }
+
+    #[derive(Deserialize)]
+    struct ErrorResponse {
+        error: String,
+        #[serde(default)]
+        error_description: String,
+    }
+
+    async fn post<T: DeserializeOwned>(
+        client: &reqwest::Client,
+        url: &str,
+        form: &[(&str, &str)],
+    ) -> Result<Result<T, ErrorResponse>> {
+        let resp = client.post(url).form(form).send().await?;
+        let status = resp.status();
+        let body = resp.bytes().await?;
+        if status.is_success() {
+            return Ok(Ok(serde_json::from_slice(&body)?));
+        }
+        let err = serde_json::from_slice(&body)
+            .with_context(|| format!("Unexpected response with status {status}"))?;
+        Ok(Err(err))
+    }
+
+    // Same as `Permission` with `offline_access`, which does not expose it.
+    let scope = format!(
+        "{}{} offline_access",
+        if write {
+            "files.readwrite"
+        } else {
+            "files.read"
+        },
+        if access_shared { ".all" } else { "" },
+    );
+    let code: DeviceCodeResponse = post(
+        client,
+        DEVICE_CODE_URL,
+        &[("client_id", client_id), ("scope", &scope)],
+    )
+    .await?
+    .map_err(|err| anyhow!("{}: {}", err.error, err.error_description))?;
+    eprintln!("{}", code.message);
+
+    let deadline = Instant::now() + Duration::from_secs(code.expires_in);
+    let mut interval = Duration::from_secs(code.interval);
+    loop {
+        tokio::time::sleep(interval).await;
+        if deadline <= Instant::now() {
+            bail!("The code is expired before login");
+        }
+        let ret = post(
+            client,
+            TOKEN_URL,
+            &[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+                ("client_id", client_id),
+                ("device_code", &code.device_code),
+            ],
+        )
+        .await?;
+        match ret {
+            Ok(tokens) => {
+                eprintln!("Logining...");
+                return Ok(tokens);
+            }
+            Err(err) if err.error == "authorization_pending" => {}
+            Err(err) if err.error == "slow_down" => interval += Duration::from_secs(5),
+            Err(err) => bail!("Login failed: {}: {}", err.error, err.error_description),
+        }
+    }
+}
+
+fn login_with_http_server(
+    client: reqwest::Client,
+    client_id: String,
+    perm: Permission,
+) -> Result<TokenResponse> {
     use reqwest::StatusCode;
     use std::io::Cursor;
     use tiny_http::{Header, Response, Server};
@@ -91,7 +224,7 @@ fn login_with_http_server(client_id: String, perm: Permission) -> Result<TokenRe
         listen_addr.port(),
         HTTP_SERVER_PATH
     );
-    let auth = Auth::new(client_id, perm, redirect_uri);
+    let auth = Auth::new_with_client(client, client_id, perm, redirect_uri);
     let auth_url = auth.code_auth_url();
 
     let _ = open::that(&auth_url);
@@ -155,6 +288,18 @@ Your browser should be opened with the login page. If not, please manually open
     }
 }
 
+/// Load and validate the configuration like `main_mount`, then print it without mounting.
+fn print_config(opt: &OptMount) -> Result<()> {
+    let config = config::Config::merge_from_default(opt.config.as_deref(), &opt.option)?;
+    config.validate()?;
+    // Credentials are kept in a separated file, and the proxy password is redacted.
+    print!(
+        "{}",
+        config::Config::merged_toml(opt.config.as_deref(), &opt.option)?
+    );
+    Ok(())
+}
+
 async fn main_mount(opt: OptMount) -> Result<()> {
     let credential_path = opt
         .credential
@@ -162,22 +307,33 @@ async fn main_mount(opt: OptMount) -> Result<()> {
         .context("No credential file provided")?;
 
     let config = config::Config::merge_from_default(opt.config.as_deref(), &opt.option)?;
+    config.validate()?;
     let readonly = config.permission.readonly;
-
-    let client = reqwest::ClientBuilder::new()
+    let mount_path = opt
+        .mount_point
+        .or_else(|| config.mount.path.clone())
+        .context("No mount point provided in the command line or `mount.path`")?;
+    config.net.check_proxy().await?;
+
+    let client = config
+        .net
+        .client_builder()?
         .redirect(reqwest::redirect::Policy::none())
         .gzip(true)
-        .https_only(true)
-        .connect_timeout(config.net.connect_timeout)
         .timeout(config.net.request_timeout)
         .build()?;
-    let unlimit_client = reqwest::ClientBuilder::new()
-        .https_only(true)
-        .connect_timeout(config.net.connect_timeout)
-        .build()?;
-
-    let onedrive =
-        ManagedOnedrive::login(client, credential_path, config.relogin, readonly).await?;
+    let unlimit_client = config.net.client_builder()?.build()?;
+
+    let onedrive = ManagedOnedrive::login(
+        client,
+        credential_path,
+        config.relogin,
+        &config.drive,
+        config.net.rate_limit,
+        readonly,
+    )
+    .await?;
+    health::spawn_server(&config.health, onedrive.clone())?;
     let vfs = vfs::Vfs::new(
         fuser::FUSE_ROOT_ID,
         readonly,
@@ -189,9 +345,8 @@ async fn main_mount(opt: OptMount) -> Result<()> {
     .context("Failed to initialize vfs")?;
 
     log::info!("Mounting...");
-    let fuse_options = [
+    let mut fuse_options = vec![
         MountOption::FSName("onedrive".into()),
-        MountOption::DefaultPermissions, // Check permission in the kernel.
         MountOption::NoDev,
         MountOption::NoSuid,
         MountOption::NoAtime,
@@ -206,9 +361,36 @@ async fn main_mount(opt: OptMount) -> Result<()> {
             MountOption::RW
         },
     ];
-    let fs = fuse_fs::Filesystem::new(vfs, config.permission);
-    tokio::task::spawn_blocking(move || fuser::mount2(fs, &opt.mount_point, &fuse_options))
-        .await??;
+    if config.mount.default_permissions {
+        // Check permission in the kernel.
+        fuse_options.push(MountOption::DefaultPermissions);
+    }
+    if config.mount.allow_other {
+        fuse_options.push(MountOption::AllowOther);
+    }
+    if config.mount.allow_root {
+        fuse_options.push(MountOption::AllowRoot);
+    }
+    let fs = fuse_fs::Filesystem::new(vfs.clone(), config.permission, config.mount.concurrent);
+    // Removed on every return from now on, if it's created.
+    let mount_point = mount_point::prepare(&mount_path, &config.mount)?;
+    let mut session = fuser::Session::new(fs, &mount_path, &fuse_options)?;
+    vfs.set_invalidator(Arc::new(fuse_fs::KernelInvalidator(session.notifier())));
+    let mut unmounter = session.unmount_callable();
+    let terminated = signal::wait_termination()?;
+    let mut running = tokio::task::spawn_blocking(move || session.run());
+    tokio::select! {
+        ret = &mut running => ret??,
+        () = terminated => {
+            log::info!("Terminating, unmounting...");
+            unmounter.unmount()?;
+            running.await??;
+        }
+    }
+
+    // No more operations can come in now.
+    vfs.shutdown().await;
+    drop(mount_point);
     Ok(())
 }
 
@@ -249,15 +431,33 @@ struct OptLogin {
     #[arg(short = 'w', long)]
     read_write: bool,
 
+    /// Request for permission to files shared with you and drives other than your own,
+    /// which is required to mount group or SharePoint drives.
+    #[arg(long)]
+    access_shared: bool,
+
     /// Whether to disable listening on `localhost` for login callback. This will require manually
     /// type the redirected URI to the terminal after login in browser.
     /// Only use this when you are unable to interact with browser on the same compuler (host).
     #[arg(long)]
     no_listen: bool,
 
+    /// Login with a code entered on another device, instead of a browser on this one.
+    /// It requires "Allow public client flows" enabled for the client in Azure.
+    #[arg(long, conflicts_with = "code")]
+    device_code: bool,
+
     /// The login code for Code-Auth.
     /// If not provided, the program will do interactive login.
     code: Option<String>,
+
+    /// Config file to override default settings. Only `net` settings like `net.proxy` are used.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Options to override default settings, like those of `mount`.
+    #[arg(short, long)]
+    option: Vec<String>,
 }
 
 #[derive(Debug, Args)]
@@ -271,6 +471,9 @@ EXAMPLES:
 
     # Modify some default settings.
     onedrive-fuse mount -o permission.umask=0o077 -o relogin.enable=false ~/mnt
+
+    # Check the effective settings.
+    onedrive-fuse mount --config /path/to/config.toml --print-config
 ")]
 struct OptMount {
     /// Secret credential file to login OneDrive account.
@@ -279,15 +482,34 @@ struct OptMount {
     credential: Option<PathBuf>,
 
     /// Config file to override default settings.
-    /// Setting from `--option` has highest priority, followed by `--config`, then the default setting.
+    /// Setting from `--option` has highest priority, followed by environment variables,
+    /// `--config`, then the default setting.
     #[arg(long)]
     config: Option<PathBuf>,
 
-    /// Mount point.
-    mount_point: PathBuf,
+    /// Mount point. Default to be `mount.path` in the config.
+    mount_point: Option<PathBuf>,
+
+    /// Run in background once the filesystem is mounted, instead of in foreground.
+    /// Exit with failure if it fails to mount.
+    #[arg(long)]
+    daemon: bool,
+
+    /// Print the effective settings after merging and validating them, then exit without
+    /// mounting.
+    #[arg(long, conflicts_with = "daemon")]
+    print_config: bool,
+
+    /// Append logs to this file instead of stderr.
+    /// Logs of `--daemon` are discarded without it.
+    #[arg(long)]
+    log_file: Option<PathBuf>,
 
     /// Options to override default settings.
-    /// Setting from `--option` has highest priority, followed by `--config`, then the default setting.
+    /// Setting from `--option` has highest priority, followed by environment variables,
+    /// `--config`, then the default setting.
+    /// Environment variables are like `ONEDRIVE_FUSE_PERMISSION__READONLY=false`
+    /// for `permission.readonly`.
     #[arg(short, long)]
     option: Vec<String>,
 }
@@ -0,0 +1,101 @@
+//! Lifecycle of the mount point directory.
+use crate::config::MountConfig;
+use anyhow::{bail, Context as _, Result};
+use std::{fs, io, path::Path, process::Command};
+
+/// A checked mount point. It's removed on drop, if it is created by us and configured so.
+pub struct MountPoint<'a> {
+    path: &'a Path,
+    remove: bool,
+}
+
+impl Drop for MountPoint<'_> {
+    fn drop(&mut self) {
+        if !self.remove {
+            return;
+        }
+        // Only empty directories are removed, so nothing is lost if it is reused meanwhile.
+        match fs::remove_dir(self.path) {
+            Ok(()) => log::info!("Removed mount point {}", self.path.display()),
+            Err(err) => log::warn!(
+                "Failed to remove mount point {}: {}",
+                self.path.display(),
+                err
+            ),
+        }
+    }
+}
+
+/// Check the mount point before mounting, creating it if configured.
+pub fn prepare<'a>(path: &'a Path, config: &MountConfig) -> Result<MountPoint<'a>> {
+    let created = check_or_create(path, config)?;
+    Ok(MountPoint {
+        path,
+        remove: created && config.remove_created_mount_point,
+    })
+}
+
+/// Return whether the mount point is created by us.
+fn check_or_create(path: &Path, config: &MountConfig) -> Result<bool> {
+    let meta = match fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            if !config.create_mount_point {
+                bail!(
+                    "Mount point {} does not exist. Set `mount.create_mount_point` to create it",
+                    path.display(),
+                );
+            }
+            fs::create_dir_all(path)
+                .with_context(|| format!("Failed to create mount point {}", path.display()))?;
+            log::info!("Created mount point {}", path.display());
+            return Ok(true);
+        }
+        Err(err) if err.raw_os_error() == Some(libc::ENOTCONN) => {
+            unmount_stale(path, config)?;
+            fs::metadata(path)
+                .with_context(|| format!("Failed to access mount point {}", path.display()))?
+        }
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to access mount point {}", path.display()))
+        }
+    };
+    if !meta.is_dir() {
+        bail!("Mount point {} is not a directory", path.display());
+    }
+    nix::unistd::access(path, nix::unistd::AccessFlags::W_OK)
+        .with_context(|| format!("Mount point {} is not writable", path.display()))?;
+    if !config.allow_non_empty {
+        let mut entries = fs::read_dir(path)
+            .with_context(|| format!("Failed to read mount point {}", path.display()))?;
+        if entries.next().is_some() {
+            bail!(
+                "Mount point {} is not empty. Set `mount.allow_non_empty` to mount over it",
+                path.display(),
+            );
+        }
+    }
+    Ok(false)
+}
+
+// Accessing a mount point whose FUSE process has died fails with ENOTCONN.
+fn unmount_stale(path: &Path, config: &MountConfig) -> Result<()> {
+    if !config.unmount_stale {
+        bail!(
+            "Mount point {} is a stale mount. Unmount it with `fusermount -u`, or set `mount.unmount_stale`",
+            path.display(),
+        );
+    }
+    log::warn!("Unmounting stale mount at {}", path.display());
+    for program in ["fusermount3", "fusermount"] {
+        match Command::new(program).arg("-uz").arg(path).status() {
+            Ok(status) if status.success() => return Ok(()),
+            Ok(status) => bail!("Failed to unmount stale mount with {program}: {status}"),
+            // Try the next one.
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err).with_context(|| format!("Failed to run {program}")),
+        }
+    }
+    bail!("Neither fusermount3 nor fusermount is found to unmount the stale mount")
+}
@@ -0,0 +1,27 @@
+//! Waiting for termination signals.
+use std::{future::Future, io};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Listen to `SIGINT` and `SIGTERM`, and return a future resolving on the first one.
+/// Following signals terminate the process immediately.
+///
+/// Nothing is left running if the future is dropped, so the runtime can shut down after an
+/// external unmount.
+pub fn wait_termination() -> io::Result<impl Future<Output = ()>> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    Ok(async move {
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+        }
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = interrupt.recv() => {}
+                _ = terminate.recv() => {}
+            }
+            log::warn!("Terminated again, exiting immediately");
+            std::process::exit(1);
+        });
+    })
+}
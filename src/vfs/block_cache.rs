@@ -0,0 +1,154 @@
+//! In-memory cache of blocks of streamed files, shared by all files.
+use crate::config::de_byte_size;
+use bytes::{Bytes, BytesMut};
+use lru_cache::LruCache;
+use onedrive_api::{ItemId, Tag};
+use serde::{de::Deserializer, Deserialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex as SyncMutex},
+};
+
+pub const BLOCK_SIZE: u64 = 256 << 10;
+
+// Blocks are keyed with CTag, so outdated ones are never hit. They're purged once changes are
+// synced.
+type BlockKey = (ItemId, Tag, u64);
+
+#[derive(Debug)]
+pub struct BlockCache {
+    /// Max total bytes of blocks. Zero disables the cache.
+    budget: u64,
+    inner: SyncMutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    lru: LruCache<BlockKey, Bytes>,
+    total_size: u64,
+}
+
+impl BlockCache {
+    fn new(budget: u64) -> Self {
+        Self {
+            budget,
+            inner: SyncMutex::new(Inner {
+                // Bounded by bytes instead.
+                lru: LruCache::new(usize::MAX),
+                total_size: 0,
+            }),
+        }
+    }
+
+    /// Get `offset..end` of a file if all blocks covering it are cached.
+    pub fn get(&self, item_id: &ItemId, c_tag: &Tag, offset: u64, end: u64) -> Option<Bytes> {
+        if self.budget == 0 || end <= offset {
+            return None;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let mut ret = BytesMut::with_capacity((end - offset) as usize);
+        for idx in offset / BLOCK_SIZE..end.div_ceil(BLOCK_SIZE) {
+            let block = inner.lru.get_mut(&(item_id.clone(), c_tag.clone(), idx))?;
+            let block_start = idx * BLOCK_SIZE;
+            let l = offset.saturating_sub(block_start) as usize;
+            let r = ((end - block_start) as usize).min(block.len());
+            if r < l {
+                return None;
+            }
+            ret.extend_from_slice(&block[l..r]);
+        }
+        // The last block is short.
+        (ret.len() as u64 == end - offset).then(|| ret.freeze())
+    }
+
+    pub fn contains(&self, item_id: &ItemId, c_tag: &Tag, idx: u64) -> bool {
+        self.budget != 0
+            && self
+                .inner
+                .lock()
+                .unwrap()
+                .lru
+                .contains_key(&(item_id.clone(), c_tag.clone(), idx))
+    }
+
+    /// Insert a block, which is `BLOCK_SIZE` long except for the last block of the file.
+    pub fn insert(&self, item_id: &ItemId, c_tag: &Tag, idx: u64, data: Bytes) {
+        if self.budget < data.len() as u64 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.total_size += data.len() as u64;
+        if let Some(old) = inner
+            .lru
+            .insert((item_id.clone(), c_tag.clone(), idx), data)
+        {
+            inner.total_size -= old.len() as u64;
+        }
+        while self.budget < inner.total_size {
+            let (_, evicted) = inner.lru.remove_lru().expect("Not empty");
+            inner.total_size -= evicted.len() as u64;
+        }
+    }
+
+    /// Drop blocks of files not matching their current CTags, or `None` for deleted files.
+    pub fn purge_outdated(&self, current: &HashMap<ItemId, Option<Tag>>) {
+        if self.budget == 0 || current.is_empty() {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let outdated = inner
+            .lru
+            .iter()
+            .filter(|((item_id, c_tag, _), _)| {
+                matches!(current.get(item_id), Some(cur) if cur.as_ref() != Some(c_tag))
+            })
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in outdated {
+            let block = inner.lru.remove(&key).expect("Exists");
+            inner.total_size -= block.len() as u64;
+        }
+    }
+}
+
+/// Deserialize a byte budget into a shared `BlockCache`, so clones of the config share the cache.
+pub fn de_block_cache<'de, D>(de: D) -> Result<Arc<BlockCache>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Budget(#[serde(deserialize_with = "de_byte_size")] u64);
+
+    let Budget(budget) = Budget::deserialize(de)?;
+    Ok(Arc::new(BlockCache::new(budget)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn purge_outdated() {
+        let cache = BlockCache::new(BLOCK_SIZE * 4);
+        let (a, b, c) = (
+            ItemId("a".to_owned()),
+            ItemId("b".to_owned()),
+            ItemId("c".to_owned()),
+        );
+        let (old, new) = (Tag("old".to_owned()), Tag("new".to_owned()));
+        let block = Bytes::from_static(b"data");
+        cache.insert(&a, &old, 0, block.clone());
+        cache.insert(&a, &new, 0, block.clone());
+        cache.insert(&b, &old, 0, block.clone());
+        cache.insert(&c, &old, 0, block.clone());
+
+        let current = HashMap::from([(a.clone(), Some(new.clone())), (b.clone(), None)]);
+        cache.purge_outdated(&current);
+        assert!(!cache.contains(&a, &old, 0));
+        assert!(cache.contains(&a, &new, 0));
+        assert!(!cache.contains(&b, &old, 0));
+        // Not synced.
+        assert!(cache.contains(&c, &old, 0));
+        assert_eq!(cache.inner.lock().unwrap().total_size, 8);
+    }
+}
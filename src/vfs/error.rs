@@ -22,16 +22,40 @@ pub enum Error {
     Invalidated,
     #[error("File is uploading, you cannot move or remove it")]
     Uploading,
+    #[error("Permission denied")]
+    PermissionDenied,
+    #[error("Insufficient storage")]
+    NoSpace,
+    #[error("No such extended attribute")]
+    NoAttribute,
+    #[error("Read-only file system")]
+    ReadOnly,
+    #[error("Too many open files")]
+    TooManyOpenFiles,
+    #[error("Operation not supported")]
+    NotSupported,
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("Network is unreachable, only cached data is available")]
+    Offline,
 
     // Api and network errors.
     #[error("Api error: {0}")]
     Api(onedrive_api::Error),
+    #[error("Throttled by server: {0}")]
+    Throttled(onedrive_api::Error),
     #[error("Deserialization error: {0}")]
     Deserialize(#[from] serde_json::Error),
+    #[error("Invalid response: {0:#}")]
+    InvalidResponse(anyhow::Error),
     #[error("reqwest error: {0}")]
-    Reqwest(#[from] reqwest::Error),
+    Reqwest(reqwest::Error),
+    #[error("Request timed out: {0}")]
+    TimedOut(reqwest::Error),
     #[error("Download failed")]
     DownloadFailed,
+    #[error("Upload failed")]
+    UploadFailed,
 
     // IO error.
     #[error("IO error: {0}")]
@@ -62,12 +86,43 @@ impl From<onedrive_api::Error> for Error {
         match err.status_code() {
             Some(StatusCode::NOT_FOUND) => Self::NotFound,
             Some(StatusCode::CONFLICT) => Self::FileExists,
+            Some(StatusCode::FORBIDDEN) => Self::PermissionDenied,
+            Some(StatusCode::INSUFFICIENT_STORAGE) => Self::NoSpace,
+            Some(StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE) => {
+                Self::Throttled(err)
+            }
             _ => Self::Api(err),
         }
     }
 }
 
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            Self::TimedOut(err)
+        } else {
+            Self::Reqwest(err)
+        }
+    }
+}
+
 impl Error {
+    /// Check the status of a response of requests not supported by `onedrive_api`.
+    pub async fn check_response(resp: reqwest::Response) -> Result<reqwest::Response> {
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(resp);
+        }
+        let body = resp.text().await.unwrap_or_default();
+        Err(match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Self::PermissionDenied,
+            StatusCode::NOT_FOUND => Self::NotFound,
+            StatusCode::INSUFFICIENT_STORAGE => Self::NoSpace,
+            StatusCode::BAD_REQUEST => Self::InvalidArgument(body),
+            st => Self::InvalidResponse(anyhow::anyhow!("{}: {}", st, body)),
+        })
+    }
+
     pub fn into_c_err(self) -> libc::c_int {
         match &self {
             // User errors.
@@ -78,25 +133,51 @@ impl Error {
             Self::FileExists => libc::EEXIST,
             Self::Invalidated => libc::EPERM,
             Self::Uploading => libc::ETXTBSY,
-            Self::InvalidFileName(_) => {
+            Self::PermissionDenied => libc::EACCES,
+            Self::NoSpace => libc::ENOSPC,
+            Self::NoAttribute => libc::ENODATA,
+            Self::ReadOnly => libc::EROFS,
+            Self::NotSupported => libc::ENOTSUP,
+            Self::Offline => libc::ENETDOWN,
+            Self::TooManyOpenFiles => {
+                log::warn!("{}", self);
+                libc::ENFILE
+            }
+            Self::InvalidFileName(_) | Self::InvalidArgument(_) => {
                 log::info!("{}", self);
                 libc::EINVAL
             }
 
             // Network errors.
-            Self::Api(_) | Self::Deserialize(_) | Self::Reqwest(_) | Self::Io(_) => {
+            Self::Api(_)
+            | Self::Deserialize(_)
+            | Self::InvalidResponse(_)
+            | Self::Reqwest(_)
+            | Self::Io(_) => {
                 log::error!("{}", self);
                 log::debug!("{:?}", self);
                 libc::EIO
             }
+            Self::Throttled(_) => {
+                log::error!("{}", self);
+                libc::EAGAIN
+            }
+            Self::TimedOut(_) => {
+                log::error!("{}", self);
+                libc::ETIMEDOUT
+            }
             // Already reported.
-            Self::DownloadFailed => libc::EIO,
+            Self::DownloadFailed | Self::UploadFailed => libc::EIO,
 
             // Not supported
-            Self::NonsequentialRead { .. } | Self::FileTooLarge | Self::WriteWithoutCache => {
+            Self::NonsequentialRead { .. } | Self::WriteWithoutCache => {
                 log::info!("{}", self);
                 libc::EPERM
             }
+            Self::FileTooLarge => {
+                log::info!("{}", self);
+                libc::EFBIG
+            }
 
             // Fuse errors.
             Self::InvalidInode(_) | Self::InvalidHandle(_) => {
@@ -1,25 +1,33 @@
 use crate::{
-    config::de_duration_sec,
+    config::{de_byte_size, de_duration_sec},
     login::ManagedOnedrive,
     paths::default_disk_cache_dir,
-    vfs::{Error, Result, UpdateEvent},
+    vfs::{
+        block_cache::{de_block_cache, BlockCache, BLOCK_SIZE},
+        metrics, op,
+        retry::{self, retry},
+        throttle::{de_throttle, Throttle},
+        Error, Result, UpdateEvent,
+    },
 };
 use bytes::{Bytes, BytesMut};
 use lru_cache::LruCache;
 use onedrive_api::{
-    option::DriveItemPutOption,
+    option::{DriveItemPutOption, ObjectOption},
     resource::{DriveItem, DriveItemField},
-    ConflictBehavior, ItemId, ItemLocation, OneDrive, Tag,
+    ConflictBehavior, FileName, ItemId, ItemLocation, OneDrive, Tag, UploadSession,
 };
 use reqwest::{header, StatusCode};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use sharded_slab::Slab;
 use std::{
+    collections::{HashMap, HashSet, VecDeque},
     convert::TryFrom as _,
+    fmt,
     io::{self, SeekFrom},
     path::PathBuf,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         Arc, Mutex as SyncMutex, Weak,
     },
     time::{Duration, Instant, SystemTime},
@@ -30,10 +38,11 @@ use tokio::{
     time,
 };
 
-use super::InodeAttr;
+use super::{inode::ContentHashes, InodeAttr};
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
+    max_open_handles: usize,
     disk_cache: DiskCacheConfig,
     download: DownloadConfig,
     upload: UploadConfig,
@@ -45,9 +54,17 @@ struct DownloadConfig {
     #[serde(deserialize_with = "de_duration_sec")]
     retry_delay: Duration,
     stream_buffer_chunks: usize,
+    #[serde(deserialize_with = "de_byte_size")]
     stream_ring_buffer_size: usize,
     #[serde(deserialize_with = "de_duration_sec")]
     chunk_timeout: Duration,
+    #[serde(deserialize_with = "de_throttle")]
+    max_bytes_per_sec: Arc<Throttle>,
+    #[serde(deserialize_with = "de_block_cache")]
+    block_cache_size: Arc<BlockCache>,
+    parallel_ranges: usize,
+    #[serde(deserialize_with = "de_byte_size")]
+    range_size: u64,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -55,28 +72,137 @@ struct DiskCacheConfig {
     enable: bool,
     #[serde(default = "default_disk_cache_dir")]
     path: PathBuf,
+    persist: bool,
+    #[serde(deserialize_with = "de_byte_size")]
     max_cached_file_size: u64,
     max_files: usize,
+    #[serde(deserialize_with = "de_byte_size")]
     max_total_size: u64,
+    #[serde(default)]
+    pinned_paths: Vec<String>,
+    #[serde(deserialize_with = "de_byte_size")]
+    max_pinned_size: u64,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 struct UploadConfig {
+    #[serde(deserialize_with = "de_byte_size")]
     max_size: u64,
+    #[serde(deserialize_with = "de_byte_size")]
+    part_size: usize,
     #[serde(deserialize_with = "de_duration_sec")]
     flush_delay: Duration,
     #[serde(deserialize_with = "de_duration_sec")]
     retry_delay: Duration,
+    #[serde(deserialize_with = "de_duration_sec")]
+    max_retry_delay: Duration,
+    on_conflict: ConflictPolicy,
+    #[serde(deserialize_with = "de_duration_sec")]
+    shutdown_timeout: Duration,
+    flush_on_close: bool,
+    #[serde(deserialize_with = "de_throttle")]
+    max_bytes_per_sec: Arc<Throttle>,
+}
+
+/// What to do when the remote file is changed since the local changes are based on.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ConflictPolicy {
+    /// Upload local content as a sibling conflicted copy, and keep the remote version.
+    Copy,
+    /// Give up uploading, and report `EIO` on `fsync`.
+    Fail,
+}
+
+impl UploadConfig {
+    /// Upload parts except the last one must be a multiple of 320 KiB.
+    /// See: https://docs.microsoft.com/en-us/graph/api/driveitem-createuploadsession?view=graph-rest-1.0#upload-bytes-to-the-upload-session
+    const PART_SIZE_ALIGN: usize = 320 << 10;
+
+    fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.part_size != 0
+                && self.part_size.is_multiple_of(Self::PART_SIZE_ALIGN)
+                && self.part_size <= onedrive_api::UploadSession::MAX_PART_SIZE,
+            "`vfs.file.upload.part_size` must be a non-zero multiple of 320 KiB and at most 60 MiB, got {}",
+            self.part_size,
+        );
+        anyhow::ensure!(
+            self.retry_delay <= self.max_retry_delay,
+            "`vfs.file.upload.retry_delay` must not be greater than `vfs.file.upload.max_retry_delay`",
+        );
+        Ok(())
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.max_open_handles != 0,
+            "`vfs.file.max_open_handles` must be positive",
+        );
+        let disk = &self.disk_cache;
+        if disk.enable {
+            anyhow::ensure!(
+                disk.max_files != 0,
+                "`vfs.file.disk_cache.max_files` must be positive",
+            );
+            anyhow::ensure!(
+                disk.max_cached_file_size <= disk.max_total_size,
+                "`vfs.file.disk_cache.max_cached_file_size` must not be greater than `vfs.file.disk_cache.max_total_size`",
+            );
+            anyhow::ensure!(
+                disk.max_pinned_size < disk.max_total_size,
+                "`vfs.file.disk_cache.max_pinned_size` must be less than `vfs.file.disk_cache.max_total_size`",
+            );
+            for path in &disk.pinned_paths {
+                anyhow::ensure!(
+                    path.starts_with('/'),
+                    "Paths in `vfs.file.disk_cache.pinned_paths` must start with `/`, got {:?}",
+                    path,
+                );
+            }
+            if disk.max_cached_file_size < self.upload.max_size {
+                log::warn!(
+                    "Files larger than `vfs.file.disk_cache.max_cached_file_size` cannot be opened for writing, though `vfs.file.upload.max_size` is larger",
+                );
+            }
+        }
+        let download = &self.download;
+        anyhow::ensure!(
+            download.stream_buffer_chunks != 0,
+            "`vfs.file.download.stream_buffer_chunks` must be positive",
+        );
+        anyhow::ensure!(
+            download.stream_ring_buffer_size != 0,
+            "`vfs.file.download.stream_ring_buffer_size` must be positive",
+        );
+        anyhow::ensure!(
+            !download.chunk_timeout.is_zero(),
+            "`vfs.file.download.chunk_timeout` must be positive",
+        );
+        anyhow::ensure!(
+            download.parallel_ranges != 0,
+            "`vfs.file.download.parallel_ranges` must be positive",
+        );
+        anyhow::ensure!(
+            download.range_size != 0,
+            "`vfs.file.download.range_size` must be positive",
+        );
+        self.upload.validate()
+    }
 }
 
 pub struct FilePool {
     handles: Slab<File>,
+    handle_count: AtomicUsize,
     disk_cache: Option<DiskCache>,
     event_tx: mpsc::Sender<UpdateEvent>,
     config: Config,
     onedrive: ManagedOnedrive,
     /// The client without timeout limit, which is used for upload and download.
     client: reqwest::Client,
+    retry: retry::Config,
 }
 
 #[derive(Debug, Clone)]
@@ -87,13 +213,48 @@ pub struct UpdatedFileAttr {
     pub c_tag: Tag,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 struct RemoteFileMeta {
     size: u64,
     c_tag: Tag,
+    hashes: ContentHashes,
     download_url: String,
 }
 
+// Download URLs are pre-authenticated, don't leak them into logs.
+impl fmt::Debug for RemoteFileMeta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RemoteFileMeta")
+            .field("size", &self.size)
+            .field("c_tag", &self.c_tag)
+            .field("hashes", &self.hashes)
+            .field("download_url", &redact_url(&self.download_url))
+            .finish()
+    }
+}
+
+/// Strip the query part of an URL, which may contain tokens.
+fn redact_url(url: &str) -> &str {
+    url.split('?').next().unwrap_or(url)
+}
+
+/// Delete an unfinished upload session.
+async fn delete_upload_session(
+    sess: &UploadSession,
+    onedrive: &ManagedOnedrive,
+) -> onedrive_api::Result<()> {
+    metrics::API_REQUESTS.inc();
+    let ret = sess.delete(onedrive.get().await.client()).await;
+    log::trace!(
+        target: "vfs::api",
+        "{}DELETE {}: {:?}",
+        op::tag(),
+        redact_url(sess.upload_url()),
+        ret.as_ref().map_err(ToString::to_string),
+    );
+    ret
+}
+
 impl FilePool {
     pub const SYNC_SELECT_FIELDS: &'static [DriveItemField] = &[DriveItemField::c_tag];
 
@@ -102,11 +263,21 @@ impl FilePool {
         onedrive: ManagedOnedrive,
         unlimit_client: reqwest::Client,
         config: Config,
+        retry: retry::Config,
+        drive_id: &str,
     ) -> anyhow::Result<Self> {
+        use anyhow::Context as _;
+
         Ok(Self {
             handles: Slab::new(),
+            handle_count: AtomicUsize::new(0),
             disk_cache: if config.disk_cache.enable {
-                Some(DiskCache::new(config.clone())?)
+                Some(DiskCache::new(config.clone(), drive_id).with_context(|| {
+                    format!(
+                        "Failed to open `vfs.file.disk_cache.path` {}",
+                        config.disk_cache.path.display(),
+                    )
+                })?)
             } else {
                 None
             },
@@ -114,6 +285,7 @@ impl FilePool {
             config,
             onedrive,
             client: unlimit_client,
+            retry,
         })
     }
 
@@ -125,25 +297,153 @@ impl FilePool {
         usize::try_from(fh).unwrap()
     }
 
-    // Fetch file size, CTag and download URL.
+    // Fetch file size, CTag, content hashes and download URL.
     async fn fetch_meta(item_id: &ItemId, onedrive: &OneDrive) -> Result<RemoteFileMeta> {
         // `download_url` is available without `$select`.
+        use anyhow::Context as _;
+
         let item = onedrive.get_item(ItemLocation::from_id(item_id)).await?;
-        Ok(RemoteFileMeta {
-            size: item.size.unwrap() as u64,
-            c_tag: item.c_tag.unwrap(),
-            download_url: item.download_url.unwrap(),
+        // Some items like OneNote notebooks or online-only documents have no content stream.
+        let Some(download_url) = item.download_url else {
+            log::info!("File {:?} has no downloadable content", item_id);
+            return Err(Error::NotSupported);
+        };
+        (|| {
+            Ok(RemoteFileMeta {
+                size: item.size.context("Missing size")? as u64,
+                c_tag: item.c_tag.context("Missing c_tag")?,
+                hashes: item
+                    .file
+                    .as_deref()
+                    .map(ContentHashes::parse)
+                    .unwrap_or_default(),
+                download_url,
+            })
+        })()
+        .map_err(Error::InvalidResponse)
+    }
+
+    async fn fetch_meta_with_retry(&self, item_id: &ItemId) -> Result<RemoteFileMeta> {
+        retry(&self.retry, "fetch file metadata", || async {
+            Self::fetch_meta(item_id, &*self.onedrive.get().await).await
+        })
+        .await
+    }
+
+    /// List previous versions of a file, one per line as `<id>\t<last modified time>\t<size>`,
+    /// the newest first.
+    pub async fn list_versions(&self, item_id: &ItemId) -> Result<String> {
+        let item = retry(&self.retry, "list file versions", || async {
+            Ok(self
+                .onedrive
+                .get()
+                .await
+                .get_item_with_option(
+                    ItemLocation::from_id(item_id),
+                    ObjectOption::new()
+                        .select(&[DriveItemField::id])
+                        .expand(DriveItemField::versions, None),
+                )
+                .await?)
+        })
+        .await?
+        .expect("No If-None-Match");
+
+        let versions = item
+            .versions
+            .as_ref()
+            .and_then(|v| v.as_array())
+            .ok_or_else(|| Error::InvalidResponse(anyhow::anyhow!("Missing versions")))?;
+        let mut ret = String::new();
+        for ver in versions {
+            let str_field = |name: &str| ver.get(name).and_then(|v| v.as_str()).unwrap_or_default();
+            let size = ver.get("size").and_then(|v| v.as_u64()).unwrap_or_default();
+            ret += &format!(
+                "{}\t{}\t{}\n",
+                str_field("id"),
+                str_field("lastModifiedDateTime"),
+                size,
+            );
+        }
+        Ok(ret)
+    }
+
+    /// The pre-authenticated URL to download a previous version of a file.
+    pub async fn version_url(&self, item_id: &ItemId, version_id: &str) -> Result<String> {
+        // Not supported by `onedrive_api`. The content endpoint redirects to the download URL,
+        // so the response body is dropped unread once the redirection is followed.
+        let url = self.onedrive.drive_api_url(&[
+            "items",
+            item_id.as_str(),
+            "versions",
+            version_id,
+            "content",
+        ]);
+        retry(&self.retry, "get file version URL", || async {
+            let onedrive = self.onedrive.get().await;
+            let resp = onedrive
+                .client()
+                .get(url.clone())
+                .bearer_auth(onedrive.access_token())
+                .send()
+                .await?;
+            Ok(Error::check_response(resp).await?.url().to_string())
+        })
+        .await
+    }
+
+    /// Restore a previous version of a file as the current one on the server side.
+    pub async fn restore_version(&self, item_id: &ItemId, version_id: &str) -> Result<()> {
+        // Not supported by `onedrive_api`.
+        let url = self.onedrive.drive_api_url(&[
+            "items",
+            item_id.as_str(),
+            "versions",
+            version_id,
+            "restoreVersion",
+        ]);
+        retry(&self.retry, "restore file version", || async {
+            let onedrive = self.onedrive.get().await;
+            let resp = onedrive
+                .client()
+                .post(url.clone())
+                .bearer_auth(onedrive.access_token())
+                .header(header::CONTENT_LENGTH, 0)
+                .send()
+                .await?;
+            Error::check_response(resp).await?;
+            Ok(())
         })
+        .await?;
+        log::info!("Restored version {:?} of {:?}", version_id, item_id);
+        Ok(())
     }
 
-    async fn open_inner(&self, item_id: &ItemId, write_mode: bool) -> Result<File> {
+    async fn open_inner(&self, item_id: &ItemId, write_mode: bool, direct: bool) -> Result<File> {
+        // Direct reads always stream from remote, bypassing all caches.
+        if direct && !write_mode {
+            let meta = self.fetch_meta_with_retry(item_id).await?;
+            log::debug!("Streaming file {:?} directly, meta: {:?}", item_id, meta);
+            let state = FileStreamState::fetch(
+                item_id.clone(),
+                meta,
+                self.onedrive.clone(),
+                self.client.clone(),
+                self.config.download.clone(),
+                false,
+            );
+            return Ok(File::Streaming(Arc::new(Mutex::new(state))));
+        }
+
         let meta = if let Some(cache) = &self.disk_cache {
-            if let Some(state) = cache.get(item_id) {
+            if let Some(state) = cache.get_valid(item_id).await {
                 log::debug!("File already cached: {:?}", item_id);
+                metrics::DISK_CACHE_HITS.inc();
                 return Ok(File::Cached(state));
             }
 
-            let meta = Self::fetch_meta(item_id, &*self.onedrive.get().await).await?;
+            metrics::DISK_CACHE_MISSES.inc();
+            let meta = self.fetch_meta_with_retry(item_id).await?;
             if let Some(state) = cache.try_alloc_and_fetch(
                 item_id,
                 &meta,
@@ -162,19 +462,59 @@ impl FilePool {
         } else if write_mode {
             return Err(Error::WriteWithoutCache);
         } else {
-            Self::fetch_meta(item_id, &*self.onedrive.get().await).await?
+            self.fetch_meta_with_retry(item_id).await?
         };
 
         log::debug!("Streaming file {:?}, meta: {:?}", item_id, meta);
-        let state =
-            FileStreamState::fetch(&meta, self.client.clone(), self.config.download.clone());
+        let state = FileStreamState::fetch(
+            item_id.clone(),
+            meta,
+            self.onedrive.clone(),
+            self.client.clone(),
+            self.config.download.clone(),
+            true,
+        );
         Ok(File::Streaming(Arc::new(Mutex::new(state))))
     }
 
-    pub async fn open(&self, item_id: &ItemId, write_mode: bool) -> Result<u64> {
-        let file = self.open_inner(item_id, write_mode).await?;
-        let key = self.handles.insert(file).expect("Pool is full");
-        Ok(Self::key_to_fh(key))
+    /// Whether the whole content of a file is in disk cache, so it can be read without requests.
+    pub async fn is_cached(&self, item_id: &ItemId) -> bool {
+        let Some(cache) = self
+            .disk_cache
+            .as_ref()
+            .and_then(|cache| cache.get(item_id))
+        else {
+            return false;
+        };
+        let state = cache.state.lock().await;
+        matches!(
+            state.status,
+            FileCacheStatus::Available
+                | FileCacheStatus::Dirty { .. }
+                | FileCacheStatus::UploadFailed
+        )
+    }
+
+    /// Open a file. If `direct` is set, reads bypass the disk cache and the block cache.
+    pub async fn open(&self, item_id: &ItemId, write_mode: bool, direct: bool) -> Result<u64> {
+        let file = self.open_inner(item_id, write_mode, direct).await?;
+        self.insert_handle(file)
+    }
+
+    fn insert_handle(&self, file: File) -> Result<u64> {
+        let max = self.config.max_open_handles;
+        self.handle_count
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |cnt| {
+                (cnt < max).then_some(cnt + 1)
+            })
+            .map_err(|_| Error::TooManyOpenFiles)?;
+        match self.handles.insert(file) {
+            Some(key) => Ok(Self::key_to_fh(key)),
+            None => {
+                self.handle_count.fetch_sub(1, Ordering::Relaxed);
+                Err(Error::TooManyOpenFiles)
+            }
+        }
     }
 
     pub async fn open_create_empty(
@@ -183,25 +523,71 @@ impl FilePool {
     ) -> Result<(u64, ItemId, InodeAttr)> {
         let cache = self.disk_cache.as_ref().ok_or(Error::WriteWithoutCache)?;
 
-        let item = self
-            .onedrive
-            .get()
-            .await
-            .upload_small(item_loc, Vec::new())
-            .await?;
+        // Uploading the same empty content again is harmless.
+        let item = retry(&self.retry, "create file", || async {
+            Ok(self
+                .onedrive
+                .get()
+                .await
+                .upload_small(item_loc, Vec::new())
+                .await?)
+        })
+        .await?;
         assert_eq!(item.size, Some(0));
-        let attr = InodeAttr::parse_item(&item).expect("Invalid attrs");
-        let id = item.id.expect("Missing id");
+        let (id, attr) = InodeAttr::parse_item_with_id(item)?;
         log::debug!("Truncated or created file {:?}", id);
 
         let file = cache
-            .insert_empty(id.clone(), attr.c_tag.clone().unwrap())
+            .insert_empty(id.clone(), attr.c_tag.clone().expect("Files have c_tag"))
             .await?;
-        let key = self
-            .handles
-            .insert(File::Cached(file))
-            .expect("Pool is full");
-        Ok((Self::key_to_fh(key), id, attr))
+        let fh = self.insert_handle(File::Cached(file))?;
+        Ok((fh, id, attr))
+    }
+
+    /// Download the whole content of a small file without caching it, like marker files of
+    /// symlinks. Return it along with its CTag.
+    pub async fn read_small(&self, item_id: &ItemId) -> Result<(Tag, Bytes)> {
+        let meta = self.fetch_meta_with_retry(item_id).await?;
+        let data = retry(&self.retry, "download file", || async {
+            let ret = self
+                .client
+                .get(&meta.download_url)
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status())
+                .map_err(|err| err.without_url());
+            log::trace!(
+                target: "vfs::api",
+                "{}GET {}: {:?}",
+                op::tag(),
+                redact_url(&meta.download_url),
+                ret.as_ref().map(|resp| resp.status()),
+            );
+            Ok(ret?.bytes().await.map_err(|err| err.without_url())?)
+        })
+        .await?;
+        metrics::DOWNLOADED_BYTES.add(data.len() as u64);
+        Ok((meta.c_tag, data))
+    }
+
+    /// Create a small file with `data` without caching it, like marker files of symlinks.
+    pub async fn create_small(
+        &self,
+        item_loc: ItemLocation<'_>,
+        data: Bytes,
+    ) -> Result<(ItemId, InodeAttr)> {
+        // Uploading the same content again is harmless.
+        let item = retry(&self.retry, "create file", || async {
+            Ok(self
+                .onedrive
+                .get()
+                .await
+                .upload_small(item_loc, data.clone())
+                .await?)
+        })
+        .await?;
+        metrics::UPLOADED_BYTES.add(data.len() as u64);
+        InodeAttr::parse_item_with_id(item)
     }
 
     pub async fn truncate_file(
@@ -210,7 +596,9 @@ impl FilePool {
         new_size: u64,
         mtime: SystemTime,
     ) -> Result<()> {
-        if new_size > self.config.disk_cache.max_cached_file_size {
+        if new_size > self.config.disk_cache.max_cached_file_size
+            || new_size > self.config.upload.max_size
+        {
             return Err(Error::FileTooLarge);
         }
 
@@ -221,27 +609,31 @@ impl FilePool {
             let mut guard = file.state.lock().await;
             match guard.status {
                 FileCacheStatus::Downloading { truncate } => {
+                    guard.cache_file.set_len(new_size).await?;
                     let download_size = truncate.map(|(sz, _)| sz).unwrap_or(guard.file_size);
                     guard.status = FileCacheStatus::Downloading {
                         truncate: Some((download_size.min(new_size), mtime)),
                     };
-                    guard.file_size = new_size;
-                    guard.cache_file.set_len(new_size).await.unwrap();
+                    file.set_size(&mut guard, new_size);
+                    file.clear_hashes();
                     log::debug!(
                         "Pending another truncate for still downloading file {:?}",
                         item_id,
                     );
                     return Ok(());
                 }
-                FileCacheStatus::Available | FileCacheStatus::Dirty { .. } => {
+                FileCacheStatus::Available
+                | FileCacheStatus::Dirty { .. }
+                | FileCacheStatus::UploadFailed => {
                     log::debug!(
                         "Truncated cached file {:?}: {} -> {}",
                         item_id,
                         guard.file_size,
                         new_size,
                     );
-                    guard.file_size = new_size;
-                    guard.cache_file.set_len(new_size).await.unwrap();
+                    guard.cache_file.set_len(new_size).await?;
+                    file.set_size(&mut guard, new_size);
+                    file.clear_hashes();
                     file.queue_upload(
                         &mut guard,
                         mtime,
@@ -256,7 +648,7 @@ impl FilePool {
             }
         }
 
-        let meta = Self::fetch_meta(item_id, &*self.onedrive.get().await).await?;
+        let meta = self.fetch_meta_with_retry(item_id).await?;
         log::debug!(
             "Download with truncate {:?}: new size: {}, remote meta: {:?}",
             item_id,
@@ -277,8 +669,46 @@ impl FilePool {
         }
     }
 
+    /// Set mtime of a file with pending changes, to be committed along with the upload.
+    /// Return `false` if there is nothing pending, and the mtime should be set remotely.
+    pub async fn set_pending_mtime(&self, item_id: &ItemId, mtime: SystemTime) -> Result<bool> {
+        let file = match &self.disk_cache {
+            Some(cache) => cache.cache.lock().unwrap().get_mut(item_id).cloned(),
+            None => None,
+        };
+        let file = match file {
+            Some(file) => file,
+            None => return Ok(false),
+        };
+
+        let mut guard = file.state.lock().await;
+        match &mut guard.status {
+            FileCacheStatus::Downloading {
+                truncate: Some((_, truncate_mtime)),
+            } => *truncate_mtime = mtime,
+            FileCacheStatus::Dirty { .. } | FileCacheStatus::UploadFailed => {
+                file.queue_upload(
+                    &mut guard,
+                    mtime,
+                    self.onedrive.clone(),
+                    self.client.clone(),
+                    self.event_tx.clone(),
+                    self.config.upload.clone(),
+                );
+            }
+            _ => return Ok(false),
+        }
+        log::debug!(
+            "Pending mtime of {:?}: {}",
+            item_id,
+            humantime::format_rfc3339_seconds(mtime),
+        );
+        Ok(true)
+    }
+
     pub async fn close(&self, fh: u64) -> Result<()> {
         if self.handles.remove(Self::fh_to_key(fh)) {
+            self.handle_count.fetch_sub(1, Ordering::Relaxed);
             Ok(())
         } else {
             Err(Error::InvalidHandle(fh))
@@ -298,7 +728,13 @@ impl FilePool {
     }
 
     /// Write to cached file. Returns item id and file size after the write.
-    pub async fn write(&self, fh: u64, offset: u64, data: &[u8]) -> Result<UpdatedFileAttr> {
+    /// Write at `offset`, or at the end of file if `None`.
+    pub async fn write(
+        &self,
+        fh: u64,
+        offset: Option<u64>,
+        data: &[u8],
+    ) -> Result<UpdatedFileAttr> {
         let file = self
             .handles
             .get(Self::fh_to_key(fh))
@@ -327,6 +763,7 @@ impl FilePool {
                 let mut guard = file.state.lock().await;
                 match guard.status {
                     FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
+                    FileCacheStatus::UploadFailed => return Err(file.upload_error()),
                     FileCacheStatus::Available | FileCacheStatus::Invalidated => return Ok(()),
                     FileCacheStatus::Downloading { .. } => {
                         let mut rx = guard.available_size.clone();
@@ -340,6 +777,7 @@ impl FilePool {
                     let (flush_tx, mut done_rx) = match &mut guard.status {
                         FileCacheStatus::Downloading { .. } => unreachable!(),
                         FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
+                        FileCacheStatus::UploadFailed => return Err(file.upload_error()),
                         FileCacheStatus::Invalidated | FileCacheStatus::Available => return Ok(()),
                         FileCacheStatus::Dirty {
                             flush_tx, done_rx, ..
@@ -361,11 +799,113 @@ impl FilePool {
         Ok(())
     }
 
+    /// Called on every close of a file descriptor. Upload and wait for local changes if
+    /// `flush_on_close` is set, otherwise they are uploaded after `flush_delay` as usual.
+    pub async fn flush_on_close(&self, item_id: &ItemId) -> Result<()> {
+        if !self.config.upload.flush_on_close {
+            return Ok(());
+        }
+        self.flush_file(item_id).await
+    }
+
+    /// Upload all dirty files now and wait for them until `shutdown_timeout`.
+    /// Files failed to be uploaded are reported in logs.
+    pub async fn flush_all(&self) {
+        let Some(cache) = &self.disk_cache else {
+            return;
+        };
+        let files = cache
+            .cache
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, file)| file.clone())
+            .collect::<Vec<_>>();
+        let mut dirty = Vec::new();
+        for file in files {
+            let mut guard = file.state.lock().await;
+            match &mut guard.status {
+                FileCacheStatus::Dirty { flush_tx, .. } => {
+                    // Start all uploads at once.
+                    if let Some(flush_tx) = flush_tx.take() {
+                        let _ = flush_tx.send(());
+                    }
+                    dirty.push(file.item_id.clone());
+                }
+                FileCacheStatus::UploadFailed => {
+                    log::error!("Local changes of {:?} are not uploaded", file.item_id);
+                }
+                _ => {}
+            }
+        }
+        if dirty.is_empty() {
+            return;
+        }
+
+        log::info!("Waiting for {} files to be uploaded...", dirty.len());
+        let deadline = time::Instant::now() + self.config.upload.shutdown_timeout;
+        for item_id in dirty {
+            match time::timeout_at(deadline, self.flush_file(&item_id)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => {
+                    log::error!("Local changes of {:?} are not uploaded: {}", item_id, err);
+                }
+                Err(_) => log::error!(
+                    "Local changes of {:?} are not uploaded before timeout",
+                    item_id,
+                ),
+            }
+        }
+    }
+
     pub async fn sync_items(&self, items: &[DriveItem]) {
+        let current = items
+            .iter()
+            .filter(|item| item.folder.is_none())
+            .filter_map(|item| {
+                let c_tag = if item.deleted.is_some() {
+                    None
+                } else {
+                    Some(item.c_tag.clone()?)
+                };
+                Some((item.id.clone()?, c_tag))
+            })
+            .collect::<HashMap<_, _>>();
+        self.config
+            .download
+            .block_cache_size
+            .purge_outdated(&current);
+
         if let Some(cache) = &self.disk_cache {
             cache.sync_items(items).await;
         }
     }
+
+    /// Drop cache of files for which `f` returns false.
+    pub fn retain_cached(&self, f: impl FnMut(&ItemId) -> bool) {
+        if let Some(cache) = &self.disk_cache {
+            cache.retain(f);
+        }
+    }
+
+    /// Paths whose files are kept from eviction, from `vfs.file.disk_cache.pinned_paths`.
+    pub fn pinned_paths(&self) -> &[String] {
+        &self.config.disk_cache.pinned_paths
+    }
+
+    /// Recalculate which cached files are pinned.
+    pub fn refresh_pins(&self, f: impl FnMut(&ItemId) -> bool) {
+        if let Some(cache) = &self.disk_cache {
+            cache.refresh_pins(f);
+        }
+    }
+
+    /// Set whether a cached file is pinned, if it's cached.
+    pub fn set_pinned(&self, item_id: &ItemId, pinned: bool) {
+        if let Some(cache) = &self.disk_cache {
+            cache.set_pinned(item_id, pinned);
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -380,6 +920,14 @@ struct FileStreamState {
     buf_start_pos: u64,
     buf: RingBuf,
     rx: mpsc::Receiver<Bytes>,
+    // For restarting the download at another position.
+    item_id: ItemId,
+    meta: RemoteFileMeta,
+    onedrive: ManagedOnedrive,
+    client: reqwest::Client,
+    config: DownloadConfig,
+    /// Whether to use the shared block cache.
+    use_block_cache: bool,
 }
 
 #[derive(Debug)]
@@ -399,6 +947,11 @@ impl RingBuf {
         self.v.len() - 1
     }
 
+    fn clear(&mut self) {
+        self.l = 0;
+        self.r = 0;
+    }
+
     fn len(&self) -> usize {
         if self.l <= self.r {
             self.r - self.l
@@ -443,24 +996,57 @@ impl RingBuf {
 }
 
 impl FileStreamState {
-    fn fetch(meta: &RemoteFileMeta, client: reqwest::Client, config: DownloadConfig) -> Self {
+    fn fetch(
+        item_id: ItemId,
+        meta: RemoteFileMeta,
+        onedrive: ManagedOnedrive,
+        client: reqwest::Client,
+        config: DownloadConfig,
+        use_block_cache: bool,
+    ) -> Self {
         let (tx, rx) = mpsc::channel(config.stream_buffer_chunks);
         let buf = RingBuf::new(config.stream_ring_buffer_size);
-        tokio::spawn(download_thread(
-            meta.size,
-            meta.download_url.clone(),
+        tokio::spawn(stream_thread(
+            item_id.clone(),
+            meta.clone(),
+            0,
             tx,
-            client,
-            config,
+            onedrive.clone(),
+            client.clone(),
+            config.clone(),
         ));
         Self {
             file_size: meta.size,
             buf_start_pos: 0,
             buf,
             rx,
+            item_id,
+            meta,
+            onedrive,
+            client,
+            config,
+            use_block_cache,
         }
     }
 
+    /// Drop the buffer and restart downloading from `pos` with a new range request.
+    fn restart(&mut self, pos: u64) {
+        let (tx, rx) = mpsc::channel(self.config.stream_buffer_chunks);
+        tokio::spawn(stream_thread(
+            self.item_id.clone(),
+            self.meta.clone(),
+            pos,
+            tx,
+            self.onedrive.clone(),
+            self.client.clone(),
+            self.config.clone(),
+        ));
+        // The previous download thread stops when the old receiver is dropped.
+        self.rx = rx;
+        self.buf.clear();
+        self.buf_start_pos = pos;
+    }
+
     async fn read(&mut self, offset: u64, size: usize) -> Result<Bytes> {
         let size = (self.file_size.saturating_sub(offset)).min(size as u64) as usize;
         if size == 0 {
@@ -468,6 +1054,27 @@ impl FileStreamState {
         }
         let end = offset + size as u64;
 
+        let block_cache = &self.config.block_cache_size;
+        if self.use_block_cache {
+            if let Some(data) = block_cache.get(&self.item_id, &self.meta.c_tag, offset, end) {
+                return Ok(data);
+            }
+        }
+
+        // Seeking backward out of the buffer, or too far forward that all buffered data would be
+        // discarded anyway.
+        let buf_end = self.buf_start_pos + self.buf.len() as u64;
+        if offset < self.buf_start_pos || buf_end + self.buf.capacity() as u64 <= offset {
+            log::debug!(
+                "Restart streaming {:?} at {}, buffered {}..{}",
+                self.item_id,
+                offset,
+                self.buf_start_pos,
+                buf_end,
+            );
+            self.restart(offset);
+        }
+
         while self.buf_start_pos + (self.buf.len() as u64) < end {
             let chunk = match self.rx.recv().await {
                 Some(chunk) => chunk,
@@ -477,6 +1084,7 @@ impl FileStreamState {
             self.buf_start_pos += advance as u64;
         }
 
+        // The read is larger than the ring buffer.
         if offset < self.buf_start_pos {
             return Err(Error::NonsequentialRead {
                 current_pos: self.buf_start_pos,
@@ -486,57 +1094,191 @@ impl FileStreamState {
         }
 
         let start = (offset - self.buf_start_pos) as usize;
-        let (lhs, rhs) = self.buf.slice(start..(start + size));
-        let mut ret = BytesMut::with_capacity(size);
+        let ret = self.buf_bytes(start..(start + size));
+        if self.use_block_cache {
+            self.cache_blocks();
+        }
+        Ok(ret)
+    }
+
+    fn buf_bytes(&self, range: std::ops::Range<usize>) -> Bytes {
+        let (lhs, rhs) = self.buf.slice(range.clone());
+        let mut ret = BytesMut::with_capacity(range.len());
         ret.extend_from_slice(lhs);
         ret.extend_from_slice(rhs);
-        Ok(ret.freeze())
+        ret.freeze()
+    }
+
+    /// Save blocks fully in the buffer into the shared block cache.
+    fn cache_blocks(&self) {
+        let block_cache = &self.config.block_cache_size;
+        let buf_end = self.buf_start_pos + self.buf.len() as u64;
+        for idx in self.buf_start_pos.div_ceil(BLOCK_SIZE).. {
+            let block_start = idx * BLOCK_SIZE;
+            let block_end = (block_start + BLOCK_SIZE).min(self.file_size);
+            if buf_end < block_end || block_end <= block_start {
+                break;
+            }
+            if block_cache.contains(&self.item_id, &self.meta.c_tag, idx) {
+                continue;
+            }
+            let start = (block_start - self.buf_start_pos) as usize;
+            let end = (block_end - self.buf_start_pos) as usize;
+            block_cache.insert(
+                &self.item_id,
+                &self.meta.c_tag,
+                idx,
+                self.buf_bytes(start..end),
+            );
+        }
+    }
+}
+
+/// Download the file from `start_pos` to the end, sending chunks to `tx` in order.
+///
+/// With `parallel_ranges` more than 1, it is split into ranges of `range_size`, and up to
+/// `parallel_ranges` of them are downloaded concurrently. Dropping the receiver stops all of them.
+async fn stream_thread(
+    item_id: ItemId,
+    meta: RemoteFileMeta,
+    start_pos: u64,
+    tx: mpsc::Sender<Bytes>,
+    onedrive: ManagedOnedrive,
+    client: reqwest::Client,
+    config: DownloadConfig,
+) {
+    let file_size = meta.size;
+    if config.parallel_ranges <= 1 {
+        return download_thread(
+            item_id,
+            meta,
+            start_pos..file_size,
+            tx,
+            onedrive,
+            client,
+            config,
+        )
+        .await;
+    }
+
+    let mut next_pos = start_pos;
+    let mut ranges = VecDeque::with_capacity(config.parallel_ranges);
+    loop {
+        while ranges.len() < config.parallel_ranges && next_pos < file_size {
+            let range = next_pos..(next_pos + config.range_size).min(file_size);
+            next_pos = range.end;
+            let (range_tx, range_rx) = mpsc::channel(config.stream_buffer_chunks);
+            tokio::spawn(download_thread(
+                item_id.clone(),
+                meta.clone(),
+                range.clone(),
+                range_tx,
+                onedrive.clone(),
+                client.clone(),
+                config.clone(),
+            ));
+            ranges.push_back((range, range_rx));
+        }
+        let Some((range, mut range_rx)) = ranges.pop_front() else {
+            return;
+        };
+        let mut pos = range.start;
+        while let Some(chunk) = range_rx.recv().await {
+            pos += chunk.len() as u64;
+            if tx.send(chunk).await.is_err() {
+                // Remaining ranges stop when their receivers are dropped.
+                return;
+            }
+        }
+        // Already reported by the range download. The reader fails on the closed channel.
+        if pos != range.end {
+            return;
+        }
     }
 }
 
+/// Download `range` of the file, sending chunks to `tx` in order.
 async fn download_thread(
-    file_size: u64,
-    download_url: String,
+    item_id: ItemId,
+    meta: RemoteFileMeta,
+    range: std::ops::Range<u64>,
     tx: mpsc::Sender<Bytes>,
+    onedrive: ManagedOnedrive,
     client: reqwest::Client,
     config: DownloadConfig,
 ) {
-    let mut pos = 0u64;
+    let file_size = meta.size;
+    let mut download_url = meta.download_url;
+    let mut pos = range.start;
+    let end = range.end;
 
-    log::debug!("Start downloading ({} bytes)", file_size);
+    log::debug!("Start downloading {}..{} ({} bytes)", pos, end, file_size);
 
-    while pos < file_size {
+    while pos < end {
+        // Open-ended if it's to the end, as before splitting ranges.
+        let range_header = if end == file_size {
+            format!("bytes={}-", pos)
+        } else {
+            format!("bytes={}-{}", pos, end - 1)
+        };
         let mut tries = 0;
         let mut resp = loop {
-            let ret: anyhow::Result<_> = client
+            let ret = client
                 .get(&download_url)
                 // We already have timeout for each chunk.
                 // FIXME: Use `Duration::MAX`.
                 .timeout(Duration::from_secs(u64::MAX))
-                .header(header::RANGE, format!("bytes={}-", pos))
+                .header(header::RANGE, &range_header)
                 .send()
                 .await
-                .map_err(|err| err.into())
-                .and_then(|resp| {
-                    if resp.status() != StatusCode::PARTIAL_CONTENT {
-                        anyhow::bail!("Not Partial Content response: {}", resp.status());
-                    }
-                    Ok(resp)
-                });
-            match ret {
-                Ok(resp) => break resp,
-                Err(err) => {
-                    tries += 1;
-                    log::error!(
-                        "Error downloading file (try {}/{}): {}",
-                        tries,
-                        config.max_retry,
-                        err,
+                .map_err(|err| err.without_url());
+            log::trace!(
+                target: "vfs::api",
+                "{}GET {} {}: {:?}",
+                op::tag(),
+                redact_url(&download_url),
+                range_header,
+                ret.as_ref().map(|resp| resp.status()),
+            );
+            let mut url_expired = false;
+            let err: anyhow::Error = match ret {
+                Ok(resp) if resp.status() == StatusCode::PARTIAL_CONTENT => break resp,
+                Ok(resp) => {
+                    url_expired = matches!(
+                        resp.status(),
+                        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
                     );
-                    if config.max_retry < tries {
+                    anyhow::anyhow!("Not Partial Content response: {}", resp.status())
+                }
+                Err(err) => err.into(),
+            };
+
+            tries += 1;
+            log::error!(
+                "Error downloading file (try {}/{}): {}",
+                tries,
+                config.max_retry,
+                err,
+            );
+            if config.max_retry < tries {
+                return;
+            }
+            tokio::time::sleep(config.retry_delay).await;
+
+            // The pre-authenticated download URL is short-lived. Re-resolve it once rejected.
+            if url_expired {
+                match FilePool::fetch_meta(&item_id, &*onedrive.get().await).await {
+                    Ok(new_meta) if new_meta.c_tag == meta.c_tag => {
+                        log::debug!("Download URL of {:?} is refreshed", item_id);
+                        download_url = new_meta.download_url;
+                    }
+                    Ok(_) => {
+                        log::error!("File {:?} changed during downloading", item_id);
                         return;
                     }
-                    tokio::time::sleep(config.retry_delay).await;
+                    Err(err) => {
+                        log::error!("Failed to refresh download URL of {:?}: {}", item_id, err);
+                    }
                 }
             }
         };
@@ -552,7 +1294,7 @@ async fn download_thread(
                     break;
                 }
                 Ok(Ok(None)) => {
-                    if pos != file_size {
+                    if pos != end {
                         log::error!("Download stream ends too early");
                     }
                     break;
@@ -560,8 +1302,10 @@ async fn download_thread(
                 Ok(Ok(Some(chunk))) => chunk,
             };
 
+            config.max_bytes_per_sec.consume(chunk.len()).await;
             pos += chunk.len() as u64;
-            assert!(pos <= file_size);
+            metrics::DOWNLOADED_BYTES.add(chunk.len() as u64);
+            assert!(pos <= end);
             if tx.send(chunk).await.is_err() {
                 log::debug!(
                     "Download stopped at {} bytes ({} bytes in total)",
@@ -573,43 +1317,242 @@ async fn download_thread(
         }
     }
 
-    assert_eq!(pos, file_size);
-    log::debug!("Download finished ({} bytes)", file_size);
+    assert_eq!(pos, end);
+    log::debug!(
+        "Download finished {}..{} ({} bytes)",
+        range.start,
+        end,
+        file_size
+    );
 }
 
 #[derive(Debug)]
 struct DiskCache {
     dir: PathBuf,
-    total_size: Arc<AtomicU64>,
+    usage: Arc<CacheUsage>,
     cache: SyncMutex<LruCache<ItemId, Arc<FileCache>>>,
     config: Config,
 }
 
+/// Total sizes of cached files, maintained by each `FileCache`.
+#[derive(Debug, Default)]
+struct CacheUsage {
+    total: AtomicU64,
+    /// Files which are not evicted for space, within `max_pinned_size`.
+    pinned: AtomicU64,
+}
+
 impl DiskCache {
-    fn new(config: Config) -> io::Result<Self> {
+    fn new(config: Config, drive_id: &str) -> io::Result<Self> {
         let disk_config = &config.disk_cache;
         assert!(disk_config.enable);
         assert!(disk_config.max_cached_file_size <= disk_config.max_total_size);
 
-        let dir = disk_config.path.clone();
+        // Separate drives, so that mounts of different drives can share the same path.
+        let dir = disk_config.path.join(drive_id.replace(['/', '\\'], "_"));
         std::fs::create_dir_all(&dir)?;
         log::info!("Disk file cache enabled at: {}", dir.display());
-        Ok(Self {
+        let this = Self {
             dir,
-            total_size: Arc::new(0.into()),
+            usage: Arc::default(),
             cache: SyncMutex::new(LruCache::new(disk_config.max_files)),
             config,
-        })
-    }
-
-    fn get(&self, item_id: &ItemId) -> Option<Arc<FileCache>> {
-        self.cache.lock().unwrap().get_mut(item_id).cloned()
+        };
+        if this.config.disk_cache.persist {
+            this.load_persisted()?;
+        }
+        Ok(this)
     }
 
-    fn try_alloc_and_fetch(
-        &self,
-        item_id: &ItemId,
-        meta: &RemoteFileMeta,
+    /// Load cache files saved by previous mounts, and remove invalid or incomplete ones.
+    /// They are then validated by CTag in the initial sync.
+    fn load_persisted(&self) -> io::Result<()> {
+        let disk_config = &self.config.disk_cache;
+        let mut kept = HashSet::new();
+        let mut cache = self.cache.lock().unwrap();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let meta_path = entry?.path();
+            if meta_path.extension() != Some("json".as_ref()) {
+                continue;
+            }
+            let data_path = meta_path.with_extension("");
+            let loaded = (|| -> anyhow::Result<_> {
+                let meta: PersistMeta = serde_json::from_slice(&std::fs::read(&meta_path)?)?;
+                let cache_file = std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(&data_path)?;
+                anyhow::ensure!(
+                    cache_file.metadata()?.len() == meta.size,
+                    "File size mismatch",
+                );
+                Ok((meta, cache_file))
+            })();
+            let (meta, cache_file) = match loaded {
+                Ok(loaded) => loaded,
+                Err(err) => {
+                    log::warn!("Invalid cache file {}: {}", meta_path.display(), err);
+                    continue;
+                }
+            };
+            if disk_config.max_cached_file_size < meta.size
+                || disk_config.max_total_size < self.usage.total.load(Ordering::Relaxed) + meta.size
+                || disk_config.max_files <= cache.len()
+            {
+                continue;
+            }
+            log::debug!("Load cache file {:?}, meta: {:?}", data_path, meta);
+            let (file, _) = FileCache::new(
+                meta.item_id.clone(),
+                meta.size,
+                meta.c_tag,
+                FileCacheStatus::Available,
+                cache_file.into(),
+                Some(data_path.clone()),
+                &self.usage,
+            );
+            cache.insert(meta.item_id, file);
+            kept.insert(data_path);
+        }
+
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            // Only remove files named `<item_id>.*` as written by `new_cache_file`.
+            let name = entry.file_name();
+            let is_ours = name.to_str().is_some_and(|name| {
+                name.split_once('.').is_some_and(|(id, _)| {
+                    !id.is_empty()
+                        && id
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '!' | '-' | '_'))
+                })
+            });
+            if !is_ours || !entry.file_type()?.is_file() {
+                continue;
+            }
+            let path = entry.path();
+            let data_path = if path.extension() == Some("json".as_ref()) {
+                path.with_extension("")
+            } else {
+                path.clone()
+            };
+            if !kept.contains(&data_path) {
+                std::fs::remove_file(&path)?;
+            }
+        }
+        log::info!(
+            "Loaded {} cache files ({} B)",
+            cache.len(),
+            self.usage.total.load(Ordering::Relaxed),
+        );
+        Ok(())
+    }
+
+    fn new_cache_file(&self, item_id: &ItemId) -> io::Result<(std::fs::File, Option<PathBuf>)> {
+        if !self.config.disk_cache.persist {
+            return Ok((tempfile::tempfile_in(&self.dir)?, None));
+        }
+        let (cache_file, path) = tempfile::Builder::new()
+            .prefix(&format!("{}.", item_id.as_str()))
+            .tempfile_in(&self.dir)?
+            .keep()
+            .map_err(|err| err.error)?;
+        Ok((cache_file, Some(path)))
+    }
+
+    /// Drop LRU files until there is enough space for a new file of `file_size`.
+    fn evict_for(&self, cache: &mut LruCache<ItemId, Arc<FileCache>>, file_size: u64) -> bool {
+        // Pinned files exceeding the budget are evicted as usual, so they never take all space.
+        let pinned_size = self.usage.pinned.load(Ordering::Relaxed);
+        let keep_pinned = pinned_size <= self.config.disk_cache.max_pinned_size;
+        if !keep_pinned {
+            log::warn!(
+                "Pinned files take {} bytes, exceeding `vfs.file.disk_cache.max_pinned_size`. They may be evicted",
+                pinned_size,
+            );
+        }
+
+        while self.config.disk_cache.max_total_size
+            < self.usage.total.load(Ordering::Relaxed) + file_size
+            || cache.capacity() <= cache.len()
+        {
+            // Iterated from the least recently used. Local changes which are not uploaded,
+            // including failed ones, are the only copy, so they are never evicted.
+            let victim = cache
+                .iter()
+                .find(|(_, file)| {
+                    (!keep_pinned || !file.pinned.load(Ordering::Relaxed))
+                        && file.state.try_lock().is_ok_and(|guard| {
+                            !matches!(
+                                guard.status,
+                                FileCacheStatus::Dirty { .. } | FileCacheStatus::UploadFailed
+                            )
+                        })
+                })
+                .map(|(id, _)| id.clone());
+            match victim {
+                Some(id) => cache.remove(&id).unwrap().remove_persisted(),
+                // Cache is already empty, or only pinned files remain.
+                None => return false,
+            }
+        }
+        true
+    }
+
+    fn refresh_pins(&self, mut f: impl FnMut(&ItemId) -> bool) {
+        let cache = self.cache.lock().unwrap();
+        for (id, file) in cache.iter() {
+            file.set_pinned(f(id));
+        }
+    }
+
+    fn set_pinned(&self, item_id: &ItemId, pinned: bool) {
+        if let Some(file) = self.cache.lock().unwrap().get_mut(item_id) {
+            file.set_pinned(pinned);
+        }
+    }
+
+    /// Remove cached files which no longer exist.
+    fn retain(&self, mut f: impl FnMut(&ItemId) -> bool) {
+        let mut cache = self.cache.lock().unwrap();
+        let removed = cache
+            .iter()
+            .map(|(id, _)| id)
+            .filter(|id| !f(id))
+            .cloned()
+            .collect::<Vec<_>>();
+        for id in removed {
+            log::debug!("Cached file {:?} is gone", id);
+            cache.remove(&id).unwrap().remove_persisted();
+        }
+    }
+
+    fn get(&self, item_id: &ItemId) -> Option<Arc<FileCache>> {
+        self.cache.lock().unwrap().get_mut(item_id).cloned()
+    }
+
+    /// Get a cached file to open. Invalidated ones are dropped and treated as a miss, so the
+    /// file is fetched again instead of failing every open until evicted.
+    async fn get_valid(&self, item_id: &ItemId) -> Option<Arc<FileCache>> {
+        let file = self.get(item_id)?;
+        if !matches!(file.state.lock().await.status, FileCacheStatus::Invalidated) {
+            return Some(file);
+        }
+        let mut cache = self.cache.lock().unwrap();
+        if cache
+            .get_mut(item_id)
+            .is_some_and(|cur| Arc::ptr_eq(cur, &file))
+        {
+            log::debug!("Drop invalidated cache of {:?}", item_id);
+            cache.remove(item_id);
+        }
+        None
+    }
+
+    fn try_alloc_and_fetch(
+        &self,
+        item_id: &ItemId,
+        meta: &RemoteFileMeta,
         truncate_to: Option<(u64, SystemTime)>,
         onedrive: ManagedOnedrive,
         event_tx: mpsc::Sender<UpdateEvent>,
@@ -629,17 +1572,11 @@ impl DiskCache {
             return Ok(Some(state.clone()));
         }
 
-        // Drop LRU until we have enough space.
-        while self.config.disk_cache.max_cached_file_size
-            < self.total_size.load(Ordering::Relaxed) + file_size
-        {
-            if cache.remove_lru().is_none() {
-                // Cache is already empty.
-                return Ok(None);
-            }
+        if !self.evict_for(&mut cache, file_size) {
+            return Ok(None);
         }
 
-        let cache_file = tempfile::tempfile_in(&self.dir)?;
+        let (cache_file, persist_path) = self.new_cache_file(item_id)?;
         cache_file.set_len(file_size)?;
 
         // The channel size doesn't really matter, since it's just for synchronization
@@ -653,22 +1590,28 @@ impl DiskCache {
                 truncate: download_truncate,
             },
             cache_file.into(),
-            &self.total_size,
+            persist_path,
+            &self.usage,
         );
+        if download_truncate.is_none() {
+            *file.hashes.lock().unwrap() = meta.hashes.clone();
+        }
         cache.insert(item_id.clone(), file.clone());
         tokio::spawn(FileCache::write_to_cache_thread(
             file.clone(),
             chunk_rx,
             pos_tx,
-            onedrive,
+            onedrive.clone(),
             client.clone(),
             event_tx,
             self.config.upload.clone(),
         ));
-        tokio::spawn(download_thread(
-            meta.size,
-            meta.download_url.clone(),
+        tokio::spawn(stream_thread(
+            item_id.clone(),
+            meta.clone(),
+            0,
             chunk_tx,
+            onedrive,
             client,
             self.config.download.clone(),
         ));
@@ -676,18 +1619,25 @@ impl DiskCache {
     }
 
     async fn insert_empty(&self, item_id: ItemId, c_tag: Tag) -> Result<Arc<FileCache>> {
-        let cache_file = tempfile::tempfile_in(&self.dir)?;
+        let (cache_file, persist_path) = self.new_cache_file(&item_id)?;
         let (file, old) = {
             let mut cache = self.cache.lock().unwrap();
+            let old = cache.remove(&item_id);
+            if let Some(old) = &old {
+                old.remove_persisted();
+            }
+            self.evict_for(&mut cache, 0);
             let (file, _) = FileCache::new(
                 item_id.clone(),
                 0,
                 c_tag,
                 FileCacheStatus::Available,
                 cache_file.into(),
-                &self.total_size,
+                persist_path,
+                &self.usage,
             );
-            let old = cache.insert(item_id, file.clone());
+            file.save_persist_meta(0);
+            cache.insert(item_id, file.clone());
             (file, old)
         };
         if let Some(old) = old {
@@ -698,6 +1648,7 @@ impl DiskCache {
 
     async fn sync_items(&self, items: &[DriveItem]) {
         let mut outdated = Vec::new();
+        let mut retagged = Vec::new();
         {
             let mut cache = self.cache.lock().unwrap();
             for item in items {
@@ -708,35 +1659,82 @@ impl DiskCache {
                     continue;
                 }
 
-                let id = item.id.clone().expect("Missing id");
+                let id = match &item.id {
+                    Some(id) => id.clone(),
+                    None => continue,
+                };
                 let file = match cache.get_mut(&id) {
                     Some(file) => file,
                     None => continue,
                 };
                 if item.deleted.is_some() {
                     log::debug!("Cached file {:?} is deleted", file.item_id);
-                    outdated.push(cache.remove(&id).unwrap());
+                    outdated.push((cache.remove(&id).unwrap(), true));
                     continue;
                 }
 
-                let c_tag = item.c_tag.clone().expect("Missing c_tag");
-                let old_c_tag = file.c_tag.lock().unwrap();
-                if *old_c_tag == c_tag {
+                let mut old_c_tag = file.c_tag.lock().unwrap();
+                let new_hashes = item
+                    .file
+                    .as_deref()
+                    .map(ContentHashes::parse)
+                    .unwrap_or_default();
+                if item.c_tag.as_ref() == Some(&*old_c_tag) {
                     log::debug!("Cached file {:?} is still up-to-date", *old_c_tag);
+                } else if let Some(c_tag) = item.c_tag.as_ref().filter(|_| {
+                    item.size.map(|sz| sz as u64) == Some(file.size.load(Ordering::Relaxed))
+                        && file.hashes.lock().unwrap().same_content(&new_hashes)
+                }) {
+                    // CTag may change without content changes, eg. on server-side conversions.
+                    log::debug!(
+                        "Cached file {:?} has the same content, ctag: {:?} -> {:?}",
+                        file.item_id,
+                        *old_c_tag,
+                        c_tag,
+                    );
+                    *old_c_tag = c_tag.clone();
+                    retagged.push(file.clone());
                 } else {
                     log::debug!(
                         "Cached file {:?} is outdated, ctag: {:?} -> {:?}",
                         file.item_id,
                         *old_c_tag,
-                        c_tag,
+                        item.c_tag,
                     );
                     drop(old_c_tag);
-                    outdated.push(cache.remove(&id).unwrap());
+                    outdated.push((file.clone(), false));
                 }
             }
         }
-        for file in outdated {
-            file.state.lock().await.status = FileCacheStatus::Invalidated;
+        for file in retagged {
+            let guard = file.state.lock().await;
+            // Downloading ones save it on completion.
+            if let FileCacheStatus::Available = guard.status {
+                file.save_persist_meta(guard.file_size);
+            }
+        }
+        for (file, deleted) in outdated {
+            let mut guard = file.state.lock().await;
+            if !deleted {
+                // Local changes are kept for the upload, which will detect the conflict.
+                if let FileCacheStatus::Dirty { .. } | FileCacheStatus::UploadFailed = guard.status
+                {
+                    log::warn!(
+                        "Cached file {:?} is changed remotely with local changes pending",
+                        file.item_id,
+                    );
+                    continue;
+                }
+                let mut cache = self.cache.lock().unwrap();
+                if cache
+                    .get_mut(&file.item_id)
+                    .is_some_and(|cur| Arc::ptr_eq(cur, &file))
+                {
+                    cache.remove(&file.item_id);
+                }
+            }
+            file.remove_persisted();
+            guard.status = FileCacheStatus::Invalidated;
         }
     }
 }
@@ -746,7 +1744,26 @@ struct FileCache {
     state: Mutex<FileCacheState>,
     item_id: ItemId,
     c_tag: SyncMutex<Tag>,
-    cache_total_size: Weak<AtomicU64>,
+    /// Remote content hashes of the cached content, if known and not modified locally.
+    hashes: SyncMutex<ContentHashes>,
+    // Path of the cache file if it's kept across mounts.
+    // Its metadata is saved as `<path>.json` when it's synchronized with remote side.
+    persist_path: Option<PathBuf>,
+    cache_usage: Weak<CacheUsage>,
+    /// The same as `FileCacheState::file_size`, readable without locking the state.
+    size: AtomicU64,
+    /// Whether it's counted in `CacheUsage::pinned`.
+    pinned: AtomicBool,
+    /// Whether the last upload failed permanently due to insufficient storage.
+    no_space: AtomicBool,
+}
+
+/// Metadata of a persistent cache file.
+#[derive(Debug, Serialize, Deserialize)]
+struct PersistMeta {
+    item_id: ItemId,
+    c_tag: Tag,
+    size: u64,
 }
 
 #[derive(Debug)]
@@ -777,6 +1794,9 @@ enum FileCacheStatus {
     },
     /// File is changed in remote side, local cache is invalidated.
     Invalidated,
+    /// Upload failed permanently. The local content is kept, and will be uploaded again on the
+    /// next modification.
+    UploadFailed,
 }
 
 impl FileCache {
@@ -786,10 +1806,11 @@ impl FileCache {
         c_tag: Tag,
         status: FileCacheStatus,
         cache_file: tokio::fs::File,
-        cache_total_size: &Arc<AtomicU64>,
+        persist_path: Option<PathBuf>,
+        cache_usage: &Arc<CacheUsage>,
     ) -> (Arc<Self>, watch::Sender<u64>) {
         let (pos_tx, pos_rx) = watch::channel(0);
-        cache_total_size.fetch_add(file_size, Ordering::Relaxed);
+        cache_usage.total.fetch_add(file_size, Ordering::Relaxed);
         let this = Arc::new(Self {
             state: Mutex::new(FileCacheState {
                 status,
@@ -799,11 +1820,95 @@ impl FileCache {
             }),
             item_id,
             c_tag: SyncMutex::new(c_tag),
-            cache_total_size: Arc::downgrade(cache_total_size),
+            hashes: SyncMutex::new(ContentHashes::default()),
+            persist_path,
+            cache_usage: Arc::downgrade(cache_usage),
+            size: AtomicU64::new(file_size),
+            pinned: AtomicBool::new(false),
+            no_space: AtomicBool::new(false),
         });
         (this, pos_tx)
     }
 
+    /// Update the file size, along with sizes of the cache.
+    fn set_size(&self, guard: &mut FileCacheState, new_size: u64) {
+        let old_size = std::mem::replace(&mut guard.file_size, new_size);
+        self.size.store(new_size, Ordering::Relaxed);
+        if let Some(usage) = self.cache_usage.upgrade() {
+            let pinned = self.pinned.load(Ordering::Relaxed);
+            for (size, counted) in [(&usage.total, true), (&usage.pinned, pinned)] {
+                if counted {
+                    size.fetch_add(new_size, Ordering::Relaxed);
+                    size.fetch_sub(old_size, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    fn set_pinned(&self, pinned: bool) {
+        if self.pinned.swap(pinned, Ordering::Relaxed) == pinned {
+            return;
+        }
+        if let Some(usage) = self.cache_usage.upgrade() {
+            let size = self.size.load(Ordering::Relaxed);
+            if pinned {
+                usage.pinned.fetch_add(size, Ordering::Relaxed);
+            } else {
+                usage.pinned.fetch_sub(size, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// The error reported for `FileCacheStatus::UploadFailed`.
+    fn upload_error(&self) -> Error {
+        if self.no_space.load(Ordering::Relaxed) {
+            Error::NoSpace
+        } else {
+            Error::UploadFailed
+        }
+    }
+
+    /// Forget content hashes from remote side after local modifications.
+    fn clear_hashes(&self) {
+        *self.hashes.lock().unwrap() = ContentHashes::default();
+    }
+
+    fn persist_meta_path(&self) -> Option<PathBuf> {
+        let mut path = self.persist_path.clone()?.into_os_string();
+        path.push(".json");
+        Some(path.into())
+    }
+
+    /// Mark the persistent cache file as synchronized with remote side.
+    fn save_persist_meta(&self, file_size: u64) {
+        if let Some(meta_path) = self.persist_meta_path() {
+            let meta = PersistMeta {
+                item_id: self.item_id.clone(),
+                c_tag: self.c_tag.lock().unwrap().clone(),
+                size: file_size,
+            };
+            let data = serde_json::to_vec(&meta).unwrap();
+            if let Err(err) = std::fs::write(&meta_path, data) {
+                log::warn!("Failed to save {}: {}", meta_path.display(), err);
+            }
+        }
+    }
+
+    /// Mark the persistent cache file as not reusable, since it's modified locally.
+    fn remove_persist_meta(&self) {
+        if let Some(meta_path) = self.persist_meta_path() {
+            let _ = std::fs::remove_file(meta_path);
+        }
+    }
+
+    /// Remove the persistent cache file. Opened handles can still access it.
+    fn remove_persisted(&self) {
+        if let Some(path) = &self.persist_path {
+            self.remove_persist_meta();
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
     async fn write_to_cache_thread(
         this: Arc<FileCache>,
         mut chunk_rx: mpsc::Receiver<Bytes>,
@@ -844,6 +1949,7 @@ impl FileCache {
                 }
                 FileCacheStatus::Downloading { truncate: None } => {
                     guard.status = FileCacheStatus::Available;
+                    this.save_persist_meta(guard.file_size);
                 }
                 _ => unreachable!(),
             }
@@ -862,9 +1968,10 @@ impl FileCache {
                     guard.file_size
                 }
                 FileCacheStatus::Downloading { .. } | FileCacheStatus::Invalidated => return,
-                FileCacheStatus::DownloadFailed { .. }
+                FileCacheStatus::DownloadFailed
                 | FileCacheStatus::Available
-                | FileCacheStatus::Dirty { .. } => unreachable!(),
+                | FileCacheStatus::Dirty { .. }
+                | FileCacheStatus::UploadFailed => unreachable!(),
             };
             assert!(download_size <= guard.file_size);
 
@@ -908,9 +2015,10 @@ impl FileCache {
                 truncate.map(|(sz, _)| sz).unwrap_or(guard.file_size)
             }
             FileCacheStatus::Invalidated => return,
-            FileCacheStatus::DownloadFailed { .. }
+            FileCacheStatus::DownloadFailed
             | FileCacheStatus::Available
-            | FileCacheStatus::Dirty { .. } => unreachable!(),
+            | FileCacheStatus::Dirty { .. }
+            | FileCacheStatus::UploadFailed => unreachable!(),
         };
 
         if pos < download_size {
@@ -933,10 +2041,13 @@ impl FileCache {
         if file_size <= offset || size == 0 {
             return Ok(Bytes::new());
         }
-        let end = offset + size as u64;
+        // Reads beyond EOF are short, so don't wait for bytes which will never come.
+        let end = offset.saturating_add(size as u64).min(file_size);
 
         match guard.status {
-            FileCacheStatus::Available | FileCacheStatus::Dirty { .. } => {}
+            FileCacheStatus::Available
+            | FileCacheStatus::Dirty { .. }
+            | FileCacheStatus::UploadFailed => {}
             FileCacheStatus::Invalidated => return Err(Error::Invalidated),
             FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
             FileCacheStatus::Downloading { .. } if end <= *guard.available_size.borrow() => {}
@@ -952,6 +2063,7 @@ impl FileCache {
                     FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
                     FileCacheStatus::Available
                     | FileCacheStatus::Dirty { .. }
+                    | FileCacheStatus::UploadFailed
                     | FileCacheStatus::Downloading { .. } => {}
                 }
             }
@@ -959,20 +2071,20 @@ impl FileCache {
 
         // File size should be retrieved after waiting since it may change.
         let end = end.min(guard.file_size);
+        if end <= offset {
+            return Ok(Bytes::new());
+        }
 
         let mut buf = vec![0u8; (end - offset) as usize];
-        guard
-            .cache_file
-            .seek(SeekFrom::Start(offset))
-            .await
-            .unwrap();
-        guard.cache_file.read_exact(&mut buf).await.unwrap();
+        guard.cache_file.seek(SeekFrom::Start(offset)).await?;
+        guard.cache_file.read_exact(&mut buf).await?;
         Ok(buf.into())
     }
 
+    /// Write at `offset`, or at the end of file if `None`, for `O_APPEND`.
     async fn write(
         this: &Arc<Self>,
-        offset: u64,
+        offset: Option<u64>,
         data: &[u8],
         event_tx: mpsc::Sender<UpdateEvent>,
         onedrive: ManagedOnedrive,
@@ -980,11 +2092,10 @@ impl FileCache {
         config: UploadConfig,
     ) -> Result<UpdatedFileAttr> {
         let mut guard = this.state.lock().await;
-        if config.max_size < offset + data.len() as u64 {
-            return Err(Error::FileTooLarge);
-        }
         match guard.status {
-            FileCacheStatus::Available | FileCacheStatus::Dirty { .. } => {}
+            FileCacheStatus::Available
+            | FileCacheStatus::Dirty { .. }
+            | FileCacheStatus::UploadFailed => {}
             FileCacheStatus::Invalidated => return Err(Error::Invalidated),
             FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
             FileCacheStatus::Downloading { .. } => {
@@ -996,12 +2107,19 @@ impl FileCache {
             }
         }
 
+        // The size is final after downloading, and appends are serialized by the lock.
+        let offset = offset.unwrap_or(guard.file_size);
+        if config.max_size < offset + data.len() as u64 {
+            return Err(Error::FileTooLarge);
+        }
         let mtime = SystemTime::now();
         match guard.status {
             FileCacheStatus::Invalidated => return Err(Error::Invalidated),
             FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
             FileCacheStatus::Downloading { .. } => unreachable!(),
-            FileCacheStatus::Dirty { .. } | FileCacheStatus::Available => {
+            FileCacheStatus::Dirty { .. }
+            | FileCacheStatus::Available
+            | FileCacheStatus::UploadFailed => {
                 this.queue_upload(
                     &mut guard,
                     mtime,
@@ -1013,26 +2131,18 @@ impl FileCache {
             }
         }
 
-        guard
-            .cache_file
-            .seek(SeekFrom::Start(offset))
-            .await
-            .unwrap();
-        guard.cache_file.write_all(data).await.unwrap();
+        guard.cache_file.seek(SeekFrom::Start(offset)).await?;
+        guard.cache_file.write_all(data).await?;
 
         let new_size = guard.file_size.max(offset + data.len() as u64);
-        if guard.file_size < new_size {
-            if let Some(total) = this.cache_total_size.upgrade() {
-                total.fetch_add(new_size - guard.file_size, Ordering::Relaxed);
-            }
-        }
         log::debug!(
             "Cached file {:?} is dirty, size: {} -> {}",
             this.item_id,
             guard.file_size,
             new_size,
         );
-        guard.file_size = new_size;
+        this.set_size(&mut guard, new_size);
+        this.clear_hashes();
 
         Ok(UpdatedFileAttr {
             item_id: this.item_id.clone(),
@@ -1043,6 +2153,33 @@ impl FileCache {
         })
     }
 
+    /// Get the parent and name to upload the conflicted copy of an item to.
+    async fn conflict_copy_location(
+        item_id: &ItemId,
+        onedrive: &ManagedOnedrive,
+    ) -> Result<(ItemId, String)> {
+        let item = onedrive
+            .get()
+            .await
+            .get_item_with_option(
+                ItemLocation::from_id(item_id),
+                ObjectOption::new()
+                    .select(&[DriveItemField::name, DriveItemField::parent_reference]),
+            )
+            .await?
+            .expect("No If-None-Match");
+        let parent_id = item
+            .parent_reference
+            .as_ref()
+            .and_then(|parent| parent.get("id")?.as_str())
+            .ok_or_else(|| Error::InvalidResponse(anyhow::anyhow!("Missing parent")))?;
+        let name = item
+            .name
+            .as_deref()
+            .ok_or_else(|| Error::InvalidResponse(anyhow::anyhow!("Missing name")))?;
+        Ok((ItemId(parent_id.to_owned()), conflict_copy_name(name)))
+    }
+
     fn queue_upload(
         self: &Arc<Self>,
         guard: &mut MutexGuard<'_, FileCacheState>,
@@ -1052,11 +2189,6 @@ impl FileCache {
         event_tx: mpsc::Sender<UpdateEvent>,
         config: UploadConfig,
     ) {
-        const UPLOAD_PART_SIZE: usize = 10 << 20;
-        static_assertions::const_assert!(
-            UPLOAD_PART_SIZE <= onedrive_api::UploadSession::MAX_PART_SIZE,
-        );
-
         let (flush_tx, flush_rx) = oneshot::channel();
         let (done_tx, done_rx) = watch::channel(false);
         let init_lock_mtime = Instant::now();
@@ -1065,6 +2197,8 @@ impl FileCache {
             flush_tx: Some(flush_tx),
             done_rx,
         };
+        self.no_space.store(false, Ordering::Relaxed);
+        self.remove_persist_meta();
 
         let this = self.clone();
         tokio::spawn(async move {
@@ -1072,7 +2206,39 @@ impl FileCache {
 
             let is_up_to_date = |status: &FileCacheStatus| matches!(status, FileCacheStatus::Dirty { lock_mtime, .. } if *lock_mtime == init_lock_mtime);
 
-            loop {
+            // Give up uploading. Local changes are kept until the next modification, and the
+            // file is no longer busy so it can be moved or removed.
+            let give_up = |no_space: bool| {
+                let (this, event_tx) = (&this, &event_tx);
+                async move {
+                    let mut guard = this.state.lock().await;
+                    if !is_up_to_date(&guard.status) {
+                        return;
+                    }
+                    guard.status = FileCacheStatus::UploadFailed;
+                    this.no_space.store(no_space, Ordering::Relaxed);
+                    drop(guard);
+                    let _ = event_tx
+                        .send(UpdateEvent::UploadFailed(this.item_id.clone()))
+                        .await;
+                    if no_space {
+                        let _ = event_tx.send(UpdateEvent::QuotaExceeded).await;
+                    }
+                }
+            };
+
+            // Capped exponential backoff, reset after every successful request.
+            let mut retry_delay = config.retry_delay;
+            let backoff = |retry_delay: &mut Duration| {
+                let delay = *retry_delay;
+                *retry_delay = (delay * 2).min(config.max_retry_delay);
+                time::sleep(delay)
+            };
+
+            // The parent and name to upload a conflicted copy to, once a conflict is detected.
+            let mut conflict_copy: Option<(ItemId, String)> = None;
+
+            let (item, file_size) = 'session: loop {
                 // Check not changed since last lock.
                 let file_size = {
                     let guard = this.state.lock().await;
@@ -1088,17 +2254,82 @@ impl FileCache {
                 initial.file_system_info = Some(Box::new(serde_json::json!({
                     "lastModifiedDateTime": humantime::format_rfc3339_seconds(mtime).to_string(),
                 })));
-                let sess = match onedrive
-                    .get()
-                    .await
-                    .new_upload_session_with_initial_option(
+                let base_c_tag = this.c_tag.lock().unwrap().clone();
+                let (loc, opt) = match &conflict_copy {
+                    // Only replace the version which local changes are based on.
+                    None => (
                         ItemLocation::from_id(&this.item_id),
-                        &initial,
-                        DriveItemPutOption::new().conflict_behavior(ConflictBehavior::Replace),
-                    )
+                        DriveItemPutOption::new()
+                            .conflict_behavior(ConflictBehavior::Replace)
+                            .if_match(&base_c_tag),
+                    ),
+                    Some((parent_id, name)) => (
+                        ItemLocation::child_of_id(
+                            parent_id,
+                            FileName::new(name).expect("Names from OneDrive are valid"),
+                        ),
+                        DriveItemPutOption::new().conflict_behavior(ConflictBehavior::Rename),
+                    ),
+                };
+                metrics::API_REQUESTS.inc();
+                let ret = onedrive
+                    .get()
                     .await
-                {
+                    .new_upload_session_with_initial_option(loc, &initial, opt)
+                    .await;
+                log::trace!(
+                    target: "vfs::api",
+                    "{}POST createUploadSession of {:?}: {:?}",
+                    op::tag(),
+                    this.item_id,
+                    ret.as_ref()
+                        .map(|(sess, _)| redact_url(sess.upload_url()))
+                        .map_err(ToString::to_string),
+                );
+                let sess = match ret {
                     Ok((sess, _)) => sess,
+                    Err(err)
+                        if conflict_copy.is_none()
+                            && err.status_code() == Some(StatusCode::PRECONDITION_FAILED) =>
+                    {
+                        if config.on_conflict == ConflictPolicy::Fail {
+                            log::error!(
+                                "File {:?} is changed remotely, giving up uploading local changes",
+                                this.item_id,
+                            );
+                            give_up(false).await;
+                            return;
+                        }
+                        match Self::conflict_copy_location(&this.item_id, &onedrive).await {
+                            Ok(target) => {
+                                log::warn!(
+                                    "File {:?} is changed remotely, uploading local changes as {:?}",
+                                    this.item_id,
+                                    target.1,
+                                );
+                                conflict_copy = Some(target);
+                            }
+                            Err(err) => {
+                                log::error!(
+                                    "Failed to get location of conflicted file {:?}, retrying: {}",
+                                    this.item_id,
+                                    err,
+                                );
+                                backoff(&mut retry_delay).await;
+                            }
+                        }
+                        continue;
+                    }
+                    Err(err) if is_permanent_upload_error(&err) => {
+                        log::error!(
+                            "Failed to create upload session of {:?} ({} B), giving up. Local changes are kept until the next modification: {}",
+                            this.item_id,
+                            file_size,
+                            err,
+                        );
+                        give_up(is_no_space_error(&err)).await;
+                        return;
+                    }
                     Err(err) => {
                         log::error!(
                             "Failed to create upload session of {:?} ({} B), retrying: {}",
@@ -1106,23 +2337,23 @@ impl FileCache {
                             file_size,
                             err,
                         );
-                        // Retry
-                        time::sleep(config.retry_delay).await;
+                        backoff(&mut retry_delay).await;
                         continue;
                     }
                 };
+                retry_delay = config.retry_delay;
 
                 // Upload parts.
                 let mut pos = 0u64;
-                let mut buf = vec![0u8; UPLOAD_PART_SIZE];
-                let item = loop {
-                    let end = file_size.min(pos + UPLOAD_PART_SIZE as u64);
+                let mut buf = vec![0u8; config.part_size];
+                loop {
+                    let end = file_size.min(pos + config.part_size as u64);
                     let len = (end - pos) as usize;
                     {
                         let mut guard = this.state.lock().await;
                         if !is_up_to_date(&guard.status) {
                             log::debug!("Upload session of {:?} outdates", this.item_id);
-                            if let Err(err) = sess.delete(onedrive.get().await.client()).await {
+                            if let Err(err) = delete_upload_session(&sess, &onedrive).await {
                                 log::error!(
                                     "Failed to delete outdated upload session of {:?}: {}",
                                     this.item_id,
@@ -1136,13 +2367,30 @@ impl FileCache {
                         guard.cache_file.read_exact(&mut buf[..len]).await.unwrap();
                     }
 
-                    match sess
-                        .upload_part(buf[..len].to_owned(), pos..end, file_size, &client)
-                        .await
-                    {
+                    config.max_bytes_per_sec.consume(len).await;
+                    metrics::API_REQUESTS.inc();
+                    let ret = {
+                        let _permit = onedrive.throttle().await;
+                        sess.upload_part(buf[..len].to_owned(), pos..end, file_size, &client)
+                            .await
+                    };
+                    log::trace!(
+                        target: "vfs::api",
+                        "{}PUT {} bytes {}-{}/{}: {:?}",
+                        op::tag(),
+                        redact_url(sess.upload_url()),
+                        pos,
+                        end - 1,
+                        file_size,
+                        ret.as_ref().map(Option::is_some).map_err(ToString::to_string),
+                    );
+                    if ret.is_ok() {
+                        metrics::UPLOADED_BYTES.add(len as u64);
+                    }
+                    match ret {
                         Ok(None) => {
                             assert_ne!(end, file_size);
-                            log::debug!(
+                            log::info!(
                                 "Uploaded part {}..{}/{} of file {:?}",
                                 pos,
                                 end,
@@ -1150,10 +2398,24 @@ impl FileCache {
                                 this.item_id,
                             );
                             pos = end;
+                            retry_delay = config.retry_delay;
                         }
                         Ok(Some(item)) => {
                             assert_eq!(end, file_size);
-                            break item;
+                            break 'session (item, file_size);
+                        }
+                        Err(err) if is_no_space_error(&err) => {
+                            log::error!(
+                                "Failed to upload part {}..{}/{} of file {:?}, giving up. Local changes are kept until the next modification: {}",
+                                pos,
+                                end,
+                                file_size,
+                                this.item_id,
+                                err,
+                            );
+                            let _ = delete_upload_session(&sess, &onedrive).await;
+                            give_up(true).await;
+                            return;
                         }
                         Err(err) => {
                             log::error!(
@@ -1164,70 +2426,340 @@ impl FileCache {
                                 this.item_id,
                                 err,
                             );
-                            // Retry
-                            time::sleep(config.retry_delay).await;
-                            continue;
+                            backoff(&mut retry_delay).await;
+
+                            // Resume from the first range the server is still missing.
+                            let ret = {
+                                let _permit = onedrive.throttle().await;
+                                sess.get_meta(&client).await
+                            };
+                            log::trace!(
+                                target: "vfs::api",
+                                "{}GET {}: {:?}",
+                                op::tag(),
+                                redact_url(sess.upload_url()),
+                                ret.as_ref()
+                                    .map(|meta| &meta.next_expected_ranges)
+                                    .map_err(ToString::to_string),
+                            );
+                            match ret {
+                                Ok(meta) => {
+                                    if let Some(range) = meta.next_expected_ranges.first() {
+                                        if range.start != pos {
+                                            log::info!(
+                                                "Resume uploading {:?} from {}/{}",
+                                                this.item_id,
+                                                range.start,
+                                                file_size,
+                                            );
+                                        }
+                                        pos = range.start.min(file_size);
+                                    }
+                                }
+                                // The session is expired or lost.
+                                Err(err) if err.status_code() == Some(StatusCode::NOT_FOUND) => {
+                                    log::warn!(
+                                        "Upload session of {:?} is lost, restarting",
+                                        this.item_id,
+                                    );
+                                    continue 'session;
+                                }
+                                Err(err) => {
+                                    log::warn!(
+                                        "Failed to query upload session of {:?}: {}",
+                                        this.item_id,
+                                        err,
+                                    );
+                                }
+                            }
                         }
                     }
-                };
+                }
+            };
 
-                let attr = super::InodeAttr::parse_item(&item).expect("Invalid attrs");
-                assert_eq!(item.id.as_ref(), Some(&this.item_id));
-                assert_eq!(attr.size, file_size);
-                let c_tag = item.c_tag.expect("Missing c_tag");
+            if conflict_copy.is_some() {
                 log::info!(
-                    "Uploaded {:?} ({} B), new c_tag: {:?}",
+                    "Uploaded conflicted copy of {:?} ({} B) as {:?}",
                     this.item_id,
                     file_size,
-                    c_tag,
+                    item.id,
                 );
-
-                {
-                    let mut guard = this.state.lock().await;
-                    match guard.status {
-                        FileCacheStatus::Downloading { .. } => unreachable!(),
-                        FileCacheStatus::Dirty { lock_mtime, .. }
-                            if lock_mtime == init_lock_mtime =>
-                        {
-                            guard.status = FileCacheStatus::Available;
-                        }
-                        FileCacheStatus::Invalidated => {
-                            log::warn!(
-                                "Cache invalidated during the upload of {:?}, maybe both changed? Suppress update event",
-                                this.item_id,
-                            );
-                            return;
-                        }
-                        // Race another upload.
-                        _ => {
-                            log::debug!("Racing upload? Suppress update event");
-                            return;
-                        }
-                    }
-                    *this.c_tag.lock().unwrap() = c_tag.clone();
-                    log::debug!("New c_tag of {:?} saved", this.item_id);
+                // The cache no longer matches any remote version. The new item will be synced.
+                let mut guard = this.state.lock().await;
+                if is_up_to_date(&guard.status) {
+                    guard.status = FileCacheStatus::Invalidated;
+                    this.remove_persisted();
                 }
-
-                let _ = event_tx
-                    .send(UpdateEvent::UpdateFile(UpdatedFileAttr {
-                        item_id: this.item_id.clone(),
-                        size: attr.size,
-                        mtime: attr.mtime,
-                        c_tag,
-                    }))
-                    .await;
                 let _ = done_tx.send(true);
-
                 return;
             }
+
+            let parsed = super::InodeAttr::parse_item(&item).and_then(|attr| {
+                use anyhow::Context as _;
+                Ok((attr, item.c_tag.clone().context("Missing c_tag")?))
+            });
+            let (attr, c_tag) = match parsed {
+                Ok(parsed) => parsed,
+                Err(err) => {
+                    log::error!(
+                        "Invalid response of uploading {:?}: {:#}",
+                        this.item_id,
+                        err
+                    );
+                    give_up(false).await;
+                    return;
+                }
+            };
+            assert_eq!(item.id.as_ref(), Some(&this.item_id));
+            assert_eq!(attr.size, file_size);
+            log::info!(
+                "Uploaded {:?} ({} B), new c_tag: {:?}",
+                this.item_id,
+                file_size,
+                c_tag,
+            );
+
+            {
+                let mut guard = this.state.lock().await;
+                match guard.status {
+                    FileCacheStatus::Downloading { .. } => unreachable!(),
+                    FileCacheStatus::Dirty { lock_mtime, .. } if lock_mtime == init_lock_mtime => {
+                        guard.status = FileCacheStatus::Available;
+                    }
+                    FileCacheStatus::Invalidated => {
+                        log::warn!(
+                            "Cache invalidated during the upload of {:?}, maybe both changed? Suppress update event",
+                            this.item_id,
+                        );
+                        return;
+                    }
+                    // Race another upload.
+                    _ => {
+                        log::debug!("Racing upload? Suppress update event");
+                        return;
+                    }
+                }
+                *this.c_tag.lock().unwrap() = c_tag.clone();
+                log::debug!("New c_tag of {:?} saved", this.item_id);
+                this.save_persist_meta(file_size);
+            }
+
+            let _ = event_tx
+                .send(UpdateEvent::UpdateFile(UpdatedFileAttr {
+                    item_id: this.item_id.clone(),
+                    size: attr.size,
+                    mtime: attr.mtime,
+                    c_tag,
+                }))
+                .await;
+            let _ = done_tx.send(true);
         });
     }
 }
 
+/// Name of the conflicted copy of `name`, like `foo (conflicted copy).txt`.
+fn conflict_copy_name(name: &str) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{stem} (conflicted copy).{ext}"),
+        _ => format!("{name} (conflicted copy)"),
+    }
+}
+
+/// Client errors which won't be resolved by retrying, like permission denied or quota exceeded.
+fn is_permanent_upload_error(err: &onedrive_api::Error) -> bool {
+    match err.status_code() {
+        Some(
+            StatusCode::UNAUTHORIZED | StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS,
+        ) => false,
+        _ if is_no_space_error(err) => true,
+        Some(status) => status.is_client_error(),
+        None => false,
+    }
+}
+
+/// Insufficient storage, or quota exceeded.
+fn is_no_space_error(err: &onedrive_api::Error) -> bool {
+    err.status_code() == Some(StatusCode::INSUFFICIENT_STORAGE)
+}
+
 impl Drop for FileCache {
     fn drop(&mut self) {
-        if let Some(arc) = self.cache_total_size.upgrade() {
-            arc.fetch_sub(self.state.get_mut().file_size, Ordering::Relaxed);
+        if let Some(usage) = self.cache_usage.upgrade() {
+            let size = self.state.get_mut().file_size;
+            usage.total.fetch_sub(size, Ordering::Relaxed);
+            if *self.pinned.get_mut() {
+                usage.pinned.fetch_sub(size, Ordering::Relaxed);
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Dependencies of writes, which never reach the server before the flush delay.
+    struct Writer {
+        config: UploadConfig,
+        onedrive: ManagedOnedrive,
+        client: reqwest::Client,
+    }
+
+    impl Writer {
+        fn new() -> Self {
+            let config = crate::config::Config::merge_for_test(&[]).unwrap();
+            Self {
+                config: config.vfs.file.upload,
+                onedrive: ManagedOnedrive::new_for_test(),
+                client: reqwest::Client::new(),
+            }
+        }
+
+        async fn write(&self, file: &Arc<FileCache>, offset: Option<u64>, data: &[u8]) -> u64 {
+            let (event_tx, _) = mpsc::channel(1);
+            let attr = FileCache::write(
+                file,
+                offset,
+                data,
+                event_tx,
+                self.onedrive.clone(),
+                self.client.clone(),
+                self.config.clone(),
+            )
+            .await
+            .unwrap();
+            attr.size
+        }
+    }
+
+    fn new_file(content: &[u8]) -> (Arc<FileCache>, Arc<CacheUsage>) {
+        use std::io::Write as _;
+
+        let mut cache_file = tempfile::tempfile().unwrap();
+        cache_file.write_all(content).unwrap();
+        let usage = Arc::default();
+        let (file, _) = FileCache::new(
+            ItemId("id".to_owned()),
+            content.len() as u64,
+            Tag("ctag".to_owned()),
+            FileCacheStatus::Available,
+            cache_file.into(),
+            None,
+            &usage,
+        );
+        (file, usage)
+    }
+
+    #[tokio::test]
+    async fn read_clamped() {
+        let (file, _) = new_file(b"hello");
+        let read = |offset, size| {
+            let file = file.clone();
+            async move { FileCache::read(&file, offset, size).await.unwrap() }
+        };
+        assert_eq!(&read(0, 5).await[..], b"hello");
+        assert_eq!(&read(3, 100).await[..], b"lo");
+        assert_eq!(&read(3, usize::MAX).await[..], b"lo");
+        assert_eq!(&read(5, 1).await[..], b"");
+        assert_eq!(&read(100, 1).await[..], b"");
+        assert_eq!(&read(u64::MAX, usize::MAX).await[..], b"");
+        assert_eq!(&read(1, 0).await[..], b"");
+
+        let (empty, _) = new_file(b"");
+        assert_eq!(&FileCache::read(&empty, 0, 4096).await.unwrap()[..], b"");
+    }
+
+    #[tokio::test]
+    async fn read_clamped_downloading() {
+        use std::io::Write as _;
+
+        let mut cache_file = tempfile::tempfile().unwrap();
+        cache_file.write_all(b"hello").unwrap();
+        let (file, pos_tx) = FileCache::new(
+            ItemId("id".to_owned()),
+            5,
+            Tag("ctag".to_owned()),
+            FileCacheStatus::Downloading { truncate: None },
+            cache_file.into(),
+            None,
+            &Arc::default(),
+        );
+        pos_tx.send(5).unwrap();
+        // Only waits for bytes before EOF.
+        assert_eq!(&FileCache::read(&file, 2, 100).await.unwrap()[..], b"llo");
+        assert_eq!(&FileCache::read(&file, 5, 100).await.unwrap()[..], b"");
+    }
+
+    #[tokio::test]
+    async fn handle_limit() {
+        let config = crate::config::Config::merge_for_test(&[]).unwrap();
+        let mut file_config = config.vfs.file;
+        file_config.max_open_handles = 2;
+        file_config.disk_cache.enable = false;
+        let (event_tx, _) = mpsc::channel(1);
+        let pool = FilePool::new(
+            event_tx,
+            ManagedOnedrive::new_for_test(),
+            reqwest::Client::new(),
+            file_config,
+            config.vfs.retry,
+            "drive",
+        )
+        .unwrap();
+
+        let (file, _) = new_file(b"");
+        let fh1 = pool.insert_handle(File::Cached(file.clone())).unwrap();
+        let fh2 = pool.insert_handle(File::Cached(file.clone())).unwrap();
+        let err = pool.insert_handle(File::Cached(file.clone())).unwrap_err();
+        assert_eq!(err.into_c_err(), libc::ENFILE);
+
+        pool.close(fh1).await.unwrap();
+        assert!(matches!(
+            pool.close(fh1).await,
+            Err(Error::InvalidHandle(_))
+        ));
+        let fh3 = pool.insert_handle(File::Cached(file.clone())).unwrap();
+        pool.close(fh2).await.unwrap();
+        pool.close(fh3).await.unwrap();
+        assert_eq!(pool.handle_count.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn write_append() {
+        let (file, usage) = new_file(b"ab");
+        let writer = Writer::new();
+        let appender = |data: &'static [u8]| {
+            let (file, writer) = (file.clone(), &writer);
+            async move {
+                for _ in 0..50 {
+                    writer.write(&file, None, data).await;
+                    tokio::task::yield_now().await;
+                }
+            }
+        };
+        tokio::join!(appender(b"x"), appender(b"yy"));
+
+        let content = FileCache::read(&file, 0, 1000).await.unwrap();
+        assert_eq!(content.len(), 2 + 50 + 100);
+        assert!(content.starts_with(b"ab"));
+        assert_eq!(content.iter().filter(|&&b| b == b'x').count(), 50);
+        // Each append is written at once.
+        let rest = String::from_utf8(content[2..].to_vec()).unwrap();
+        assert_eq!(rest.replace("yy", "").replace('x', ""), "");
+        assert_eq!(usage.total.load(Ordering::Relaxed), content.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn write_grow_then_append() {
+        let (file, usage) = new_file(b"ab");
+        let writer = Writer::new();
+        assert_eq!(writer.write(&file, Some(4), b"cd").await, 6);
+        assert_eq!(writer.write(&file, None, b"ef").await, 8);
+        // Writing inside does not move the end.
+        assert_eq!(writer.write(&file, Some(1), b"B").await, 8);
+        assert_eq!(writer.write(&file, None, b"g").await, 9);
+        let content = FileCache::read(&file, 0, 100).await.unwrap();
+        assert_eq!(&content[..], b"aB\0\0cdefg");
+        assert_eq!(usage.total.load(Ordering::Relaxed), 9);
+    }
+}
@@ -1,17 +1,30 @@
 //! Directory hierarchy and item attributes.
-use crate::vfs::error::{Error, Result};
+use crate::{
+    config::{de_duration_sec, de_duration_sec_opt},
+    login::ManagedOnedrive,
+    vfs::{
+        error::{Error, Result},
+        inode_id::InoAllocation,
+        retry::{self, retry, retry_checked},
+    },
+};
 use indexmap::IndexMap;
 use onedrive_api::{
-    option::{DriveItemPutOption, ObjectOption},
+    option::{CollectionOption, DriveItemPutOption, ObjectOption},
     resource::{DriveItem, DriveItemField},
-    ConflictBehavior, FileName, ItemId, ItemLocation, OneDrive, Tag,
+    ConflictBehavior, FileName, ItemId, ItemLocation, Tag,
 };
 use reqwest::StatusCode;
 use serde::Deserialize;
+use sharded_slab::Slab;
+use smallvec::SmallVec;
 use std::{
+    borrow::Cow,
     collections::{HashMap, HashSet},
-    sync::Mutex as SyncMutex,
-    time::SystemTime,
+    ffi::OsStr,
+    fmt::Write as _,
+    sync::{Arc, Mutex as SyncMutex},
+    time::{Duration, SystemTime},
 };
 
 #[derive(Debug, Clone)]
@@ -22,43 +35,214 @@ pub struct InodeAttr {
     pub is_directory: bool,
     // Files have CTag, while directories have not.
     pub c_tag: Option<Tag>,
+    pub e_tag: Option<Tag>,
+    // Whether this item is checked out on SharePoint, thus cannot be modified.
+    pub locked: bool,
+    // The package type like `oneNote`, for items that are neither files nor directories.
+    // They are exposed as empty files which cannot be opened.
+    pub package: Option<String>,
+    // Hashes of the remote content. They are cleared when changed locally.
+    pub hashes: ContentHashes,
+    // The MIME type known by OneDrive. Directories have none.
+    pub mime_type: Option<String>,
+    // Who created or last modified it, as `Display Name <email>`.
+    pub created_by: Option<String>,
+    pub modified_by: Option<String>,
     // Whether this file is changed locally and waiting for uploading.
     pub dirty: bool,
+    // Whether this file is the verified marker of a symlink. It depends on the name, siblings
+    // and content, so it's only set on attributes returned by `InodePool`.
+    pub is_symlink: bool,
+}
+
+/// Content hashes of files calculated by OneDrive.
+/// Personal drives have SHA1 and SHA256, while business drives only have QuickXorHash.
+#[derive(Debug, Clone, Default)]
+pub struct ContentHashes {
+    pub quick_xor: Option<String>,
+    pub sha1: Option<String>,
+    pub sha256: Option<String>,
+}
+
+impl ContentHashes {
+    pub fn parse(file: &serde_json::Value) -> Self {
+        let get = |field: &str| Some(file.get("hashes")?.get(field)?.as_str()?.to_owned());
+        Self {
+            quick_xor: get("quickXorHash"),
+            sha1: get("sha1Hash"),
+            sha256: get("sha256Hash"),
+        }
+    }
+
+    /// Whether both sides have a same kind of hash, which all agree.
+    pub fn same_content(&self, other: &Self) -> bool {
+        let pairs = [
+            (&self.quick_xor, &other.quick_xor),
+            (&self.sha1, &other.sha1),
+            (&self.sha256, &other.sha256),
+        ];
+        let known = pairs
+            .iter()
+            .filter_map(|(a, b)| Some((a.as_ref()?, b.as_ref()?)));
+        let mut any = false;
+        for (a, b) in known {
+            if !a.eq_ignore_ascii_case(b) {
+                return false;
+            }
+            any = true;
+        }
+        any
+    }
+}
+
+/// Warn about an expected field missing from API responses, only once for each field, since it's
+/// likely a change of the API affecting every item.
+fn warn_missing_field(field: &'static str) {
+    static WARNED: SyncMutex<Vec<&'static str>> = SyncMutex::new(Vec::new());
+    let mut warned = WARNED.lock().unwrap();
+    if !warned.contains(&field) {
+        warned.push(field);
+        log::warn!(
+            "Field `{}` is missing from items, using a default instead. The API may be changed",
+            field,
+        );
+    }
+}
+
+/// Parse a RFC 3339 timestamp, with either `Z` or a numeric offset suffix.
+fn parse_rfc3339(s: &str) -> Option<SystemTime> {
+    // `humantime` only accepts UTC timestamps.
+    let split = s
+        .len()
+        .checked_sub(6)
+        .and_then(|pos| Some((s.get(..pos)?, s.get(pos..)?)));
+    let (utc, offset) = match split {
+        Some((utc, offset))
+            if matches!(offset.as_bytes()[0], b'+' | b'-') && offset.as_bytes()[3] == b':' =>
+        {
+            let hours: u64 = offset.get(1..3)?.parse().ok()?;
+            let minutes: u64 = offset.get(4..6)?.parse().ok()?;
+            (
+                utc,
+                Some((
+                    offset.starts_with('+'),
+                    Duration::from_secs((hours * 60 + minutes) * 60),
+                )),
+            )
+        }
+        _ => return humantime::parse_rfc3339(s).ok(),
+    };
+    let time = humantime::parse_rfc3339(&format!("{}Z", utc)).ok()?;
+    match offset {
+        Some((true, offset)) => time.checked_sub(offset),
+        Some((false, offset)) => time.checked_add(offset),
+        None => Some(time),
+    }
 }
 
 impl InodeAttr {
     pub fn parse_item(item: &DriveItem) -> anyhow::Result<InodeAttr> {
         use anyhow::Context;
 
-        fn parse_time(fs_info: &serde_json::Value, field: &str) -> anyhow::Result<SystemTime> {
-            let s = fs_info
-                .get(field)
-                .and_then(|v| v.as_str())
-                .with_context(|| format!("Missing {}", field))?;
-            humantime::parse_rfc3339(s).with_context(|| format!("Invalid time: {:?}", s))
+        // Prefer client-side times in `fileSystemInfo`, then ones of the item itself.
+        fn parse_time(item: &DriveItem, field: &str) -> anyhow::Result<Option<SystemTime>> {
+            let s = item
+                .file_system_info
+                .as_ref()
+                .and_then(|fs_info| fs_info.get(field)?.as_str())
+                .or(match field {
+                    "createdDateTime" => item.created_date_time.as_deref(),
+                    "lastModifiedDateTime" => item.last_modified_date_time.as_deref(),
+                    _ => None,
+                });
+            s.map(|s| parse_rfc3339(s).with_context(|| format!("Invalid time: {:?}", s)))
+                .transpose()
+        }
+
+        // Format the user of an identity set, falling back to the application or the device.
+        fn parse_identity(identity: Option<&serde_json::Value>) -> Option<String> {
+            let identity = ["user", "application", "device"]
+                .iter()
+                .find_map(|kind| identity?.get(kind))?;
+            let name = identity.get("displayName").and_then(|name| name.as_str());
+            let email = identity.get("email").and_then(|email| email.as_str());
+            match (name, email) {
+                (Some(name), Some(email)) => Some(format!("{} <{}>", name, email)),
+                (Some(s), None) | (None, Some(s)) => Some(s.to_owned()),
+                (None, None) => None,
+            }
         }
 
         fn parse_attr(item: &DriveItem) -> anyhow::Result<InodeAttr> {
-            let fs_info = item
-                .file_system_info
-                .as_ref()
-                .context("Missing file_system_info")?;
+            let mtime = parse_time(item, "lastModifiedDateTime")?;
+            let crtime = parse_time(item, "createdDateTime")?;
+            // Use the other one if only one timestamp is available.
+            // Timestamps are only informational, so don't lose the item for them.
+            let (mtime, crtime) = match (mtime, crtime) {
+                (Some(mtime), Some(crtime)) => (mtime, crtime),
+                (Some(time), None) | (None, Some(time)) => (time, time),
+                (None, None) => {
+                    warn_missing_field("lastModifiedDateTime");
+                    (SystemTime::UNIX_EPOCH, SystemTime::UNIX_EPOCH)
+                }
+            };
+            let package = item.package.as_ref().map(|package| {
+                match package.get("type").and_then(|ty| ty.as_str()) {
+                    Some(ty) => ty.to_owned(),
+                    None => "unknown".to_owned(),
+                }
+            });
             Ok(InodeAttr {
-                size: item.size.context("Missing size")? as u64,
-                mtime: parse_time(fs_info, "lastModifiedDateTime")?,
-                crtime: parse_time(fs_info, "createdDateTime")?,
+                // The size of a package is the total size of its content, which cannot be read.
+                size: if package.is_some() {
+                    0
+                } else {
+                    item.size.context("Missing size")? as u64
+                },
+                mtime,
+                crtime,
                 is_directory: item.folder.is_some(),
                 c_tag: if item.folder.is_some() {
                     None
+                } else if package.is_some() {
+                    item.c_tag.clone()
                 } else {
                     Some(item.c_tag.clone().context("Missing c_tag for file")?)
                 },
+                e_tag: item.e_tag.clone(),
+                locked: item
+                    .publication
+                    .as_ref()
+                    .and_then(|publication| publication.get("level")?.as_str())
+                    == Some("checkout"),
+                package,
+                hashes: item
+                    .file
+                    .as_deref()
+                    .map(ContentHashes::parse)
+                    .unwrap_or_default(),
+                mime_type: item
+                    .file
+                    .as_ref()
+                    .and_then(|file| Some(file.get("mimeType")?.as_str()?.to_owned())),
+                created_by: parse_identity(item.created_by.as_deref()),
+                modified_by: parse_identity(item.last_modified_by.as_deref()),
                 dirty: false,
+                is_symlink: false,
             })
         }
 
         parse_attr(item).with_context(|| format!("Failed to parse item: {:?}", item))
     }
+
+    /// Parse an item returned from API requests.
+    pub fn parse_item_with_id(item: DriveItem) -> Result<(ItemId, InodeAttr)> {
+        let attr = Self::parse_item(&item).map_err(Error::InvalidResponse)?;
+        let id = item
+            .id
+            .ok_or_else(|| Error::InvalidResponse(anyhow::anyhow!("Missing id")))?;
+        Ok((id, attr))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -66,24 +250,285 @@ pub struct DirEntry {
     pub item_id: ItemId,
     pub name: String,
     pub attr: InodeAttr,
+    /// The offset to continue reading after this entry.
+    pub next_offset: u64,
 }
 
+/// Remote changes which may be cached by the kernel.
+#[derive(Debug, Default)]
+pub struct Invalidations {
+    /// Items whose attributes or content are changed.
+    pub items: Vec<ItemId>,
+    /// Directory entries `(parent_id, name)` which are added or removed.
+    pub entries: Vec<(ItemId, String)>,
+}
+
+/// Names and ids of directory children. Attributes are looked up when read.
+type DirSnapshot = Box<[(String, ItemId)]>;
+
+/// CTag of the content of a marker file, and the symlink target in it.
+type SymlinkTarget = (Tag, Arc<[u8]>);
+
 #[derive(Debug, Deserialize)]
-pub struct Config {}
+pub struct Config {
+    pub ino_allocation: InoAllocation,
+    case_insensitive: bool,
+    #[serde(default, deserialize_with = "de_duration_sec_opt")]
+    max_attr_ttl: Option<Duration>,
+    #[serde(default)]
+    max_attr_ttl_overrides: Vec<TtlOverride>,
+    hidden_names: Vec<String>,
+    hide_packages: bool,
+    hide_from_lookup: bool,
+    #[serde(default)]
+    escape_chars: String,
+    symlink_suffix: String,
+    on_invalid_item: InvalidItemPolicy,
+    dir_order: DirOrder,
+}
+
+/// The order of directory entries.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum DirOrder {
+    /// As they are synced from OneDrive.
+    Server,
+    /// By names.
+    Name,
+    /// By case-folded names, then names.
+    NameCaseInsensitive,
+    /// By modification times, the oldest first, then names.
+    Mtime,
+}
+
+/// What to do with items whose metadata fails to parse.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum InvalidItemPolicy {
+    /// Omit them and keep the rest of the directory usable.
+    Skip,
+    /// Fail opening their parent directories with `EIO`.
+    Fail,
+}
+
+#[derive(Debug, Deserialize)]
+struct TtlOverride {
+    path: String,
+    #[serde(deserialize_with = "de_duration_sec")]
+    max_attr_ttl: Duration,
+}
+
+/// Header of marker files of symlinks, followed by the target.
+/// Files without it are never symlinks, even if their names match.
+pub const SYMLINK_MAGIC: &[u8] = b"!<onedrive-fuse-symlink>\n";
+/// Max length of symlink targets, which is the max length of paths.
+pub const MAX_SYMLINK_TARGET: usize = libc::PATH_MAX as usize;
+
+/// Get the target from the content of a marker file, if it's valid.
+pub fn parse_symlink_marker(data: &[u8]) -> Option<&[u8]> {
+    data.strip_prefix(SYMLINK_MAGIC)
+        .filter(|target| !target.is_empty() && target.len() <= MAX_SYMLINK_TARGET)
+}
+
+impl Config {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for ovr in &self.max_attr_ttl_overrides {
+            anyhow::ensure!(
+                ovr.path.starts_with('/'),
+                "Paths in `vfs.inode.max_attr_ttl_overrides` must start with `/`, got {:?}",
+                ovr.path,
+            );
+        }
+        anyhow::ensure!(
+            self.escape_chars
+                .chars()
+                .all(|c| c.is_ascii() && c != '/' && c != '\0'),
+            "`vfs.inode.escape_chars` must only contain ASCII characters except `/` and NUL, got {:?}",
+            self.escape_chars,
+        );
+        anyhow::ensure!(
+            self.symlink_suffix.is_empty() || FileName::new(&self.symlink_suffix).is_some(),
+            "`vfs.inode.symlink_suffix` must be valid in file names, got {:?}",
+            self.symlink_suffix,
+        );
+        Ok(())
+    }
+
+    /// Whether a child should be omitted from listings.
+    fn is_hidden(&self, name: &str, attr: &InodeAttr) -> bool {
+        (self.hide_packages && attr.package.is_some())
+            || self
+                .hidden_names
+                .iter()
+                .any(|pat| wildcard_match(&pat.to_lowercase(), &name.to_lowercase()))
+    }
+
+    /// The local name of a child if it may be the marker file `<name><symlink_suffix>` of a
+    /// symlink, by its name and size. Markers whose local names are taken by siblings are
+    /// exposed as regular files.
+    fn symlink_name<'a>(
+        &self,
+        children: &DirChildren,
+        name: &'a str,
+        attr: &InodeAttr,
+    ) -> Option<&'a str> {
+        if self.symlink_suffix.is_empty()
+            || attr.is_directory
+            || attr.package.is_some()
+            || !Self::is_symlink_size(attr.size)
+        {
+            return None;
+        }
+        name.strip_suffix(&*self.symlink_suffix)
+            .filter(|link_name| {
+                !link_name.is_empty()
+                    && children
+                        .get_with(link_name, self.case_insensitive)
+                        .is_none()
+            })
+    }
+
+    fn is_symlink_size(size: u64) -> bool {
+        let magic_len = SYMLINK_MAGIC.len() as u64;
+        magic_len < size && size <= magic_len + MAX_SYMLINK_TARGET as u64
+    }
+
+    fn is_escaped(&self, c: char) -> bool {
+        !self.escape_chars.is_empty() && (c == '%' || self.escape_chars.contains(c))
+    }
+
+    /// Percent-encode characters in `escape_chars` for names shown locally.
+    /// `%` itself is also encoded when enabled, so that it is reversible by `unescape_name`.
+    fn escape_name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        if !name.contains(|c| self.is_escaped(c)) {
+            return Cow::Borrowed(name);
+        }
+        let mut buf = String::with_capacity(name.len() + 8);
+        for c in name.chars() {
+            if self.is_escaped(c) {
+                write!(buf, "%{:02X}", c as u32).unwrap();
+            } else {
+                buf.push(c);
+            }
+        }
+        Cow::Owned(buf)
+    }
+
+    /// Decode escapes produced by `escape_name`. Other `%` sequences are kept as is.
+    ///
+    /// It's not the exact inverse for names which `escape_name` never produces, like `100%` with
+    /// a lone `%` or `%3a` in lower cases. They are still accepted when looking up or creating,
+    /// but listed in the canonical form, like `100%25` and `%3A`.
+    fn unescape_name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        if self.escape_chars.is_empty() || !name.contains('%') {
+            return Cow::Borrowed(name);
+        }
+        let mut buf = String::with_capacity(name.len());
+        let mut rest = name;
+        while let Some(pos) = rest.find('%') {
+            buf.push_str(&rest[..pos]);
+            rest = &rest[pos..];
+            match rest
+                .get(1..3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .map(char::from)
+                .filter(|&c| self.is_escaped(c))
+            {
+                Some(c) => {
+                    buf.push(c);
+                    rest = &rest[3..];
+                }
+                None => {
+                    buf.push('%');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        buf.push_str(rest);
+        Cow::Owned(buf)
+    }
+}
+
+/// Match `name` against `pattern` with `*` for any sequence and `?` for any single character.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let (pat, name) = (
+        pattern.chars().collect::<Vec<_>>(),
+        name.chars().collect::<Vec<_>>(),
+    );
+    let (mut p, mut n) = (0, 0);
+    // The position after the last `*` in the pattern, and where it starts matching in the name.
+    let mut backtrack = None;
+    while n < name.len() {
+        match pat.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, n));
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` eat one more character.
+                Some((bp, bn)) => {
+                    p = bp;
+                    n = bn + 1;
+                    backtrack = Some((bp, bn + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pat[p..].iter().all(|&c| c == '*')
+}
+
+impl TtlOverride {
+    /// Whether `path` is or is under the path of this override. It's a prefix of whole path
+    /// components, without wildcards, so `/a` matches `/a/b` but not `/ab`.
+    fn matches(&self, path: &str) -> bool {
+        is_under_path(&self.path, path)
+    }
+}
+
+/// Whether `path` is or is under `prefix`.
+/// Paths are compared case-insensitively, as OneDrive does.
+fn is_under_path(prefix: &str, path: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/').to_lowercase();
+    let path = path.to_lowercase();
+    path == prefix || path.starts_with(&(prefix + "/"))
+}
 
 pub struct InodePool {
     tree: SyncMutex<InodeTree>,
+    /// Snapshots of directory entries for each opened directory handle, taken on the first read.
+    /// Offsets of `read_dir` index into the snapshot, so they are stable even if the directory
+    /// is modified during the iteration.
+    dir_handles: Slab<SyncMutex<Option<DirSnapshot>>>,
+    /// Ids of marker files with verified headers -> Their targets.
+    /// Always locked after `tree`.
+    symlink_targets: SyncMutex<HashMap<ItemId, SymlinkTarget>>,
+    /// Possible marker files whose content is not verified yet.
+    unverified_symlinks: SyncMutex<HashSet<ItemId>>,
+    retry: retry::Config,
+    config: Config,
 }
 
 struct InodeTree {
     // ItemId -> Content, (parent_id, parent_child_idx)
     map: HashMap<ItemId, (Inode, Option<(ItemId, usize)>)>,
+    /// Items failed to parse in the last sync -> Their parent ids.
+    invalid_items: HashMap<ItemId, ItemId>,
+    /// Directory entries `(parent_id, name)` whose items are changed by resolving name
+    /// conflicts, to be invalidated on the next sync.
+    renamed_entries: Vec<(ItemId, String)>,
 }
 
 impl InodeTree {
     fn new() -> Self {
         Self {
             map: HashMap::new(),
+            invalid_items: HashMap::new(),
+            renamed_entries: Vec::new(),
         }
     }
 
@@ -95,6 +540,33 @@ impl InodeTree {
         self.map.get_mut(id).map(|(inode, _)| inode)
     }
 
+    fn parent_of(&self, id: &ItemId) -> Option<(ItemId, String)> {
+        let (parent_id, child_idx) = self.map.get(id)?.1.as_ref()?;
+        let (name, _) = self
+            .get(parent_id)?
+            .children()
+            .ok()?
+            .get_index(*child_idx)?;
+        Some((parent_id.clone(), name.clone()))
+    }
+
+    /// Get the path of an item from the root, like `/foo/bar`.
+    fn path_of(&self, id: &ItemId) -> Option<String> {
+        let mut names = Vec::new();
+        let mut cur = id;
+        while let Some((parent_id, child_idx)) = &self.map.get(cur)?.1 {
+            let (name, _) = self
+                .get(parent_id)?
+                .children()
+                .ok()?
+                .get_index(*child_idx)?;
+            names.push(name.as_str());
+            cur = parent_id;
+        }
+        names.reverse();
+        Some(format!("/{}", names.join("/")))
+    }
+
     // Insert a new item, or panic if already exists.
     fn insert_item(&mut self, id: ItemId, attr: InodeAttr) {
         assert!(
@@ -110,7 +582,7 @@ impl InodeTree {
         let (inode, _) = self.map.remove(id).unwrap();
         // For directory, also detach all children.
         if let Inode::Dir { children, .. } = inode {
-            for (_, child_id) in children {
+            for child_id in children.into_item_ids() {
                 self.set_parent(&child_id, None);
             }
         }
@@ -118,30 +590,101 @@ impl InodeTree {
 
     // Set parent of an existing item, or panic if source item or parent item or does not exists.
     fn set_parent(&mut self, item_id: &ItemId, new_parent: Option<(ItemId, String)>) {
-        // Detach from old parent.
-        if let Some((parent_id, child_idx)) =
-            self.map.get_mut(item_id).expect("Item not exists").1.take()
-        {
-            let children = self.get_mut(&parent_id).unwrap().children_mut().unwrap();
-            children.swap_remove_index(child_idx);
-            if child_idx < children.len() {
-                // Previous last child is swapped to a `child_idx`. Maintain parent reference.
-                let swapped_child_item_id = children[child_idx].clone();
-                let (_, parent) = self.map.get_mut(&swapped_child_item_id).unwrap();
-                parent.as_mut().unwrap().1 = child_idx;
+        let freed = self.detach(item_id);
+        if let Some((new_parent_id, child_name)) = new_parent {
+            self.resolve_names(&new_parent_id, &child_name, Some(item_id));
+        }
+        // Children shadowed by us may take the name back, or shift their aliases.
+        if let Some((parent_id, real_name)) = freed {
+            self.resolve_names(&parent_id, &real_name, None);
+        }
+    }
+
+    /// Detach an item from its parent. Return the parent and the real name of the item.
+    fn detach(&mut self, item_id: &ItemId) -> Option<(ItemId, String)> {
+        let (parent_id, child_idx) = self
+            .map
+            .get_mut(item_id)
+            .expect("Item not exists")
+            .1
+            .take()?;
+        let children = self.get_mut(&parent_id).unwrap().children_mut().unwrap();
+        let real_name = children.shadowed.get(item_id).cloned();
+        let (name, _) = children.swap_remove_index(child_idx).unwrap();
+        if child_idx < children.len() {
+            // Previous last child is swapped to a `child_idx`. Maintain parent reference.
+            let swapped_child_item_id = children.get_index(child_idx).unwrap().1.clone();
+            let (_, parent) = self.map.get_mut(&swapped_child_item_id).unwrap();
+            parent.as_mut().unwrap().1 = child_idx;
+        }
+        Some((parent_id, real_name.unwrap_or(name)))
+    }
+
+    /// Attach children named `real_name`, including `new_child` if any, ordered by their ids.
+    /// The lowest one gets the real name and others are exposed as `name (2)` and so on, so the
+    /// exposed names don't depend on the order of changes.
+    fn resolve_names(&mut self, parent_id: &ItemId, real_name: &str, new_child: Option<&ItemId>) {
+        let children = self.get(parent_id).unwrap().children().unwrap();
+        let mut members = children
+            .shadowed
+            .iter()
+            .filter(|(_, name)| *name == real_name)
+            .map(|(item_id, _)| item_id.clone())
+            .chain(
+                children
+                    .get(real_name)
+                    .filter(|item_id| !children.shadowed.contains_key(*item_id))
+                    .cloned(),
+            )
+            .collect::<Vec<_>>();
+        if members.is_empty() {
+            if let Some(item_id) = new_child {
+                self.attach(item_id, parent_id, real_name.to_owned());
             }
+            return;
         }
 
-        // Set a new parent.
-        if let Some((new_parent_id, child_name)) = new_parent {
-            let (inode, _) = self.map.get_mut(&new_parent_id).expect("Item not exists");
-            let children = inode.children_mut().unwrap();
-            let (child_idx, old) = children.insert_full(child_name, item_id.clone());
-            assert!(old.is_none(), "Duplicated name");
-            assert_eq!(child_idx, children.len() - 1);
-            self.map.get_mut(item_id).unwrap().1 = Some((new_parent_id, child_idx));
+        let mut old_names = HashMap::new();
+        for item_id in &members {
+            let (_, name) = self.parent_of(item_id).expect("Attached");
+            self.detach(item_id);
+            old_names.insert(item_id.clone(), name);
+        }
+        members.extend(new_child.cloned());
+        members.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        for item_id in &members {
+            let name = self.attach(item_id, parent_id, real_name.to_owned());
+            match old_names.get(item_id) {
+                Some(old_name) if *old_name != name => {
+                    log::debug!(
+                        "Item {:?} is renamed from {:?} to {:?}",
+                        item_id,
+                        old_name,
+                        name
+                    );
+                    self.renamed_entries
+                        .push((parent_id.clone(), old_name.clone()));
+                    self.renamed_entries.push((parent_id.clone(), name));
+                }
+                // The new child exposed by an alias, which may be negatively cached.
+                None if name != real_name => {
+                    self.renamed_entries.push((parent_id.clone(), name));
+                }
+                _ => {}
+            }
         }
     }
+
+    /// Attach a detached item to a parent. Return the exposed name, which may be an alias.
+    fn attach(&mut self, item_id: &ItemId, parent_id: &ItemId, name: String) -> String {
+        let (inode, _) = self.map.get_mut(parent_id).expect("Item not exists");
+        let children = inode.children_mut().unwrap();
+        let child_idx = children.insert(name, item_id.clone());
+        assert_eq!(child_idx, children.len() - 1);
+        let name = children.get_index(child_idx).unwrap().0.clone();
+        self.map.get_mut(item_id).unwrap().1 = Some((parent_id.clone(), child_idx));
+        name
+    }
 }
 
 #[derive(Debug)]
@@ -199,8 +742,136 @@ impl Inode {
     }
 }
 
-// Child name -> Child item id.
-type DirChildren = IndexMap<String, ItemId>;
+/// Children of a directory.
+///
+/// OneDrive resolves names case-insensitively, so besides the exact name, children are also
+/// indexed by their case-folded name. Exact matches always take precedence.
+#[derive(Debug)]
+struct DirChildren {
+    // Child name -> Child item id.
+    entries: IndexMap<String, ItemId>,
+    // Case-folded child name -> Indices of children in `entries`, the earliest inserted first.
+    folded: HashMap<String, SmallVec<[usize; 1]>>,
+    // Child item id -> Real name, for children renamed due to name conflicts.
+    shadowed: HashMap<ItemId, String>,
+}
+
+impl DirChildren {
+    fn new() -> Self {
+        Self {
+            entries: IndexMap::new(),
+            folded: HashMap::new(),
+            shadowed: HashMap::new(),
+        }
+    }
+
+    /// Fold cases as OneDrive does. Like NTFS, it compares names by simple uppercase mapping of
+    /// each character rather than the full Unicode case folding, so `ß` does not match `SS`.
+    fn fold(name: &str) -> String {
+        name.chars()
+            .map(|c| {
+                let mut upper = c.to_uppercase();
+                match (upper.next(), upper.next()) {
+                    (Some(upper), None) => upper,
+                    // Multi-character mappings are not applied.
+                    _ => c,
+                }
+            })
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn get(&self, name: &str) -> Option<&ItemId> {
+        self.entries.get(name).or_else(|| {
+            let idx = *self.folded.get(&Self::fold(name))?.first()?;
+            Some(&self.entries[idx])
+        })
+    }
+
+    /// Get a child, only by the exact name if not `case_insensitive`.
+    fn get_with(&self, name: &str, case_insensitive: bool) -> Option<&ItemId> {
+        if case_insensitive {
+            self.get(name)
+        } else {
+            self.entries.get(name)
+        }
+    }
+
+    fn get_index(&self, idx: usize) -> Option<(&String, &ItemId)> {
+        self.entries.get_index(idx)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&String, &ItemId)> {
+        self.entries.iter()
+    }
+
+    /// Insert a new child and return its index.
+    ///
+    /// If the name is already taken by another child, which may happen transiently during
+    /// syncing, the new child is exposed as `name (2)`, `name (3)` and so on until its real name
+    /// is available again.
+    fn insert(&mut self, name: String, item_id: ItemId) -> usize {
+        let name = if self.entries.contains_key(&name) {
+            let alias = (2..)
+                .map(|n| match name.rsplit_once('.') {
+                    Some((stem, ext)) if !stem.is_empty() => format!("{stem} ({n}).{ext}"),
+                    _ => format!("{name} ({n})"),
+                })
+                .find(|alias| !self.entries.contains_key(alias))
+                .unwrap();
+            log::warn!(
+                "Duplicated name {:?} of item {:?}, exposed as {:?}",
+                name,
+                item_id,
+                alias,
+            );
+            self.shadowed.insert(item_id.clone(), name);
+            alias
+        } else {
+            name
+        };
+        let key = Self::fold(&name);
+        let (idx, old) = self.entries.insert_full(name, item_id);
+        assert!(old.is_none());
+        self.folded.entry(key).or_default().push(idx);
+        idx
+    }
+
+    /// Remove a child by index, returning its name in this directory.
+    fn swap_remove_index(&mut self, idx: usize) -> Option<(String, ItemId)> {
+        let (name, item_id) = self.entries.swap_remove_index(idx)?;
+        self.shadowed.remove(&item_id);
+        let key = Self::fold(&name);
+        let indices = self.folded.get_mut(&key).expect("Indexed");
+        indices.retain(|i| *i != idx);
+        if indices.is_empty() {
+            self.folded.remove(&key);
+        }
+        // The last child is moved into the removed position.
+        let moved_from = self.entries.len();
+        if let Some((moved_name, _)) = self.entries.get_index(idx) {
+            let indices = self
+                .folded
+                .get_mut(&Self::fold(moved_name))
+                .expect("Indexed");
+            for i in indices.iter_mut().filter(|i| **i == moved_from) {
+                *i = idx;
+            }
+        }
+        Some((name, item_id))
+    }
+
+    fn into_item_ids(self) -> impl Iterator<Item = ItemId> {
+        self.entries.into_values()
+    }
+}
 
 impl InodePool {
     pub const SYNC_SELECT_FIELDS: &'static [DriveItemField] = &[
@@ -213,77 +884,406 @@ impl InodePool {
         DriveItemField::deleted,
         // InodeAttr.
         DriveItemField::size,
+        // Hashes and MIME type.
         DriveItemField::file,
         DriveItemField::file_system_info,
+        DriveItemField::created_date_time,
+        DriveItemField::last_modified_date_time,
+        DriveItemField::created_by,
+        DriveItemField::last_modified_by,
         DriveItemField::folder,
         DriveItemField::c_tag,
+        DriveItemField::e_tag,
+        DriveItemField::publication,
+        DriveItemField::package,
     ];
 
-    pub fn new(_config: Config) -> Self {
+    pub fn new(config: Config, retry: retry::Config) -> Self {
         Self {
             tree: SyncMutex::new(InodeTree::new()),
+            dir_handles: Slab::new(),
+            symlink_targets: SyncMutex::new(HashMap::new()),
+            unverified_symlinks: SyncMutex::new(HashSet::new()),
+            retry,
+            config,
+        }
+    }
+
+    /// The max time for the kernel to cache attributes and entries of an item, if configured.
+    /// The override with the longest path matching takes precedence.
+    pub fn max_attr_ttl(&self, item_id: &ItemId) -> Option<Duration> {
+        if self.config.max_attr_ttl_overrides.is_empty() {
+            return self.config.max_attr_ttl;
         }
+        let path = match self.tree.lock().unwrap().path_of(item_id) {
+            Some(path) => path,
+            None => return self.config.max_attr_ttl,
+        };
+        self.config
+            .max_attr_ttl_overrides
+            .iter()
+            .filter(|ovr| ovr.matches(&path))
+            .max_by_key(|ovr| ovr.path.trim_end_matches('/').len())
+            .map(|ovr| ovr.max_attr_ttl)
+            .or(self.config.max_attr_ttl)
+    }
+
+    /// Whether an item is or is under any of `ids` or `paths`.
+    pub fn is_under(&self, item_id: &ItemId, ids: &HashSet<ItemId>, paths: &[String]) -> bool {
+        let tree = self.tree.lock().unwrap();
+        let mut cur = item_id;
+        loop {
+            if ids.contains(cur) {
+                return true;
+            }
+            match tree.map.get(cur) {
+                Some((_, Some((parent_id, _)))) => cur = parent_id,
+                _ => break,
+            }
+        }
+        !paths.is_empty()
+            && tree
+                .path_of(item_id)
+                .is_some_and(|path| paths.iter().any(|prefix| is_under_path(prefix, &path)))
     }
 
     /// Get attribute of an item.
     pub fn get_attr(&self, item_id: &ItemId) -> Result<InodeAttr> {
         let tree = self.tree.lock().unwrap();
-        Ok(tree.get(item_id).ok_or(Error::NotFound)?.attr().clone())
+        let mut attr = tree.get(item_id).ok_or(Error::NotFound)?.attr().clone();
+        attr.is_symlink = self.is_symlink(&tree, item_id, &attr);
+        Ok(attr)
+    }
+
+    fn is_symlink(&self, tree: &InodeTree, item_id: &ItemId, attr: &InodeAttr) -> bool {
+        if self.config.symlink_suffix.is_empty() || attr.is_directory {
+            return false;
+        }
+        let Some((parent_id, name)) = tree.parent_of(item_id) else {
+            return false;
+        };
+        let children = tree.get(&parent_id).unwrap().children().unwrap();
+        self.config.symlink_name(children, &name, attr).is_some()
+            && self.has_symlink_target(item_id, attr)
+    }
+
+    fn has_symlink_target(&self, item_id: &ItemId, attr: &InodeAttr) -> bool {
+        self.symlink_targets
+            .lock()
+            .unwrap()
+            .get(item_id)
+            .is_some_and(|(c_tag, _)| attr.c_tag.as_ref() == Some(c_tag))
+    }
+
+    /// The target of a symlink, if the item is a verified marker file.
+    pub fn symlink_target(&self, item_id: &ItemId) -> Option<Arc<[u8]>> {
+        let tree = self.tree.lock().unwrap();
+        let attr = tree.get(item_id)?.attr();
+        let targets = self.symlink_targets.lock().unwrap();
+        let (c_tag, target) = targets.get(item_id)?;
+        (attr.c_tag.as_ref() == Some(c_tag)).then(|| target.clone())
+    }
+
+    /// Record the verified target of a marker file with content of `c_tag`.
+    /// Return the directory entry to invalidate, since it may turn into a symlink.
+    pub fn set_symlink_target(
+        &self,
+        item_id: &ItemId,
+        c_tag: Tag,
+        target: &[u8],
+    ) -> Option<(ItemId, String)> {
+        let tree = self.tree.lock().unwrap();
+        self.symlink_targets
+            .lock()
+            .unwrap()
+            .insert(item_id.clone(), (c_tag, target.into()));
+        tree.parent_of(item_id)
+    }
+
+    /// Collect possible marker files in `updated` to be verified, along with ones failed to be
+    /// verified before, and forget targets of removed ones.
+    pub fn take_unverified_symlinks(&self, updated: &[DriveItem]) -> Vec<ItemId> {
+        let mut unverified = self.unverified_symlinks.lock().unwrap();
+        if self.config.symlink_suffix.is_empty() {
+            return Vec::new();
+        }
+        let mut targets = self.symlink_targets.lock().unwrap();
+        for item in updated {
+            let Some(item_id) = &item.id else { continue };
+            if item.deleted.is_some() {
+                targets.remove(item_id);
+                unverified.remove(item_id);
+                continue;
+            }
+            let candidate = item.file.is_some()
+                && item.package.is_none()
+                && item
+                    .name
+                    .as_deref()
+                    .is_some_and(|name| self.link_name(name).is_some())
+                && item
+                    .size
+                    .is_some_and(|size| Config::is_symlink_size(size as u64));
+            let verified = targets
+                .get(item_id)
+                .is_some_and(|(c_tag, _)| item.c_tag.as_ref() == Some(c_tag));
+            if candidate && !verified {
+                unverified.insert(item_id.clone());
+            }
+        }
+        unverified.drain().collect()
+    }
+
+    /// Verify it again on the next sync, after failing to fetch the content.
+    pub fn defer_unverified_symlink(&self, item_id: ItemId) {
+        self.unverified_symlinks.lock().unwrap().insert(item_id);
+    }
+
+    /// Get a child by its local name, which is the name without `symlink_suffix` for symlinks.
+    fn get_child<'t>(
+        &self,
+        tree: &'t InodeTree,
+        children: &'t DirChildren,
+        name: &str,
+        case_insensitive: bool,
+    ) -> Option<&'t ItemId> {
+        if let Some(item_id) = children.get_with(name, case_insensitive) {
+            return Some(item_id);
+        }
+        if self.config.symlink_suffix.is_empty() {
+            return None;
+        }
+        let marker_name = format!("{}{}", name, self.config.symlink_suffix);
+        let item_id = children.get_with(&marker_name, case_insensitive)?;
+        self.is_symlink(tree, item_id, tree.get(item_id)?.attr())
+            .then_some(item_id)
+    }
+
+    /// The OneDrive name of the marker file, if `name` is a symlink in the directory.
+    pub fn resolve_symlink(&self, parent_id: &ItemId, name: &FileName) -> Option<String> {
+        let tree = self.tree.lock().unwrap();
+        let children = tree.get(parent_id)?.children().ok()?;
+        if children
+            .get_with(name.as_str(), self.config.case_insensitive)
+            .is_some()
+        {
+            return None;
+        }
+        let item_id =
+            self.get_child(&tree, children, name.as_str(), self.config.case_insensitive)?;
+        Some(tree.parent_of(item_id)?.1)
+    }
+
+    /// The name of the marker file to store a symlink `name`.
+    pub fn symlink_marker_name(&self, name: &FileName) -> Result<String> {
+        if self.config.symlink_suffix.is_empty() {
+            return Err(Error::NotSupported);
+        }
+        Ok(format!("{}{}", name.as_str(), self.config.symlink_suffix))
+    }
+
+    /// The local name of a symlink if `name` may be its marker file, regardless of siblings.
+    pub fn link_name<'a>(&self, name: &'a str) -> Option<&'a str> {
+        if self.config.symlink_suffix.is_empty() {
+            return None;
+        }
+        name.strip_suffix(&*self.config.symlink_suffix)
+            .filter(|link_name| !link_name.is_empty())
     }
 
     /// Lookup a child by name of an directory item.
+    /// If `case_insensitive` is enabled, names are matched case-insensitively as OneDrive does,
+    /// preferring the exact match. Otherwise only the exact match is accepted.
     pub fn lookup(&self, parent_id: &ItemId, child_name: &FileName) -> Result<ItemId> {
+        self.lookup_inner(parent_id, child_name, self.config.case_insensitive)
+    }
+
+    /// Lookup a child case-insensitively, regardless of `case_insensitive`.
+    pub fn lookup_ignore_case(&self, parent_id: &ItemId, child_name: &FileName) -> Result<ItemId> {
+        self.lookup_inner(parent_id, child_name, true)
+    }
+
+    fn lookup_inner(
+        &self,
+        parent_id: &ItemId,
+        child_name: &FileName,
+        case_insensitive: bool,
+    ) -> Result<ItemId> {
         let tree = self.tree.lock().unwrap();
         let children = tree.get(parent_id).ok_or(Error::NotFound)?.children()?;
-        children
-            .get(child_name.as_str())
-            .cloned()
-            .ok_or(Error::NotFound)
+        let item_id = self
+            .get_child(&tree, children, child_name.as_str(), case_insensitive)
+            .ok_or(Error::NotFound)?;
+        if self.config.hide_from_lookup
+            && self
+                .config
+                .is_hidden(child_name.as_str(), tree.get(item_id).unwrap().attr())
+        {
+            return Err(Error::NotFound);
+        }
+        Ok(item_id.clone())
+    }
+
+    /// Map a OneDrive name to the local name shown in listings.
+    pub fn escape_name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        self.config.escape_name(name)
+    }
+
+    /// Map a local name back to the OneDrive name, reversing escapes in listings.
+    pub fn unescape_name<'a>(&self, name: &'a OsStr) -> Cow<'a, OsStr> {
+        match name.to_str().map(|name| self.config.unescape_name(name)) {
+            Some(Cow::Owned(name)) => Cow::Owned(name.into()),
+            // Invalid UTF-8 is rejected later.
+            _ => Cow::Borrowed(name),
+        }
     }
 
     /// Read entries of a directory.
-    pub fn read_dir(&self, parent_id: &ItemId, offset: u64, count: usize) -> Result<Vec<DirEntry>> {
-        let tree = self.tree.lock().unwrap();
-        let children = tree.get(parent_id).ok_or(Error::NotFound)?.children()?;
+    pub fn open_dir(&self, parent_id: &ItemId) -> Result<u64> {
+        {
+            let tree = self.tree.lock().unwrap();
+            tree.get(parent_id).ok_or(Error::NotFound)?.children()?;
+            if let Some((item_id, _)) = tree.invalid_items.iter().find(|(_, p)| *p == parent_id) {
+                return Err(Error::InvalidResponse(anyhow::anyhow!(
+                    "Directory {:?} contains invalid item {:?}",
+                    parent_id,
+                    item_id,
+                )));
+            }
+        }
+        let key = self
+            .dir_handles
+            .insert(SyncMutex::new(None))
+            .ok_or(Error::TooManyOpenFiles)?;
+        Ok(u64::try_from(key).unwrap())
+    }
 
-        let mut entries = Vec::with_capacity(count);
-        let l = (offset as usize).min(children.len());
-        let r = (l + count).min(children.len());
-        for i in l..r {
-            let (name, child_id) = children.get_index(i).unwrap();
-            let child_attr = tree.get(child_id).unwrap().attr();
-            entries.push(DirEntry {
-                name: name.clone(),
-                item_id: child_id.clone(),
-                attr: child_attr.clone(),
-            });
+    pub fn close_dir(&self, fh: u64) -> Result<()> {
+        if self.dir_handles.remove(usize::try_from(fh).unwrap()) {
+            Ok(())
+        } else {
+            Err(Error::InvalidHandle(fh))
         }
-        Ok(entries)
+    }
+
+    /// Read entries from the snapshot of an opened directory handle.
+    /// Reading from offset 0 (like `rewinddir`) refreshes the snapshot.
+    /// Children removed since the snapshot are skipped.
+    pub fn read_dir(
+        &self,
+        fh: u64,
+        parent_id: &ItemId,
+        offset: u64,
+        count: usize,
+    ) -> Result<Vec<DirEntry>> {
+        let handle = self
+            .dir_handles
+            .get(usize::try_from(fh).unwrap())
+            .ok_or(Error::InvalidHandle(fh))?;
+        let mut snapshot = handle.lock().unwrap();
+        let tree = self.tree.lock().unwrap();
+        let snapshot = match &mut *snapshot {
+            Some(snapshot) if offset != 0 => snapshot,
+            snapshot => {
+                let children = tree.get(parent_id).ok_or(Error::NotFound)?.children()?;
+                // Hidden children are filtered in the snapshot, so offsets are still contiguous.
+                let mut entries = children
+                    .iter()
+                    .filter(|(name, id)| {
+                        tree.get(id)
+                            .is_some_and(|inode| !self.config.is_hidden(name, inode.attr()))
+                    })
+                    .map(|(name, id)| {
+                        let attr = tree.get(id).unwrap().attr();
+                        let name = self
+                            .config
+                            .symlink_name(children, name, attr)
+                            .filter(|_| self.has_symlink_target(id, attr))
+                            .unwrap_or(name);
+                        (self.escape_name(name).into_owned(), id.clone())
+                    })
+                    .collect::<Vec<_>>();
+                match self.config.dir_order {
+                    DirOrder::Server => {}
+                    DirOrder::Name => entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b)),
+                    DirOrder::NameCaseInsensitive => entries
+                        .sort_by_cached_key(|(name, _)| (DirChildren::fold(name), name.clone())),
+                    DirOrder::Mtime => entries.sort_by_cached_key(|(name, id)| {
+                        let mtime = tree.get(id).map(|inode| inode.attr().mtime);
+                        (mtime, name.clone())
+                    }),
+                }
+                snapshot.insert(entries.into())
+            }
+        };
+
+        let l = usize::try_from(offset)
+            .unwrap_or(usize::MAX)
+            .min(snapshot.len());
+        // Keep scanning past removed children, since an empty result means the end.
+        Ok((l..snapshot.len())
+            .filter_map(|idx| {
+                let (name, item_id) = &snapshot[idx];
+                let mut attr = tree.get(item_id)?.attr().clone();
+                attr.is_symlink = self.is_symlink(&tree, item_id, &attr);
+                Some(DirEntry {
+                    item_id: item_id.clone(),
+                    name: name.clone(),
+                    attr,
+                    next_offset: idx as u64 + 1,
+                })
+            })
+            .take(count)
+            .collect())
     }
 
     pub async fn create_dir(
         &self,
         parent_id: &ItemId,
         name: &FileName,
-        onedrive: &OneDrive,
+        onedrive: &ManagedOnedrive,
     ) -> Result<(ItemId, InodeAttr)> {
         {
             let tree = self.tree.lock().unwrap();
             let children = tree.get(parent_id).ok_or(Error::NotFound)?.children()?;
-            if children.contains_key(name.as_str()) {
+            if self
+                .get_child(&tree, children, name.as_str(), true)
+                .is_some()
+            {
                 return Err(Error::FileExists);
             }
         }
 
-        let item = onedrive
-            .create_folder_with_option(
-                ItemLocation::from_id(parent_id),
-                name,
-                DriveItemPutOption::new().conflict_behavior(ConflictBehavior::Fail),
-            )
-            .await?;
-        let attr = InodeAttr::parse_item(&item).expect("Invalid attrs");
-        let id = item.id.expect("Missing id");
+        let item = retry_checked(
+            &self.retry,
+            "create directory",
+            || async {
+                Ok(onedrive
+                    .get()
+                    .await
+                    .create_folder_with_option(
+                        ItemLocation::from_id(parent_id),
+                        name,
+                        DriveItemPutOption::new().conflict_behavior(ConflictBehavior::Fail),
+                    )
+                    .await?)
+            },
+            // A retry after a successful attempt would fail with a conflict.
+            || async {
+                let ret = onedrive
+                    .get()
+                    .await
+                    .get_item(ItemLocation::child_of_id(parent_id, name))
+                    .await;
+                match ret {
+                    Ok(item) => Ok(item.folder.is_some().then_some(item)),
+                    Err(e) if e.status_code() == Some(StatusCode::NOT_FOUND) => Ok(None),
+                    Err(e) => Err(e.into()),
+                }
+            },
+        )
+        .await?;
+        let (id, attr) = InodeAttr::parse_item_with_id(item)?;
 
         let mut tree = self.tree.lock().unwrap();
         tree.insert_item(id.clone(), attr.clone());
@@ -298,51 +1298,102 @@ impl InodePool {
         old_name: &FileName,
         new_parent_id: &ItemId,
         new_name: &FileName,
-        onedrive: &OneDrive,
+        no_replace: bool,
+        onedrive: &ManagedOnedrive,
     ) -> Result<Option<ItemId>> {
         let mut replaced_item_id = None;
+        let mut replace_dir = false;
         let item_id = {
             let tree = self.tree.lock().unwrap();
             let old_children = tree.get(old_parent_id).ok_or(Error::NotFound)?.children()?;
             let new_children = tree.get(new_parent_id).ok_or(Error::NotFound)?.children()?;
-            if let Some(id) = new_children.get(new_name.as_str()) {
-                replaced_item_id = Some(id.clone());
-                let attr = tree.get(id).unwrap().attr();
-                if attr.is_directory {
-                    return Err(Error::IsADirectory);
-                }
-                if attr.dirty {
-                    return Err(Error::Uploading);
-                }
-            }
             let item_id = old_children
-                .get(old_name.as_str())
+                .get_with(old_name.as_str(), self.config.case_insensitive)
                 .ok_or(Error::NotFound)?
                 .clone();
-            if tree.get(&item_id).unwrap().attr().dirty {
+            let attr = tree.get(&item_id).unwrap().attr();
+            if attr.dirty {
                 return Err(Error::Uploading);
             }
+            match new_children.get(new_name.as_str()) {
+                // Renaming to the same name with only cases changed.
+                Some(id) if *id == item_id => {}
+                Some(_) if no_replace => return Err(Error::FileExists),
+                Some(id) => {
+                    replaced_item_id = Some(id.clone());
+                    let target = tree.get(id).unwrap();
+                    match (attr.is_directory, target) {
+                        (true, Inode::File { .. }) => return Err(Error::NotADirectory),
+                        (false, Inode::Dir { .. }) => return Err(Error::IsADirectory),
+                        (true, Inode::Dir { children, .. }) if !children.is_empty() => {
+                            return Err(Error::DirectoryNotEmpty)
+                        }
+                        (true, Inode::Dir { .. }) => replace_dir = true,
+                        (false, Inode::File { .. }) => {}
+                    }
+                    if target.attr().dirty {
+                        return Err(Error::Uploading);
+                    }
+                }
+                None => {}
+            }
             item_id
         };
 
-        match onedrive
-            .move_with_option(
-                ItemLocation::from_id(&item_id),
-                ItemLocation::from_id(new_parent_id),
-                Some(new_name),
-                DriveItemPutOption::new().conflict_behavior(ConflictBehavior::Replace),
-            )
-            .await
-        {
-            Ok(_) => {}
-            // 400 Bad Request is returned when the destination item is not a directory.
-            // `error: { code: "invalidRequest", message: "Bad Argument" }`
-            Err(e) if e.status_code() == Some(StatusCode::BAD_REQUEST) => {
-                return Err(Error::NotADirectory)
-            }
-            Err(e) => return Err(e.into()),
+        // Moving cannot replace directories. Remove the empty one first, which also checks it's
+        // still empty on the server side.
+        if replace_dir {
+            self.remove(new_parent_id, new_name, true, onedrive).await?;
         }
 
+        retry_checked(
+            &self.retry,
+            "move item",
+            || async {
+                let ret = onedrive
+                    .get()
+                    .await
+                    .move_with_option(
+                        ItemLocation::from_id(&item_id),
+                        ItemLocation::from_id(new_parent_id),
+                        Some(new_name),
+                        DriveItemPutOption::new().conflict_behavior(ConflictBehavior::Replace),
+                    )
+                    .await;
+                match ret {
+                    Ok(_) => Ok(()),
+                    // 400 Bad Request is returned when the destination item is not a directory.
+                    // `error: { code: "invalidRequest", message: "Bad Argument" }`
+                    Err(e) if e.status_code() == Some(StatusCode::BAD_REQUEST) => {
+                        Err(Error::NotADirectory)
+                    }
+                    Err(e) => Err(e.into()),
+                }
+            },
+            // A retry after a successful attempt would replace an item created at the
+            // destination since then.
+            || async {
+                let item = onedrive
+                    .get()
+                    .await
+                    .get_item_with_option(
+                        ItemLocation::from_id(&item_id),
+                        ObjectOption::new()
+                            .select(&[DriveItemField::name, DriveItemField::parent_reference]),
+                    )
+                    .await?
+                    .expect("No If-None-Match");
+                let parent_id = item
+                    .parent_reference
+                    .as_ref()
+                    .and_then(|parent| parent.get("id")?.as_str());
+                let moved = parent_id == Some(new_parent_id.as_str())
+                    && item.name.as_deref() == Some(new_name.as_str());
+                Ok(moved.then_some(()))
+            },
+        )
+        .await?;
+
         log::debug!(
             "Moved file {:?} from {:?}/{} to {:?}/{}, replaced {:?}",
             item_id,
@@ -354,8 +1405,8 @@ impl InodePool {
         );
 
         let mut tree = self.tree.lock().unwrap();
-        // Remove the old item first, or name collides.
-        if let Some(id) = &replaced_item_id {
+        // Remove the old item first, or name collides. Replaced directories are already removed.
+        if let Some(id) = replaced_item_id.as_ref().filter(|_| !replace_dir) {
             tree.remove_item(id);
         }
         tree.set_parent(
@@ -371,12 +1422,14 @@ impl InodePool {
         parent_id: &ItemId,
         name: &FileName,
         directory: bool,
-        onedrive: &OneDrive,
-    ) -> Result<()> {
+        onedrive: &ManagedOnedrive,
+    ) -> Result<ItemId> {
         let item_id = {
             let tree = self.tree.lock().unwrap();
             let children = tree.get(parent_id).ok_or(Error::NotFound)?.children()?;
-            let item_id = children.get(name.as_str()).ok_or(Error::NotFound)?;
+            let item_id = children
+                .get_with(name.as_str(), self.config.case_insensitive)
+                .ok_or(Error::NotFound)?;
             let inode = tree.get(item_id).unwrap();
             if inode.attr().dirty {
                 return Err(Error::Uploading);
@@ -390,9 +1443,229 @@ impl InodePool {
             item_id.clone()
         };
 
-        onedrive.delete(ItemLocation::from_id(&item_id)).await?;
+        let mut opt = DriveItemPutOption::new();
+        let e_tag;
+        if directory {
+            // Children may be added remotely but not synced yet. Check it on the server side,
+            // and only delete the directory if it is not changed since then.
+            let item = retry(&self.retry, "get directory", || async {
+                Ok(onedrive
+                    .get()
+                    .await
+                    .get_item_with_option(
+                        ItemLocation::from_id(&item_id),
+                        ObjectOption::new()
+                            .select(&[DriveItemField::e_tag, DriveItemField::folder]),
+                    )
+                    .await?)
+            })
+            .await?
+            .expect("No If-None-Match");
+            let child_count = item
+                .folder
+                .as_ref()
+                .and_then(|folder| folder.get("childCount")?.as_u64())
+                .unwrap_or(0);
+            if child_count != 0 {
+                return Err(Error::DirectoryNotEmpty);
+            }
+            e_tag = item
+                .e_tag
+                .ok_or_else(|| Error::InvalidResponse(anyhow::anyhow!("Missing e_tag")))?;
+            opt = opt.if_match(&e_tag);
+        }
+
+        retry_checked(
+            &self.retry,
+            "delete item",
+            || async {
+                let ret = onedrive
+                    .get()
+                    .await
+                    .delete_with_option(ItemLocation::from_id(&item_id), opt.clone())
+                    .await;
+                match ret {
+                    Ok(()) => Ok(()),
+                    // The directory is changed after the check. Likely some children are added.
+                    Err(e) if e.status_code() == Some(StatusCode::PRECONDITION_FAILED) => {
+                        Err(Error::DirectoryNotEmpty)
+                    }
+                    Err(e) => Err(e.into()),
+                }
+            },
+            // A retry after a successful attempt would fail with not found.
+            || async {
+                let ret = onedrive
+                    .get()
+                    .await
+                    .get_item_with_option(
+                        ItemLocation::from_id(&item_id),
+                        ObjectOption::new().select(&[DriveItemField::id]),
+                    )
+                    .await;
+                match ret {
+                    Ok(_) => Ok(None),
+                    Err(e) if e.status_code() == Some(StatusCode::NOT_FOUND) => Ok(Some(())),
+                    Err(e) => Err(e.into()),
+                }
+            },
+        )
+        .await?;
 
         self.tree.lock().unwrap().remove_item(&item_id);
+        Ok(item_id)
+    }
+
+    /// Fetch a directory and its children in the same form as delta items, to refresh them.
+    /// Also return whether some children known locally are missing remotely. They are left to
+    /// the next sync, since they may be moved rather than deleted.
+    pub async fn fetch_dir(
+        &self,
+        item_id: &ItemId,
+        onedrive: &ManagedOnedrive,
+    ) -> Result<(Vec<DriveItem>, bool)> {
+        let items = retry(&self.retry, "refresh directory", || async {
+            let dir = onedrive
+                .get()
+                .await
+                .get_item_with_option(
+                    ItemLocation::from_id(item_id),
+                    ObjectOption::new().select(Self::SYNC_SELECT_FIELDS),
+                )
+                .await?
+                .expect("No If-None-Match");
+            let fetcher = onedrive
+                .get()
+                .await
+                .list_children_with_option(
+                    ItemLocation::from_id(item_id),
+                    CollectionOption::new().select(Self::SYNC_SELECT_FIELDS),
+                )
+                .await?;
+            let mut items = vec![dir];
+            if let Some(mut fetcher) = fetcher {
+                // Each page is a request of the rate limit.
+                while let Some(page) = {
+                    let onedrive = onedrive.get().await;
+                    fetcher.fetch_next_page(&onedrive).await?
+                } {
+                    items.extend(page);
+                }
+            }
+            Ok(items)
+        })
+        .await?;
+        if items[0].folder.is_none() {
+            return Err(Error::NotADirectory);
+        }
+
+        let tree = self.tree.lock().unwrap();
+        let remote = items
+            .iter()
+            .filter_map(|item| item.id.as_ref())
+            .collect::<HashSet<_>>();
+        let missing = tree
+            .get(item_id)
+            .ok_or(Error::NotFound)?
+            .children()?
+            .iter()
+            .any(|(_, id)| !remote.contains(id));
+        Ok((items, missing))
+    }
+
+    /// Get the URL to open an item in the browser.
+    pub async fn web_url(&self, item_id: &ItemId, onedrive: &ManagedOnedrive) -> Result<String> {
+        let item = retry(&self.retry, "get web URL", || async {
+            Ok(onedrive
+                .get()
+                .await
+                .get_item_with_option(
+                    ItemLocation::from_id(item_id),
+                    ObjectOption::new().select(&[DriveItemField::web_url]),
+                )
+                .await?)
+        })
+        .await?
+        .expect("No If-None-Match");
+        item.web_url.ok_or(Error::NoAttribute)
+    }
+
+    /// Existing sharing links of an item in JSON.
+    pub async fn share_links(
+        &self,
+        item_id: &ItemId,
+        onedrive: &ManagedOnedrive,
+    ) -> Result<String> {
+        let item = retry(&self.retry, "list sharing links", || async {
+            Ok(onedrive
+                .get()
+                .await
+                .get_item_with_option(
+                    ItemLocation::from_id(item_id),
+                    ObjectOption::new()
+                        .select(&[DriveItemField::id])
+                        .expand(DriveItemField::permissions, None),
+                )
+                .await?)
+        })
+        .await?
+        .expect("No If-None-Match");
+        let links = item
+            .permissions
+            .as_ref()
+            .and_then(|v| v.as_array())
+            .ok_or_else(|| Error::InvalidResponse(anyhow::anyhow!("Missing permissions")))?
+            .iter()
+            // Other permissions are direct grants to users, without links.
+            .filter_map(|perm| {
+                let link = perm.get("link")?;
+                Some(serde_json::json!({
+                    "id": perm.get("id"),
+                    "roles": perm.get("roles"),
+                    "type": link.get("type"),
+                    "scope": link.get("scope"),
+                    "web_url": link.get("webUrl"),
+                }))
+            })
+            .collect::<Vec<_>>();
+        Ok(serde_json::Value::from(links).to_string())
+    }
+
+    /// Create a sharing link of `link_type` like `view` or `edit`, with an optional scope like
+    /// `anonymous` or `organization`. An existing link of the same kind is reused by the server.
+    pub async fn create_share_link(
+        &self,
+        item_id: &ItemId,
+        link_type: &str,
+        scope: Option<&str>,
+        onedrive: &ManagedOnedrive,
+    ) -> Result<()> {
+        // Not supported by `onedrive_api`.
+        let url = onedrive.drive_api_url(&["items", item_id.as_str(), "createLink"]);
+        let mut body = serde_json::json!({ "type": link_type });
+        if let Some(scope) = scope {
+            body["scope"] = scope.into();
+        }
+        let resp = {
+            let onedrive = onedrive.get().await;
+            onedrive
+                .client()
+                .post(url)
+                .bearer_auth(onedrive.access_token())
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.to_string())
+                .send()
+                .await?
+        };
+        let perm = Error::check_response(resp)
+            .await?
+            .json::<serde_json::Value>()
+            .await?;
+        let web_url = perm
+            .get("link")
+            .and_then(|link| link.get("webUrl")?.as_str())
+            .ok_or_else(|| Error::InvalidResponse(anyhow::anyhow!("Missing link URL")))?;
+        log::info!("Created {} link of {:?}: {}", link_type, item_id, web_url);
         Ok(())
     }
 
@@ -409,6 +1682,18 @@ impl InodePool {
         inode.attr().clone()
     }
 
+    /// Mark a file as no longer waiting for uploading, if it still exists.
+    pub fn clear_dirty(&self, item_id: &ItemId) {
+        let mut tree = self.tree.lock().unwrap();
+        if let Some(inode) = tree.get_mut(item_id) {
+            let attr = inode.attr().clone();
+            inode.set_attr(InodeAttr {
+                dirty: false,
+                ..attr
+            });
+        }
+    }
+
     /// Insert a new item to a directory.
     pub fn insert_item(
         &self,
@@ -418,7 +1703,12 @@ impl InodePool {
         child_attr: InodeAttr,
     ) {
         let mut tree = self.tree.lock().unwrap();
-        tree.insert_item(child_id.clone(), child_attr);
+        match tree.get_mut(&child_id) {
+            // An existing item is replaced on the server side, if it's created remotely but
+            // not synced yet when we looked it up.
+            Some(inode) => inode.set_attr(child_attr),
+            None => tree.insert_item(child_id.clone(), child_attr),
+        }
         tree.set_parent(&child_id, Some((parent_id, child_name.as_str().to_owned())))
     }
 
@@ -427,7 +1717,7 @@ impl InodePool {
         &self,
         item_id: &ItemId,
         mtime: SystemTime,
-        onedrive: &OneDrive,
+        onedrive: &ManagedOnedrive,
     ) -> Result<InodeAttr> {
         let opt = ObjectOption::new().select(Self::SYNC_SELECT_FIELDS);
         let mut patch = DriveItem::default();
@@ -435,10 +1725,15 @@ impl InodePool {
         patch.file_system_info = Some(Box::new(serde_json::json!({
             "lastModifiedDateTime": humantime::format_rfc3339_seconds(mtime).to_string(),
         })));
-        let item = onedrive
-            .update_item_with_option(ItemLocation::from_id(item_id), &patch, opt)
-            .await?;
-        let attr = InodeAttr::parse_item(&item).expect("Invalid attr");
+        let item = retry(&self.retry, "update item", || async {
+            Ok(onedrive
+                .get()
+                .await
+                .update_item_with_option(ItemLocation::from_id(item_id), &patch, opt.clone())
+                .await?)
+        })
+        .await?;
+        let attr = InodeAttr::parse_item(&item).map_err(Error::InvalidResponse)?;
         log::debug!(
             "Set attribute of {:?}: mtime -> {}",
             item_id,
@@ -446,13 +1741,20 @@ impl InodePool {
         );
 
         let mut tree = self.tree.lock().unwrap();
-        tree.get_mut(item_id).unwrap().set_attr(attr.clone());
+        // It may be removed concurrently.
+        tree.get_mut(item_id)
+            .ok_or(Error::NotFound)?
+            .set_attr(attr.clone());
         Ok(attr)
     }
 
     /// Sync item changes from remote. Items not in cache are skipped.
-    pub fn sync_items(&self, updated: &[DriveItem]) {
+    /// Apply remote changes. Return the changed items and directory entries, which may be
+    /// cached by the kernel, except for the initial sync.
+    pub fn sync_items(&self, updated: &[DriveItem]) -> Invalidations {
         let mut tree = self.tree.lock().unwrap();
+        let mut inval = Invalidations::default();
+        let initial = tree.map.is_empty();
 
         // > You should only delete a folder locally if it is empty after syncing all the changes.
         // See: https://docs.microsoft.com/en-us/graph/api/driveitem-delta?view=graph-rest-1.0&tabs=http
@@ -462,16 +1764,24 @@ impl InodePool {
             if !(item.file.is_some() || item.folder.is_some()) {
                 continue;
             }
-            let item_id = item.id.as_ref().expect("Missing id");
+            let item_id = match &item.id {
+                Some(id) => id,
+                None => {
+                    log::warn!("Skip item without id: {:?}", item);
+                    continue;
+                }
+            };
 
             // Remove an existing item.
             if item.deleted.is_some() {
+                tree.invalid_items.remove(item_id);
                 if tree.get(item_id).is_some() {
                     if item.folder.is_some() {
                         log::debug!("Mark remove for directory {:?}", item_id);
                         dir_marked_deleted.insert(item_id);
                     } else {
                         log::debug!("Remove file {:?}", item_id);
+                        inval.entries.extend(tree.parent_of(item_id));
                         tree.remove_item(item_id);
                     }
                 }
@@ -481,11 +1791,16 @@ impl InodePool {
             let parent_id = if item.root.is_some() {
                 None
             } else {
-                let parent_id = (|| {
+                let parent_id = match (|| {
                     let id = item.parent_reference.as_ref()?.get("id")?.as_str()?;
                     Some(ItemId(id.to_owned()))
-                })()
-                .expect("Missing new parent for non-root item");
+                })() {
+                    Some(parent_id) => parent_id,
+                    None => {
+                        log::warn!("Skip non-root item without parent: {:?}", item);
+                        continue;
+                    }
+                };
 
                 match tree.get(&parent_id) {
                     // Normal case: parent is a directory.
@@ -510,25 +1825,77 @@ impl InodePool {
                 }
             };
 
+            let attr = match InodeAttr::parse_item(item) {
+                Ok(attr) => {
+                    tree.invalid_items.remove(item_id);
+                    attr
+                }
+                Err(err) => {
+                    log::warn!("Skip invalid item {:?}: {:#}", item_id, err);
+                    log::debug!(
+                        "Raw invalid item: {}",
+                        serde_json::to_string(item).unwrap_or_default(),
+                    );
+                    if let (InvalidItemPolicy::Fail, Some(parent_id)) =
+                        (self.config.on_invalid_item, &parent_id)
+                    {
+                        tree.invalid_items
+                            .insert(item_id.clone(), parent_id.clone());
+                    }
+                    continue;
+                }
+            };
+            let name = match (&parent_id, &item.name) {
+                (Some(_), None) => {
+                    log::warn!("Skip non-root item without name: {:?}", item);
+                    continue;
+                }
+                // Names from OneDrive are always valid UTF-8, but they must also be valid locally,
+                // or they would break lookups of the whole listing.
+                (Some(_), Some(name))
+                    if name.is_empty()
+                        || name == "."
+                        || name == ".."
+                        || name.contains(['/', '\0']) =>
+                {
+                    log::warn!("Skip item {:?} with invalid local name {:?}", item_id, name);
+                    continue;
+                }
+                (_, name) => name.clone(),
+            };
+
             match tree.get_mut(item_id) {
                 // Insert a new item.
                 None => {
                     log::debug!("Insert item {:?}", item_id);
-                    let attr = InodeAttr::parse_item(item).expect("Invalid attrs");
                     tree.insert_item(item_id.clone(), attr);
                 }
                 // Update an existing item.
-                Some(inode) => {
+                Some(inode) if inode.attr().is_directory == attr.is_directory => {
                     log::debug!("Update item {:?}", item_id);
-                    let attr = InodeAttr::parse_item(item).expect("Invalid attrs");
                     inode.set_attr(attr);
                 }
+                Some(_) => {
+                    log::warn!(
+                        "Skip item {:?} changing between file and directory",
+                        item_id
+                    );
+                    continue;
+                }
             }
 
             // Update parent for non-root items.
-            if let Some(parent_id) = parent_id {
-                let name = item.name.clone().expect("Missing name");
-                tree.set_parent(item_id, Some((parent_id, name)));
+            if let (Some(parent_id), Some(name)) = (parent_id, name) {
+                let old_parent = tree.parent_of(item_id);
+                let new_parent = (parent_id, name);
+                if !initial && old_parent.as_ref() != Some(&new_parent) {
+                    inval.entries.extend(old_parent);
+                    inval.entries.push(new_parent.clone());
+                }
+                tree.set_parent(item_id, Some(new_parent));
+            }
+            if !initial {
+                inval.items.push(item_id.clone());
             }
         }
 
@@ -538,10 +1905,358 @@ impl InodePool {
                 if let Ok(children) = inode.children() {
                     if children.is_empty() {
                         log::debug!("Remove directory {:?}", item_id);
+                        inval.entries.extend(tree.parent_of(item_id));
                         tree.remove_item(item_id);
                     }
                 }
             }
         }
+
+        // Also those renamed by local operations since the last sync.
+        let renamed = std::mem::take(&mut tree.renamed_entries);
+        if !initial {
+            inval.entries.extend(renamed);
+        }
+        inval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symlink_marker() {
+        let content = [SYMLINK_MAGIC, b"../a b/\xFF"].concat();
+        assert_eq!(parse_symlink_marker(&content), Some(&b"../a b/\xFF"[..]));
+        assert_eq!(parse_symlink_marker(SYMLINK_MAGIC), None);
+        assert_eq!(parse_symlink_marker(b""), None);
+        assert_eq!(parse_symlink_marker(b"../target"), None);
+        let long = [SYMLINK_MAGIC, &[b'a'; MAX_SYMLINK_TARGET + 1]].concat();
+        assert_eq!(parse_symlink_marker(&long), None);
+        assert!(!Config::is_symlink_size(0));
+        assert!(!Config::is_symlink_size(SYMLINK_MAGIC.len() as u64));
+        assert!(Config::is_symlink_size(content.len() as u64));
+    }
+
+    fn default_config() -> Config {
+        crate::config::Config::merge_for_test(&[])
+            .unwrap()
+            .vfs
+            .inode
+    }
+
+    fn escape_config(escape_chars: &str) -> Config {
+        let mut config = default_config();
+        config.escape_chars = escape_chars.to_owned();
+        config
+    }
+
+    fn new_pool(items: serde_json::Value) -> InodePool {
+        let retry = crate::config::Config::merge_for_test(&[])
+            .unwrap()
+            .vfs
+            .retry;
+        let pool = InodePool::new(default_config(), retry);
+        let items = serde_json::from_value::<Vec<DriveItem>>(items).unwrap();
+        pool.sync_items(&items);
+        pool
+    }
+
+    fn id(s: &str) -> ItemId {
+        ItemId(s.to_owned())
+    }
+
+    #[test]
+    fn parse_future_item() {
+        let parse = |value: serde_json::Value| {
+            InodeAttr::parse_item(&serde_json::from_value::<DriveItem>(value).unwrap())
+        };
+        // Unknown fields are ignored, and renamed timestamps fall back to the others.
+        let attr = parse(serde_json::json!({
+            "id": "file",
+            "name": "a.txt",
+            "size": 3,
+            "cTag": "ctag",
+            "fileSystemInfo": {
+                "modifiedAt": "2020-01-01T00:00:00Z",
+                "lastAccessedDateTime": "2020-01-01T00:00:00Z",
+            },
+            "createdAt": "2020-01-01T00:00:00Z",
+            "lastModifiedDateTime": "1970-01-01T00:01:00+00:00",
+            "file": {
+                "mimeType": "text/plain",
+                "hashes": { "sha1Hash": "1234", "blake3Hash": "5678" },
+                "processingMetadata": true,
+            },
+            "sensitivityLabel": { "id": "label" },
+            "@microsoft.graph.futureAnnotation": 42,
+        }))
+        .unwrap();
+        assert_eq!(attr.size, 3);
+        assert_eq!(attr.mtime, SystemTime::UNIX_EPOCH + Duration::from_secs(60));
+        assert_eq!(attr.crtime, attr.mtime);
+        assert_eq!(attr.mime_type.as_deref(), Some("text/plain"));
+        assert_eq!(attr.hashes.sha1.as_deref(), Some("1234"));
+        assert!(!attr.is_directory);
+
+        // Timestamps are informational and default to the epoch.
+        let attr = parse(serde_json::json!({ "id": "dir", "size": 0, "folder": {} })).unwrap();
+        assert_eq!(attr.mtime, SystemTime::UNIX_EPOCH);
+        assert!(attr.is_directory);
+
+        // But the size and CTag of files are required.
+        assert!(parse(serde_json::json!({ "id": "file", "cTag": "ctag", "file": {} })).is_err());
+        assert!(parse(serde_json::json!({ "id": "file", "size": 3, "file": {} })).is_err());
+
+        // Invalid items are skipped by default, without breaking their siblings.
+        let pool = new_pool(serde_json::json!([
+            { "id": "root", "root": {}, "folder": {}, "size": 0 },
+            { "id": "bad", "name": "bad", "parentReference": { "id": "root" }, "file": {} },
+            {
+                "id": "good",
+                "name": "good",
+                "parentReference": { "id": "root" },
+                "file": {},
+                "size": 0,
+                "cTag": "ctag",
+                "newField": [],
+            },
+        ]));
+        let lookup = |name| pool.lookup(&id("root"), FileName::new(name).unwrap());
+        assert!(matches!(lookup("bad"), Err(Error::NotFound)));
+        assert_eq!(lookup("good").unwrap(), id("good"));
+    }
+
+    fn root_item() -> serde_json::Value {
+        serde_json::json!({
+            "id": "root",
+            "root": {},
+            "folder": { "childCount": 0 },
+            "size": 0,
+            "lastModifiedDateTime": "2020-01-01T00:00:00Z",
+        })
+    }
+
+    fn file_item(id: &str, name: &str, parent: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": id,
+            "name": name,
+            "parentReference": { "id": parent },
+            "file": {},
+            "size": 3,
+            "cTag": "ctag",
+            "lastModifiedDateTime": "2020-01-01T00:00:00Z",
+        })
+    }
+
+    fn sync(pool: &InodePool, items: serde_json::Value) -> Invalidations {
+        pool.sync_items(&serde_json::from_value::<Vec<DriveItem>>(items).unwrap())
+    }
+
+    #[test]
+    fn duplicated_names() {
+        let lookup = |pool: &InodePool, name: &str| {
+            pool.lookup(&id("root"), FileName::new(name).unwrap()).ok()
+        };
+        let has_entry = |inval: &Invalidations, name: &str| {
+            inval.entries.contains(&(id("root"), name.to_owned()))
+        };
+
+        // The lowest id keeps the real name, regardless of the order of changes.
+        for (first, second) in [("a", "b"), ("b", "a")] {
+            let pool = new_pool(serde_json::json!([
+                root_item(),
+                file_item(first, "x.txt", "root")
+            ]));
+            let inval = sync(
+                &pool,
+                serde_json::json!([file_item(second, "x.txt", "root")]),
+            );
+            assert_eq!(lookup(&pool, "x.txt"), Some(id("a")));
+            assert_eq!(lookup(&pool, "x (2).txt"), Some(id("b")));
+            assert!(has_entry(&inval, "x (2).txt"));
+        }
+        let pool = new_pool(serde_json::json!([
+            root_item(),
+            file_item("b", "x.txt", "root"),
+            file_item("a", "x.txt", "root"),
+        ]));
+        assert_eq!(lookup(&pool, "x.txt"), Some(id("a")));
+        assert_eq!(lookup(&pool, "x (2).txt"), Some(id("b")));
+
+        // The alias is invalidated once the real name is given back.
+        let inval = sync(
+            &pool,
+            serde_json::json!([{ "id": "a", "deleted": {}, "file": {} }]),
+        );
+        assert_eq!(lookup(&pool, "x.txt"), Some(id("b")));
+        assert_eq!(lookup(&pool, "x (2).txt"), None);
+        assert!(has_entry(&inval, "x.txt"));
+        assert!(has_entry(&inval, "x (2).txt"));
+    }
+
+    #[test]
+    fn deleted_items() {
+        let pool = new_pool(serde_json::json!([
+            root_item(),
+            {
+                "id": "dir",
+                "name": "d",
+                "parentReference": { "id": "root" },
+                "folder": { "childCount": 1 },
+                "size": 0,
+                "lastModifiedDateTime": "2020-01-01T00:00:00Z",
+            },
+            file_item("f", "f.txt", "root"),
+            file_item("g", "g.txt", "dir"),
+        ]));
+        let lookup =
+            |parent: &str, name: &str| pool.lookup(&id(parent), FileName::new(name).unwrap()).ok();
+
+        let inval = sync(
+            &pool,
+            serde_json::json!([{ "id": "f", "deleted": {}, "file": {} }]),
+        );
+        assert_eq!(lookup("root", "f.txt"), None);
+        assert!(matches!(pool.get_attr(&id("f")), Err(Error::NotFound)));
+        assert!(inval.items.is_empty());
+        assert_eq!(inval.entries, [(id("root"), "f.txt".to_owned())]);
+
+        // Non-empty folders are kept until they are empty after a whole batch.
+        let inval = sync(
+            &pool,
+            serde_json::json!([{ "id": "dir", "deleted": {}, "folder": {} }]),
+        );
+        assert_eq!(lookup("root", "d"), Some(id("dir")));
+        assert_eq!(lookup("dir", "g.txt"), Some(id("g")));
+        assert!(inval.items.is_empty());
+        assert!(inval.entries.is_empty());
+
+        let inval = sync(
+            &pool,
+            serde_json::json!([
+                { "id": "dir", "deleted": {}, "folder": {} },
+                { "id": "g", "deleted": {}, "file": {} },
+            ]),
+        );
+        assert_eq!(lookup("root", "d"), None);
+        assert!(matches!(pool.get_attr(&id("dir")), Err(Error::NotFound)));
+        assert!(matches!(pool.get_attr(&id("g")), Err(Error::NotFound)));
+        assert!(inval.items.is_empty());
+        assert_eq!(
+            inval.entries,
+            [
+                (id("dir"), "g.txt".to_owned()),
+                (id("root"), "d".to_owned()),
+            ],
+        );
+    }
+
+    #[test]
+    fn open_dir_type() {
+        let pool = new_pool(serde_json::json!([
+            {
+                "id": "root",
+                "root": {},
+                "folder": { "childCount": 1 },
+                "size": 0,
+                "lastModifiedDateTime": "2020-01-01T00:00:00Z",
+            },
+            {
+                "id": "file",
+                "name": "a.txt",
+                "parentReference": { "id": "root" },
+                "file": {},
+                "size": 3,
+                "cTag": "ctag",
+                "lastModifiedDateTime": "2020-01-01T00:00:00Z",
+            },
+        ]));
+        assert_eq!(
+            pool.lookup(&id("root"), FileName::new("a.txt").unwrap())
+                .unwrap(),
+            id("file"),
+        );
+        let err = pool.open_dir(&id("file")).unwrap_err();
+        assert_eq!(err.into_c_err(), libc::ENOTDIR);
+        assert!(matches!(pool.open_dir(&id("gone")), Err(Error::NotFound)));
+        let fh = pool.open_dir(&id("root")).unwrap();
+        let entries = pool.read_dir(fh, &id("root"), 0, 10).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "a.txt");
+        pool.close_dir(fh).unwrap();
+    }
+
+    #[test]
+    fn escape_name() {
+        let config = escape_config(":*?");
+        for (name, escaped) in [
+            ("plain.txt", "plain.txt"),
+            ("a:b", "a%3Ab"),
+            ("100%", "100%25"),
+            ("%3A", "%253A"),
+            ("?*:%", "%3F%2A%3A%25"),
+            ("中文:", "中文%3A"),
+        ] {
+            assert_eq!(config.escape_name(name), escaped);
+            assert_eq!(config.unescape_name(escaped), name);
+        }
+
+        // Non-canonical names are kept as is.
+        assert_eq!(config.unescape_name("100%"), "100%");
+        assert_eq!(config.unescape_name("%3a"), ":");
+        assert_eq!(config.unescape_name("%41%"), "%41%");
+        assert_eq!(config.unescape_name("%e4%"), "%e4%");
+        assert_eq!(config.unescape_name("中%"), "中%");
+
+        let config = escape_config("");
+        assert_eq!(config.escape_name("a%3A:"), "a%3A:");
+        assert_eq!(config.unescape_name("a%3A:"), "a%3A:");
+    }
+
+    #[test]
+    fn rfc3339() {
+        let t = |secs| Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(parse_rfc3339("1970-01-01T01:00:00Z"), t(3600));
+        assert_eq!(
+            parse_rfc3339("1970-01-01T01:00:00.5Z"),
+            t(3600).map(|t| t + Duration::from_millis(500))
+        );
+        assert_eq!(parse_rfc3339("1970-01-01T09:00:00+08:00"), t(3600));
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00-01:30"), t(5400));
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00+00:00"), t(0));
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00+0x:00"), None);
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00"), None);
+        assert_eq!(parse_rfc3339(""), None);
+        // Not on char boundaries.
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Zééé"), None);
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00+\u{e9}:00"), None);
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00+0\u{e9}00"), None);
+        assert_eq!(parse_rfc3339("\u{4e2d}\u{6587}"), None);
+    }
+
+    #[test]
+    fn dir_children_folded() {
+        let id = |s: &str| ItemId(s.to_owned());
+        let mut children = DirChildren::new();
+        children.insert("Foo".into(), id("1"));
+        children.insert("bar".into(), id("2"));
+        children.insert("FOO".into(), id("3"));
+        children.insert("straße".into(), id("4"));
+        assert_eq!(children.get("foo"), Some(&id("1")));
+        assert_eq!(children.get("FOO"), Some(&id("3")));
+        assert_eq!(children.get("STRASSE"), None);
+        assert_eq!(children.get("STRAßE"), Some(&id("4")));
+
+        // "straße" is moved to index 0.
+        assert_eq!(children.swap_remove_index(0).unwrap().1, id("1"));
+        assert_eq!(children.get("foo"), Some(&id("3")));
+        assert_eq!(children.get("Straße"), Some(&id("4")));
+        assert_eq!(children.swap_remove_index(2).unwrap().1, id("3"));
+        assert_eq!(children.get("foo"), None);
+        assert_eq!(children.get("Bar"), Some(&id("2")));
+        assert_eq!(children.get("STRAßE"), Some(&id("4")));
+        assert_eq!(children.len(), 2);
     }
 }
@@ -1,14 +1,30 @@
 //! Inode number pool with bidirectional mapping to `ItemId`.
 use crate::vfs::error::{Error, Result};
 use onedrive_api::ItemId;
+use serde::Deserialize;
 use std::{
     collections::hash_map::{Entry, HashMap},
     sync::Mutex as SyncMutex,
 };
 
+/// How inode numbers are allocated to items.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InoAllocation {
+    /// Sequentially from the root inode.
+    Counter,
+    /// From a stable hash of the item id, so inode numbers are the same across runs,
+    /// unless they collide.
+    Hash,
+}
+
+/// Hashed inode numbers below this are reserved, including the root inode.
+const HASH_MIN_INO: u64 = 1 << 16;
+
 pub struct InodeIdPool {
     inner: SyncMutex<PoolInner>,
     root_ino: u64,
+    allocation: InoAllocation,
 }
 
 struct PoolInner {
@@ -17,18 +33,24 @@ struct PoolInner {
     map: HashMap<u64, (u64, ItemId)>,
     /// item_id -> ino
     rev_map: HashMap<ItemId, u64>,
+    /// ino -> (generation, item_id) of the last owner of hashed inodes, which may be reused by
+    /// other items after freed. Counter inodes are never reused, so they are always generation 0.
+    owners: HashMap<u64, (u64, ItemId)>,
 }
 
 impl InodeIdPool {
-    pub fn new(root_ino: u64) -> Self {
+    pub fn new(root_ino: u64, allocation: InoAllocation) -> Self {
+        assert!(root_ino < HASH_MIN_INO);
         InodeIdPool {
             inner: SyncMutex::new(PoolInner {
                 // Do not allocate root inode id automatically.
                 inode_counter: root_ino + 1,
                 map: HashMap::new(),
                 rev_map: HashMap::new(),
+                owners: HashMap::new(),
             }),
             root_ino,
+            allocation,
         }
     }
 
@@ -52,9 +74,41 @@ impl InodeIdPool {
                 ino
             }
             None => {
-                let ino = inner.inode_counter;
-                assert_ne!(ino, u64::MAX);
-                inner.inode_counter += 1;
+                let ino = match self.allocation {
+                    InoAllocation::Counter => {
+                        let ino = inner.inode_counter;
+                        assert_ne!(ino, u64::MAX);
+                        inner.inode_counter += 1;
+                        ino
+                    }
+                    InoAllocation::Hash => {
+                        // Probe the next ones on collision.
+                        let mut ino = hash_ino(item_id);
+                        while inner.map.contains_key(&ino) {
+                            log::debug!("Inode {} collides, probing the next one", ino);
+                            ino = if ino + 1 == u64::MAX {
+                                HASH_MIN_INO
+                            } else {
+                                ino + 1
+                            };
+                        }
+                        // The kernel tells items apart by (ino, generation), so a different item
+                        // reusing a freed inode gets a new generation.
+                        match inner.owners.entry(ino) {
+                            Entry::Vacant(ent) => {
+                                ent.insert((0, item_id.clone()));
+                            }
+                            Entry::Occupied(mut ent) => {
+                                let (generation, owner) = ent.get_mut();
+                                if owner != item_id {
+                                    *generation += 1;
+                                    *owner = item_id.clone();
+                                }
+                            }
+                        }
+                        ino
+                    }
+                };
                 inner.map.insert(ino, (1, item_id.clone()));
                 inner.rev_map.insert(item_id.clone(), ino);
                 ino
@@ -65,21 +119,33 @@ impl InodeIdPool {
     /// Decrease reference count of an inode by `count`.
     /// Return if it is freed.
     pub fn free(&self, ino: u64, count: u64) -> Result<bool> {
+        self.inner.lock().unwrap().free(ino, count)
+    }
+
+    /// Decrease reference counts of multiple `(ino, count)` at once.
+    /// Return the number of freed inodes.
+    pub fn free_many(&self, nodes: &[(u64, u64)]) -> Result<usize> {
         let mut inner = self.inner.lock().unwrap();
-        match inner.map.entry(ino) {
-            Entry::Vacant(_) => Err(Error::InvalidInode(ino)),
-            Entry::Occupied(mut ent) => {
-                assert!(count <= ent.get_mut().0);
-                if ent.get_mut().0 == count {
-                    let (_, item_id) = ent.remove();
-                    assert!(inner.rev_map.remove(&item_id).is_some());
-                    Ok(true)
-                } else {
-                    ent.get_mut().0 -= count;
-                    Ok(false)
-                }
-            }
+        let mut freed = 0;
+        for &(ino, count) in nodes {
+            freed += usize::from(inner.free(ino, count)?);
         }
+        Ok(freed)
+    }
+
+    /// Get the generation of an allocated inode, which changes once it's reused by another item.
+    pub fn generation(&self, ino: u64) -> u64 {
+        self.inner
+            .lock()
+            .unwrap()
+            .owners
+            .get(&ino)
+            .map_or(0, |&(generation, _)| generation)
+    }
+
+    /// Get the inode of an item if it is allocated, without changing the reference count.
+    pub fn get_ino(&self, item_id: &ItemId) -> Option<u64> {
+        self.inner.lock().unwrap().rev_map.get(item_id).copied()
     }
 
     /// Get item id from an existing inode.
@@ -95,3 +161,128 @@ impl InodeIdPool {
             .clone())
     }
 }
+
+impl PoolInner {
+    fn free(&mut self, ino: u64, count: u64) -> Result<bool> {
+        match self.map.entry(ino) {
+            Entry::Vacant(_) => Err(Error::InvalidInode(ino)),
+            Entry::Occupied(mut ent) => {
+                assert!(count <= ent.get_mut().0);
+                if ent.get_mut().0 == count {
+                    let (_, item_id) = ent.remove();
+                    assert!(self.rev_map.remove(&item_id).is_some());
+                    Ok(true)
+                } else {
+                    ent.get_mut().0 -= count;
+                    Ok(false)
+                }
+            }
+        }
+    }
+}
+
+/// FNV-1a hash of the item id into `HASH_MIN_INO..u64::MAX`, which is stable across runs and
+/// versions, unlike `std` hashers.
+fn hash_ino(item_id: &ItemId) -> u64 {
+    let hash = item_id
+        .as_str()
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, b| {
+            (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+        });
+    HASH_MIN_INO + hash % (u64::MAX - HASH_MIN_INO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(s: &str) -> ItemId {
+        ItemId(s.to_owned())
+    }
+
+    #[test]
+    fn lookup_forget() {
+        let pool = InodeIdPool::new(1, InoAllocation::Counter);
+        pool.set_root_item_id(id("root"));
+        let a = pool.acquire_or_alloc(&id("a"));
+        assert_eq!(pool.acquire_or_alloc(&id("a")), a);
+        let b = pool.acquire_or_alloc(&id("b"));
+        assert_ne!(a, b);
+        assert_eq!(pool.get_item_id(a).unwrap(), id("a"));
+
+        assert!(!pool.free(a, 1).unwrap());
+        assert!(pool.free(a, 1).unwrap());
+        assert_eq!(pool.get_ino(&id("a")), None);
+        assert!(matches!(pool.get_item_id(a), Err(Error::InvalidInode(ino)) if ino == a));
+        assert!(matches!(pool.free(a, 1), Err(Error::InvalidInode(_))));
+
+        // Looked up again after forgotten, with a new inode.
+        let a2 = pool.acquire_or_alloc(&id("a"));
+        assert_ne!(a2, a);
+        for _ in 0..9 {
+            pool.acquire_or_alloc(&id("a"));
+        }
+        assert_eq!(pool.free_many(&[(a2, 4), (b, 1)]).unwrap(), 1);
+        assert_eq!(pool.free_many(&[(a2, 6)]).unwrap(), 1);
+        assert_eq!(pool.get_ino(&id("a")), None);
+        assert_eq!(pool.get_ino(&id("b")), None);
+
+        // The root is never freed by lookups of others.
+        assert_eq!(pool.get_item_id(1).unwrap(), id("root"));
+        let inner = pool.inner.lock().unwrap();
+        assert_eq!(inner.map.len(), 1);
+        assert_eq!(inner.rev_map.len(), 1);
+    }
+
+    #[test]
+    fn hash_allocation() {
+        let pool = InodeIdPool::new(1, InoAllocation::Hash);
+        pool.set_root_item_id(id("root"));
+        let a = pool.acquire_or_alloc(&id("a"));
+        assert_eq!(a, hash_ino(&id("a")));
+        assert!(HASH_MIN_INO <= a);
+        // Stable across runs.
+        assert_eq!(
+            hash_ino(&id("a")),
+            0xaf63_dc4c_8601_ec8c % (u64::MAX - HASH_MIN_INO) + HASH_MIN_INO
+        );
+
+        // Occupy the hashed inode of `c` and the next one, so it probes past them.
+        let c = hash_ino(&id("c"));
+        {
+            let mut inner = pool.inner.lock().unwrap();
+            inner.map.insert(c, (1, id("x")));
+            inner.map.insert(c + 1, (1, id("y")));
+        }
+        assert_eq!(pool.acquire_or_alloc(&id("c")), c + 2);
+        assert_eq!(pool.get_item_id(c + 2).unwrap(), id("c"));
+        assert_eq!(pool.acquire_or_alloc(&id("c")), c + 2);
+
+        // Freed inodes are reused by the same item.
+        assert!(pool.free(a, 1).unwrap());
+        assert_eq!(pool.acquire_or_alloc(&id("a")), a);
+        assert_eq!(pool.generation(a), 0);
+
+        // `w` collides with `b` and takes its hashed inode first, so `b` gets the next one.
+        // Once both are forgotten, `b` gets the one `w` had, with a new generation.
+        let (b, x) = (id("b"), id("w"));
+        let h = hash_ino(&b);
+        {
+            let mut inner = pool.inner.lock().unwrap();
+            inner.map.insert(h, (1, x.clone()));
+            inner.rev_map.insert(x.clone(), h);
+            inner.owners.insert(h, (0, x.clone()));
+        }
+        assert_eq!(pool.acquire_or_alloc(&b), h + 1);
+        assert_eq!(pool.generation(h + 1), 0);
+        assert!(pool.free(h, 1).unwrap());
+        assert!(pool.free(h + 1, 1).unwrap());
+        assert_eq!(pool.acquire_or_alloc(&b), h);
+        assert_eq!(pool.generation(h), 1);
+        // Reused by the same item again, the generation stays.
+        assert!(pool.free(h, 1).unwrap());
+        assert_eq!(pool.acquire_or_alloc(&b), h);
+        assert_eq!(pool.generation(h), 1);
+    }
+}
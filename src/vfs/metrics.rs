@@ -0,0 +1,128 @@
+//! Counters for diagnostics and cache tuning.
+use crate::config::de_duration_sec;
+use serde::Deserialize;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    enable_log: bool,
+    #[serde(deserialize_with = "de_duration_sec")]
+    log_period: Duration,
+}
+
+impl Config {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !self.enable_log || !self.log_period.is_zero(),
+            "`vfs.metrics.log_period` must be positive",
+        );
+        Ok(())
+    }
+}
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) -> u64 {
+        self.add(1)
+    }
+
+    /// Add `n` and return the new value.
+    pub fn add(&self, n: u64) -> u64 {
+        self.0.fetch_add(n, Ordering::Relaxed) + n
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// API requests sent, including retries.
+pub static API_REQUESTS: Counter = Counter::new();
+/// Retries of API requests.
+pub static API_RETRIES: Counter = Counter::new();
+/// Opened files which are already in disk cache.
+pub static DISK_CACHE_HITS: Counter = Counter::new();
+/// Opened files which are not in disk cache.
+pub static DISK_CACHE_MISSES: Counter = Counter::new();
+pub static DOWNLOADED_BYTES: Counter = Counter::new();
+pub static UPLOADED_BYTES: Counter = Counter::new();
+/// Seconds since UNIX epoch of the last successful API request, or 0 if none.
+static LAST_API_SUCCESS: AtomicU64 = AtomicU64::new(0);
+/// Seconds since UNIX epoch of the last successful sync of remote changes, or 0 if none.
+static LAST_SYNC: AtomicU64 = AtomicU64::new(0);
+/// API requests failed without any response since the last successful one.
+static NETWORK_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+fn record_now(time: &AtomicU64) {
+    let secs = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    time.store(secs, Ordering::Relaxed);
+}
+
+fn load_time(time: &AtomicU64) -> Option<SystemTime> {
+    match time.load(Ordering::Relaxed) {
+        0 => None,
+        secs => Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+    }
+}
+
+pub fn record_api_success() {
+    record_now(&LAST_API_SUCCESS);
+    NETWORK_FAILURES.store(0, Ordering::Relaxed);
+}
+
+/// Record a request failed due to connection failures or timeouts.
+pub fn record_network_failure() {
+    NETWORK_FAILURES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn network_failures() -> u64 {
+    NETWORK_FAILURES.load(Ordering::Relaxed)
+}
+
+pub fn last_api_success() -> Option<SystemTime> {
+    load_time(&LAST_API_SUCCESS)
+}
+
+pub fn record_sync() {
+    record_now(&LAST_SYNC);
+}
+
+pub fn last_sync() -> Option<SystemTime> {
+    load_time(&LAST_SYNC)
+}
+
+/// All counters in `key=value` format.
+pub fn summary() -> String {
+    format!(
+        "api_requests={} api_retries={} disk_cache_hits={} disk_cache_misses={} downloaded_bytes={} uploaded_bytes={}",
+        API_REQUESTS.get(),
+        API_RETRIES.get(),
+        DISK_CACHE_HITS.get(),
+        DISK_CACHE_MISSES.get(),
+        DOWNLOADED_BYTES.get(),
+        UPLOADED_BYTES.get(),
+    )
+}
+
+pub fn spawn_logger(config: Config) {
+    if !config.enable_log {
+        return;
+    }
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(config.log_period).await;
+            log::info!("Metrics: {}", summary());
+        }
+    });
+}
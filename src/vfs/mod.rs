@@ -1,31 +1,86 @@
 use crate::login::ManagedOnedrive;
-use onedrive_api::{resource::DriveItem, FileName, ItemLocation, OneDrive};
+use anyhow::Context as _;
+use onedrive_api::{
+    option::ObjectOption,
+    resource::{DriveField, DriveItem, DriveItemField},
+    FileName, ItemId, ItemLocation, OneDrive,
+};
 use serde::Deserialize;
 use std::{
+    collections::HashSet,
     ffi::OsStr,
-    ops::Deref,
-    sync::{Arc, Weak},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex as SyncMutex, OnceLock, Weak,
+    },
     time::{Duration, SystemTime},
 };
 use tokio::sync::{mpsc, oneshot};
 
+mod block_cache;
 pub mod error;
 mod file;
 mod inode;
 mod inode_id;
+mod metrics;
+pub mod op;
+mod retry;
 mod statfs;
+mod throttle;
+mod thumbnail;
 mod tracker;
 
 pub use error::{Error, Result};
 pub use inode::{DirEntry, InodeAttr};
+pub use metrics::{last_api_success, last_sync};
 pub use statfs::StatfsData;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
+    #[serde(default)]
+    root_path: Option<String>,
+    #[serde(default)]
+    root_special_folder: Option<String>,
+    #[serde(default)]
+    offline_after_failures: u64,
     statfs: statfs::Config,
     inode: inode::Config,
     file: file::Config,
     tracker: tracker::Config,
+    thumbnail: thumbnail::Config,
+    retry: retry::Config,
+    metrics: metrics::Config,
+}
+
+impl Config {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(path) = &self.root_path {
+            anyhow::ensure!(
+                path.starts_with('/'),
+                "`vfs.root_path` must start with `/`, got {:?}",
+                path,
+            );
+        }
+        if let Some(name) = &self.root_special_folder {
+            anyhow::ensure!(
+                self.root_path.is_none(),
+                "`vfs.root_path` and `vfs.root_special_folder` are mutually exclusive",
+            );
+            anyhow::ensure!(
+                SPECIAL_FOLDERS.contains(&&**name),
+                "`vfs.root_special_folder` must be one of {:?}, got {:?}",
+                SPECIAL_FOLDERS,
+                name,
+            );
+        }
+        self.statfs.validate()?;
+        self.inode.validate()?;
+        self.file.validate()?;
+        self.tracker.validate()?;
+        self.thumbnail.validate()?;
+        self.retry.validate()?;
+        self.metrics.validate()
+    }
 }
 
 #[derive(Debug)]
@@ -34,6 +89,18 @@ pub enum UpdateEvent {
     BatchUpdate(Vec<DriveItem>),
     /// Update attribute of a single file due to modification.
     UpdateFile(file::UpdatedFileAttr),
+    /// An upload failed permanently. Local changes are kept, but no longer pending.
+    UploadFailed(ItemId),
+    /// An upload failed due to insufficient storage, so the cached quota is outdated.
+    QuotaExceeded,
+}
+
+/// Receiver of invalidations of kernel caches on remote changes.
+pub trait Invalidator: Send + Sync + 'static {
+    /// Invalidate attributes and content of an inode.
+    fn inval_inode(&self, ino: u64);
+    /// Invalidate a directory entry, or a negative entry if it does not exist.
+    fn inval_entry(&self, parent_ino: u64, name: &str);
 }
 
 pub struct Vfs {
@@ -41,9 +108,19 @@ pub struct Vfs {
     id_pool: inode_id::InodeIdPool,
     inode_pool: inode::InodePool,
     file_pool: file::FilePool,
+    thumbnail_pool: thumbnail::ThumbnailPool,
     tracker: tracker::Tracker,
     onedrive: ManagedOnedrive,
     readonly: bool,
+    invalidator: OnceLock<Arc<dyn Invalidator>>,
+    /// Files and directories pinned in disk cache at runtime.
+    pinned_items: SyncMutex<HashSet<ItemId>>,
+    /// Consecutive network failures to go offline. Zero disables it.
+    offline_after_failures: u64,
+    /// Offline mode set at runtime, regardless of network failures.
+    offline_forced: AtomicBool,
+    /// Whether it was offline when last checked, to log the transitions.
+    offline: AtomicBool,
 }
 
 impl Vfs {
@@ -54,7 +131,13 @@ impl Vfs {
         onedrive: ManagedOnedrive,
         client: reqwest::Client,
     ) -> anyhow::Result<Arc<Self>> {
+        let root_id = match (&config.root_path, &config.root_special_folder) {
+            (Some(path), _) => Some(resolve_root_path(&*onedrive.get().await, path).await?),
+            (_, Some(name)) => Some(resolve_special_folder(&*onedrive.get().await, name).await?),
+            (None, None) => None,
+        };
         let statfs = statfs::Statfs::new(onedrive.clone(), config.statfs).await?;
+        metrics::spawn_logger(config.metrics);
 
         let (event_tx, event_rx) = mpsc::channel(1);
         let (init_tx, init_rx) = oneshot::channel();
@@ -69,32 +152,55 @@ impl Vfs {
             config.tracker,
         )
         .await?;
+        let file_pool = file::FilePool::new(
+            event_tx,
+            onedrive.clone(),
+            client.clone(),
+            config.file,
+            config.retry.clone(),
+            statfs.drive_id().unwrap_or("default"),
+        )?;
 
         let this = Arc::new(Self {
             statfs,
-            id_pool: inode_id::InodeIdPool::new(root_ino),
-            inode_pool: inode::InodePool::new(config.inode),
-            file_pool: file::FilePool::new(
-                event_tx,
+            id_pool: inode_id::InodeIdPool::new(root_ino, config.inode.ino_allocation),
+            inode_pool: inode::InodePool::new(config.inode, config.retry.clone()),
+            file_pool,
+            thumbnail_pool: thumbnail::ThumbnailPool::new(
                 onedrive.clone(),
-                client.clone(),
-                config.file,
-            )?,
+                client,
+                config.thumbnail,
+                config.retry,
+            ),
             tracker,
             onedrive,
             readonly,
+            invalidator: OnceLock::new(),
+            pinned_items: SyncMutex::new(HashSet::new()),
+            offline_after_failures: config.offline_after_failures,
+            offline_forced: AtomicBool::new(false),
+            offline: AtomicBool::new(false),
         });
 
-        tokio::task::spawn(Self::sync_thread(Arc::downgrade(&this), event_rx, init_tx));
+        tokio::task::spawn(Self::sync_thread(
+            Arc::downgrade(&this),
+            event_rx,
+            init_tx,
+            root_id,
+        ));
+        if this.offline_after_failures != 0 {
+            tokio::task::spawn(Self::offline_probe_thread(Arc::downgrade(&this)));
+        }
         // Wait for initialization.
-        init_rx.await.expect("Initialization failed");
+        init_rx.await.expect("Initialization failed")?;
         Ok(this)
     }
 
     async fn sync_thread(
         this: Weak<Self>,
         mut event_rx: mpsc::Receiver<UpdateEvent>,
-        init_tx: oneshot::Sender<()>,
+        init_tx: oneshot::Sender<anyhow::Result<()>>,
+        root_id: Option<ItemId>,
     ) {
         let mut init_tx = Some(init_tx);
         while let Some(event) = event_rx.recv().await {
@@ -105,31 +211,73 @@ impl Vfs {
 
             match event {
                 UpdateEvent::BatchUpdate(updated) => {
-                    this.inode_pool.sync_items(&updated);
+                    let inval = this.inode_pool.sync_items(&updated);
                     this.file_pool.sync_items(&updated).await;
+                    this.invalidate(inval);
+                    // Items may be moved into or out of pinned paths.
+                    this.refresh_pins_of(&updated);
+                    // It's done in background since it needs requests. They're exposed as
+                    // regular files until then.
+                    let unverified = this.inode_pool.take_unverified_symlinks(&updated);
+                    if !unverified.is_empty() {
+                        let this = this.clone();
+                        tokio::spawn(async move { this.verify_symlinks(unverified).await });
+                    }
 
                     if let Some(init_tx) = init_tx.take() {
-                        let root_id = updated
-                            .iter()
-                            .find(|item| item.root.is_some())
-                            .expect("No root item found")
-                            .id
-                            .as_ref()
-                            .expect("Missing id");
-                        this.id_pool.set_root_item_id(root_id.clone());
-
-                        if init_tx.send(()).is_err() {
+                        // The whole drive is always synced, but only the subtree under the
+                        // configured root is exposed.
+                        let root_id = root_id.clone().or_else(|| {
+                            updated
+                                .iter()
+                                .find(|item| item.root.is_some())
+                                .and_then(|item| item.id.clone())
+                        });
+                        // The root must be a directory with valid attributes, or `stat` on the
+                        // mount point would fail.
+                        let root_id = match root_id {
+                            Some(id) => match this.inode_pool.get_attr(&id) {
+                                Ok(attr) if attr.is_directory => Ok(id),
+                                Ok(_) => {
+                                    Err(anyhow::anyhow!("Root item {:?} is not a directory", id))
+                                }
+                                Err(_) => Err(anyhow::anyhow!(
+                                    "Root item {:?} is missing or invalid in the initial sync",
+                                    id,
+                                )),
+                            },
+                            None => Err(anyhow::anyhow!("No root item in the initial sync")),
+                        };
+                        let root_id = match root_id {
+                            Ok(id) => id,
+                            Err(err) => {
+                                let _ = init_tx.send(Err(err));
+                                return;
+                            }
+                        };
+                        this.id_pool.set_root_item_id(root_id);
+                        // Files deleted remotely when unmounted are not in the initial sync.
+                        this.file_pool
+                            .retain_cached(|id| this.inode_pool.get_attr(id).is_ok());
+
+                        if init_tx.send(Ok(())).is_err() {
                             return;
                         }
                     }
                 }
+                UpdateEvent::QuotaExceeded => this.statfs.request_refresh(),
+                UpdateEvent::UploadFailed(item_id) => this.inode_pool.clear_dirty(&item_id),
                 // This event will be triggered after a successful upload.
                 UpdateEvent::UpdateFile(updated) => {
+                    this.statfs.request_refresh();
                     this.inode_pool
                         .update_attr(&updated.item_id, |attr| InodeAttr {
                             size: updated.size,
                             mtime: updated.mtime,
                             c_tag: Some(updated.c_tag.clone()),
+                            // The new eTag and hashes are filled by the next sync.
+                            e_tag: None,
+                            hashes: Default::default(),
                             dirty: true,
                             ..attr
                         });
@@ -138,14 +286,173 @@ impl Vfs {
         }
     }
 
-    async fn onedrive(&self) -> impl Deref<Target = OneDrive> + '_ {
-        self.onedrive.get().await
+    /// Set the receiver of kernel cache invalidations. It can only be set once.
+    pub fn set_invalidator(&self, invalidator: Arc<dyn Invalidator>) {
+        if self.invalidator.set(invalidator).is_err() {
+            panic!("Invalidator is already set");
+        }
+    }
+
+    fn is_pinned(&self, item_id: &ItemId) -> bool {
+        let pinned_items = self.pinned_items.lock().unwrap();
+        self.inode_pool
+            .is_under(item_id, &pinned_items, self.file_pool.pinned_paths())
+    }
+
+    fn has_pins(&self) -> bool {
+        !self.pinned_items.lock().unwrap().is_empty() || !self.file_pool.pinned_paths().is_empty()
+    }
+
+    /// Recalculate pins of all cached files, after pins are changed.
+    fn refresh_pins(&self) {
+        // Skip walking the tree for each cached file in the common case.
+        if !self.has_pins() {
+            self.file_pool.refresh_pins(|_| false);
+            return;
+        }
+        self.file_pool.refresh_pins(|id| self.is_pinned(id));
+    }
+
+    /// Recalculate pins of cached files affected by synced items. Moving a directory may change
+    /// pins of any file under it, and all of them are recalculated then.
+    fn refresh_pins_of(&self, updated: &[DriveItem]) {
+        if !self.has_pins() {
+            return;
+        }
+        if updated.iter().any(|item| item.folder.is_some()) {
+            self.refresh_pins();
+            return;
+        }
+        for id in updated.iter().filter_map(|item| item.id.as_ref()) {
+            self.file_pool.set_pinned(id, self.is_pinned(id));
+        }
+    }
+
+    /// Recalculate the pin of a single cached file, eg. after it's newly cached.
+    fn refresh_pin(&self, item_id: &ItemId) {
+        if self.has_pins() {
+            self.file_pool.set_pinned(item_id, self.is_pinned(item_id));
+        }
+    }
+
+    fn invalidate(&self, inval: inode::Invalidations) {
+        let Some(invalidator) = self.invalidator.get().cloned() else {
+            return;
+        };
+        // Only inodes known by the kernel can be cached.
+        let inos = inval
+            .items
+            .iter()
+            .filter_map(|id| self.id_pool.get_ino(id))
+            .collect::<Vec<_>>();
+        let entries = inval
+            .entries
+            .into_iter()
+            .filter_map(|(parent_id, name)| {
+                let parent_ino = self.id_pool.get_ino(&parent_id)?;
+                // Marker files may be exposed as symlinks without the suffix.
+                let link_name = self.inode_pool.link_name(&name).map(|link_name| {
+                    (
+                        parent_ino,
+                        self.inode_pool.escape_name(link_name).into_owned(),
+                    )
+                });
+                let name = self.inode_pool.escape_name(&name).into_owned();
+                Some(std::iter::once((parent_ino, name)).chain(link_name))
+            })
+            .flatten()
+            .collect::<Vec<_>>();
+        if inos.is_empty() && entries.is_empty() {
+            return;
+        }
+        log::debug!(
+            "Invalidating {} inodes and {} entries in kernel",
+            inos.len(),
+            entries.len(),
+        );
+        // Notifications may block until the kernel finishes operations on them.
+        tokio::task::spawn_blocking(move || {
+            for ino in inos {
+                invalidator.inval_inode(ino);
+            }
+            for (parent_ino, name) in entries {
+                invalidator.inval_entry(parent_ino, &name);
+            }
+        });
     }
 
-    fn ttl(&self) -> Duration {
+    fn ttl(&self, item_id: &ItemId) -> Duration {
         // Use `i64::MAX` to avoid overflowing `libc::time_t`;
         const MAX_TTL: Duration = Duration::from_secs(i64::MAX as u64);
-        self.tracker.time_to_next_sync().unwrap_or(MAX_TTL)
+        let ttl = self.tracker.time_to_next_sync().unwrap_or(MAX_TTL);
+        match self.inode_pool.max_attr_ttl(item_id) {
+            Some(max_ttl) => ttl.min(max_ttl),
+            None => ttl,
+        }
+    }
+
+    /// Reject mutating operations on readonly mounts or when offline, before any request is sent.
+    fn check_writable(&self) -> Result<()> {
+        if self.readonly || self.is_offline() {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Whether to serve cached data only, instead of waiting for requests to time out.
+    /// It goes online again once any request succeeds, like the periodic fetch of the tracker.
+    fn is_offline(&self) -> bool {
+        let offline = self.offline_forced.load(Ordering::Relaxed)
+            || (self.offline_after_failures != 0
+                && self.offline_after_failures <= metrics::network_failures());
+        if self.offline.swap(offline, Ordering::Relaxed) != offline {
+            if offline {
+                log::warn!("Network is unreachable, serving cached data only");
+            } else {
+                log::info!("Network is back, leaving offline mode");
+            }
+        }
+        offline
+    }
+
+    /// Check whether the network is back periodically when offline, since no other request may
+    /// be sent then, eg. when the tracker is disabled or frozen.
+    async fn offline_probe_thread(this: Weak<Self>) {
+        const PROBE_PERIOD: Duration = Duration::from_secs(30);
+
+        loop {
+            tokio::time::sleep(PROBE_PERIOD).await;
+            let Some(this) = this.upgrade() else { return };
+            if this.offline_forced.load(Ordering::Relaxed) || !this.is_offline() {
+                continue;
+            }
+            log::debug!("Probing network in offline mode");
+            metrics::API_REQUESTS.inc();
+            let ret = this
+                .onedrive
+                .get()
+                .await
+                .get_drive_with_option(ObjectOption::new().select(&[DriveField::id]))
+                .await;
+            match ret {
+                Err(err) if retry::is_api_network_failure(&err) => {
+                    log::debug!("Network is still unreachable: {}", err)
+                }
+                // Any response means the network is back.
+                _ => {
+                    metrics::record_api_success();
+                    this.is_offline();
+                }
+            }
+        }
+    }
+
+    /// Reject operations which always need requests when offline.
+    fn check_online(&self) -> Result<()> {
+        if self.is_offline() {
+            return Err(Error::Offline);
+        }
+        Ok(())
     }
 
     pub async fn statfs(&self) -> Result<StatfsData> {
@@ -158,14 +465,15 @@ impl Vfs {
         &self,
         parent_ino: u64,
         child_name: &OsStr,
-    ) -> Result<(u64, InodeAttr, Duration)> {
+    ) -> Result<(u64, u64, InodeAttr, Duration)> {
         let parent_id = self.id_pool.get_item_id(parent_ino)?;
-        let child_name = cvt_filename(child_name)?;
+        let child_name = self.inode_pool.unescape_name(child_name);
+        let child_name = cvt_filename(&child_name)?;
         let id = self.inode_pool.lookup(&parent_id, child_name)?;
         let attr = self.inode_pool.get_attr(&id)?;
         let ino = self.id_pool.acquire_or_alloc(&id);
         log::trace!(target: "vfs::inode", "lookup: id={:?} ino={} attr={:?}", id, ino, attr);
-        Ok((ino, attr, self.ttl()))
+        Ok((ino, self.id_pool.generation(ino), attr, self.ttl(&id)))
     }
 
     pub async fn forget(&self, ino: u64, count: u64) -> Result<()> {
@@ -174,41 +482,378 @@ impl Vfs {
         Ok(())
     }
 
+    /// Forget multiple `(ino, count)` at once, taking the lock only once.
+    pub async fn batch_forget(&self, nodes: &[(u64, u64)]) -> Result<()> {
+        let freed = self.id_pool.free_many(nodes)?;
+        log::trace!(target: "vfs::inode", "batch_forget: nodes={} freed={}", nodes.len(), freed);
+        Ok(())
+    }
+
     pub async fn get_attr(&self, ino: u64) -> Result<(InodeAttr, Duration)> {
         let id = self.id_pool.get_item_id(ino)?;
         let attr = self.inode_pool.get_attr(&id)?;
         log::trace!(target: "vfs::inode", "get_attr: id={:?} ino={} attr={:?}", id, ino, attr);
-        Ok((attr, self.ttl()))
+        Ok((attr, self.ttl(&id)))
+    }
+
+    /// Names of read-only extended attributes exposing OneDrive metadata.
+    const XATTR_ID: &'static str = "user.onedrive.id";
+    const XATTR_ETAG: &'static str = "user.onedrive.etag";
+    const XATTR_CTAG: &'static str = "user.onedrive.ctag";
+    const XATTR_QUICKXORHASH: &'static str = "user.onedrive.quickxorhash";
+    const XATTR_SHA1: &'static str = "user.onedrive.sha1";
+    const XATTR_SHA256: &'static str = "user.onedrive.sha256";
+    const XATTR_PACKAGE: &'static str = "user.onedrive.package";
+    /// The MIME type known by OneDrive, in the common name used by file managers.
+    const XATTR_MIME_TYPE: &'static str = "user.mime_type";
+    /// Who created or last modified the item, as `Display Name <email>`.
+    const XATTR_CREATED_BY: &'static str = "user.onedrive.created_by";
+    const XATTR_MODIFIED_BY: &'static str = "user.onedrive.modified_by";
+    /// Followed by `small`, `medium` or `large`. Not listed since they're fetched on demand.
+    const XATTR_THUMBNAIL_PREFIX: &'static str = "user.onedrive.thumbnail.";
+    /// Control attributes available on every inode, but not listed.
+    /// Reading `stats` returns counters of `metrics`, and setting `sync` triggers a sync now.
+    /// Reading `sync_status` returns the delta token and the last sync in JSON,
+    /// and setting `resync` discards the delta token to fetch the whole tree again.
+    const XATTR_STATS: &'static str = "user.onedrive.stats";
+    const XATTR_SYNC: &'static str = "user.onedrive.sync";
+    const XATTR_SYNC_STATUS: &'static str = "user.onedrive.sync_status";
+    const XATTR_RESYNC: &'static str = "user.onedrive.resync";
+    /// Setting `refresh` on a directory fetches it and its children now, without a full sync.
+    const XATTR_REFRESH: &'static str = "user.onedrive.refresh";
+    /// Setting `freeze` to a number of seconds stops fetching remote changes for that long,
+    /// for a consistent view during traversals like backups. Setting `unfreeze` ends it early.
+    const XATTR_FREEZE: &'static str = "user.onedrive.freeze";
+    const XATTR_UNFREEZE: &'static str = "user.onedrive.unfreeze";
+    /// Setting `offline` to `1` serves cached data only as if the network is unreachable,
+    /// and `0` restores the automatic detection. The tracker keeps fetching remote changes.
+    const XATTR_OFFLINE: &'static str = "user.onedrive.offline";
+    /// Setting `pin` or `unpin` on a file or directory keeps files under it from eviction of
+    /// disk cache or not. Paths in `vfs.file.disk_cache.pinned_paths` cannot be unpinned.
+    const XATTR_PIN: &'static str = "user.onedrive.pin";
+    const XATTR_UNPIN: &'static str = "user.onedrive.unpin";
+    /// Information of the mounted drive and account in JSON.
+    const XATTR_DRIVE: &'static str = "user.onedrive.drive";
+    /// Previous versions of a file. Not listed since they're fetched on demand.
+    const XATTR_VERSIONS: &'static str = "user.onedrive.versions";
+    /// The download URL of a previous version, followed by its id from `versions`.
+    const XATTR_VERSION_URL_PREFIX: &'static str = "user.onedrive.version_url.";
+    /// Write-only. Setting it to a version id from `versions` restores that version as the
+    /// current content of the file.
+    const XATTR_RESTORE_VERSION: &'static str = "user.onedrive.restore_version";
+    /// The URL to open the item in the browser, useful for items without downloadable content.
+    /// Not listed since it's fetched on demand.
+    const XATTR_WEB_URL: &'static str = "user.onedrive.web_url";
+    /// Sharing links in JSON. Not listed since they're fetched on demand.
+    const XATTR_SHARE_LINKS: &'static str = "user.onedrive.share_links";
+    /// Write-only. Setting it to a link type like `view` or `edit`, optionally followed by a
+    /// scope like `edit:organization`, creates a sharing link.
+    const XATTR_CREATE_SHARE_LINK: &'static str = "user.onedrive.create_share_link";
+
+    pub async fn get_xattr(&self, ino: u64, name: &OsStr) -> Result<Vec<u8>> {
+        let id = self.id_pool.get_item_id(ino)?;
+        let attr = self.inode_pool.get_attr(&id)?;
+        log::trace!(target: "vfs::inode", "get_xattr: ino={} name={:?}", ino, name);
+        if let Some(size) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(Self::XATTR_THUMBNAIL_PREFIX))
+        {
+            let size = thumbnail::ThumbnailSize::parse(size).ok_or(Error::NoAttribute)?;
+            self.check_online()?;
+            let data = self
+                .thumbnail_pool
+                .get(&id, attr.c_tag.as_ref(), size)
+                .await?;
+            return Ok(data.to_vec());
+        }
+        if let Some(version_id) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(Self::XATTR_VERSION_URL_PREFIX))
+        {
+            if attr.is_directory || version_id.is_empty() {
+                return Err(Error::NoAttribute);
+            }
+            self.check_online()?;
+            return Ok(self
+                .file_pool
+                .version_url(&id, version_id)
+                .await?
+                .into_bytes());
+        }
+        match name.to_str() {
+            Some(Self::XATTR_STATS) => return Ok(metrics::summary().into_bytes()),
+            Some(Self::XATTR_SYNC_STATUS) => return Ok(self.tracker.status().into_bytes()),
+            Some(Self::XATTR_DRIVE) => return Ok(self.statfs.drive_info().into_bytes()),
+            Some(Self::XATTR_VERSIONS) if !attr.is_directory => {
+                self.check_online()?;
+                return Ok(self.file_pool.list_versions(&id).await?.into_bytes());
+            }
+            Some(Self::XATTR_WEB_URL) => {
+                self.check_online()?;
+                return Ok(self
+                    .inode_pool
+                    .web_url(&id, &self.onedrive)
+                    .await?
+                    .into_bytes());
+            }
+            Some(Self::XATTR_SHARE_LINKS) => {
+                self.check_online()?;
+                return Ok(self
+                    .inode_pool
+                    .share_links(&id, &self.onedrive)
+                    .await?
+                    .into_bytes());
+            }
+            _ => {}
+        }
+        let value = match name.to_str() {
+            Some(Self::XATTR_ID) => Some(id.as_str()),
+            Some(Self::XATTR_ETAG) => attr.e_tag.as_ref().map(|tag| tag.as_str()),
+            Some(Self::XATTR_CTAG) => attr.c_tag.as_ref().map(|tag| tag.as_str()),
+            Some(Self::XATTR_QUICKXORHASH) => attr.hashes.quick_xor.as_deref(),
+            Some(Self::XATTR_SHA1) => attr.hashes.sha1.as_deref(),
+            Some(Self::XATTR_SHA256) => attr.hashes.sha256.as_deref(),
+            Some(Self::XATTR_PACKAGE) => attr.package.as_deref(),
+            Some(Self::XATTR_MIME_TYPE) => attr.mime_type.as_deref(),
+            Some(Self::XATTR_CREATED_BY) => attr.created_by.as_deref(),
+            Some(Self::XATTR_MODIFIED_BY) => attr.modified_by.as_deref(),
+            _ => None,
+        };
+        value
+            .map(|value| value.as_bytes().to_vec())
+            .ok_or(Error::NoAttribute)
+    }
+
+    pub async fn set_xattr(&self, ino: u64, name: &OsStr, value: &[u8]) -> Result<()> {
+        log::trace!(target: "vfs::inode", "set_xattr: ino={} name={:?}", ino, name);
+        let id = self.id_pool.get_item_id(ino)?;
+        match name.to_str() {
+            Some(Self::XATTR_CREATE_SHARE_LINK) => {
+                self.check_writable()?;
+                let value = std::str::from_utf8(value)
+                    .map_err(|_| Error::InvalidArgument("Non UTF-8 link type".into()))?
+                    .trim();
+                let (link_type, scope) = match value.split_once(':') {
+                    Some((link_type, scope)) => (link_type, Some(scope)),
+                    None => (value, None),
+                };
+                if link_type.is_empty() || scope == Some("") {
+                    return Err(Error::InvalidArgument(format!(
+                        "Invalid link type {:?}",
+                        value
+                    )));
+                }
+                self.inode_pool
+                    .create_share_link(&id, link_type, scope, &self.onedrive)
+                    .await
+            }
+            Some(Self::XATTR_RESTORE_VERSION) => {
+                self.check_writable()?;
+                let attr = self.inode_pool.get_attr(&id)?;
+                if attr.is_directory {
+                    return Err(Error::IsADirectory);
+                }
+                // Pending local writes would overwrite the restored content.
+                if attr.dirty {
+                    return Err(Error::Uploading);
+                }
+                let version_id = std::str::from_utf8(value)
+                    .ok()
+                    .map(str::trim)
+                    .filter(|id| !id.is_empty())
+                    .ok_or_else(|| {
+                        Error::InvalidArgument(format!(
+                            "Invalid version id {:?}",
+                            String::from_utf8_lossy(value)
+                        ))
+                    })?;
+                self.file_pool.restore_version(&id, version_id).await?;
+                // Caches are invalidated once the new cTag is fetched.
+                self.tracker.request_sync();
+                Ok(())
+            }
+            Some(Self::XATTR_SYNC) => {
+                self.tracker.request_sync();
+                Ok(())
+            }
+            Some(Self::XATTR_RESYNC) => {
+                self.tracker.request_resync();
+                Ok(())
+            }
+            Some(Self::XATTR_FREEZE) => std::str::from_utf8(value)
+                .ok()
+                .and_then(|s| s.trim().parse::<u64>().ok())
+                .and_then(|secs| self.tracker.freeze(Duration::from_secs(secs)))
+                .ok_or_else(|| {
+                    Error::InvalidArgument(format!(
+                        "Invalid freezing seconds {:?}",
+                        String::from_utf8_lossy(value)
+                    ))
+                }),
+            Some(Self::XATTR_UNFREEZE) => {
+                self.tracker.unfreeze();
+                Ok(())
+            }
+            Some(Self::XATTR_OFFLINE) => {
+                let forced = match value.trim_ascii() {
+                    b"1" => true,
+                    b"0" => false,
+                    _ => {
+                        return Err(Error::InvalidArgument(format!(
+                            "Invalid offline mode {:?}, expecting `0` or `1`",
+                            String::from_utf8_lossy(value)
+                        )))
+                    }
+                };
+                self.offline_forced.store(forced, Ordering::Relaxed);
+                self.is_offline();
+                Ok(())
+            }
+            Some(Self::XATTR_REFRESH) => {
+                self.check_online()?;
+                let (items, missing) = self.inode_pool.fetch_dir(&id, &self.onedrive).await?;
+                log::debug!(
+                    "Refreshed directory {:?} with {} items, missing children: {}",
+                    id,
+                    items.len(),
+                    missing,
+                );
+                let inval = self.inode_pool.sync_items(&items);
+                self.file_pool.sync_items(&items).await;
+                self.invalidate(inval);
+                self.refresh_pins_of(&items);
+                self.verify_symlinks(self.inode_pool.take_unverified_symlinks(&items))
+                    .await;
+                if missing {
+                    self.tracker.request_sync();
+                }
+                Ok(())
+            }
+            Some(name @ (Self::XATTR_PIN | Self::XATTR_UNPIN)) => {
+                let mut pinned_items = self.pinned_items.lock().unwrap();
+                if name == Self::XATTR_PIN {
+                    pinned_items.insert(id);
+                } else {
+                    pinned_items.remove(&id);
+                }
+                drop(pinned_items);
+                self.refresh_pins();
+                Ok(())
+            }
+            _ => Err(Error::NotSupported),
+        }
+    }
+
+    /// Return the null-terminated list of extended attribute names.
+    pub async fn list_xattr(&self, ino: u64) -> Result<Vec<u8>> {
+        let id = self.id_pool.get_item_id(ino)?;
+        let attr = self.inode_pool.get_attr(&id)?;
+        log::trace!(target: "vfs::inode", "list_xattr: ino={}", ino);
+        let mut names = vec![Self::XATTR_ID];
+        if attr.e_tag.is_some() {
+            names.push(Self::XATTR_ETAG);
+        }
+        if attr.c_tag.is_some() {
+            names.push(Self::XATTR_CTAG);
+        }
+        for (name, hash) in [
+            (Self::XATTR_QUICKXORHASH, &attr.hashes.quick_xor),
+            (Self::XATTR_SHA1, &attr.hashes.sha1),
+            (Self::XATTR_SHA256, &attr.hashes.sha256),
+        ] {
+            if hash.is_some() {
+                names.push(name);
+            }
+        }
+        if attr.package.is_some() {
+            names.push(Self::XATTR_PACKAGE);
+        }
+        if attr.mime_type.is_some() {
+            names.push(Self::XATTR_MIME_TYPE);
+        }
+        if attr.created_by.is_some() {
+            names.push(Self::XATTR_CREATED_BY);
+        }
+        if attr.modified_by.is_some() {
+            names.push(Self::XATTR_MODIFIED_BY);
+        }
+        Ok(names
+            .into_iter()
+            .flat_map(|name| name.bytes().chain(Some(0)))
+            .collect())
     }
 
-    // fh is not used for directories.
     pub async fn open_dir(&self, ino: u64) -> Result<u64> {
-        log::trace!(target: "vfs::dir", "open_dir: ino={}", ino);
-        Ok(0)
+        let id = self.id_pool.get_item_id(ino)?;
+        let fh = self.inode_pool.open_dir(&id)?;
+        log::trace!(target: "vfs::dir", "open_dir: ino={} fh={}", ino, fh);
+        Ok(fh)
     }
 
-    // fh is not used for directories.
-    pub async fn close_dir(&self, ino: u64, _fh: u64) -> Result<()> {
-        log::trace!(target: "vfs::dir", "close_dir: ino={}", ino);
-        Ok(())
+    pub async fn close_dir(&self, ino: u64, fh: u64) -> Result<()> {
+        log::trace!(target: "vfs::dir", "close_dir: ino={} fh={}", ino, fh);
+        self.inode_pool.close_dir(fh)
     }
 
     pub async fn read_dir(
         &self,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: u64,
         count: usize,
     ) -> Result<impl AsRef<[DirEntry]>> {
         let parent_id = self.id_pool.get_item_id(ino)?;
-        let ret = self.inode_pool.read_dir(&parent_id, offset, count)?;
+        let ret = self.inode_pool.read_dir(fh, &parent_id, offset, count)?;
         log::trace!(target: "vfs::dir", "read_dir: ino={} offset={}", ino, offset);
         Ok(ret)
     }
 
-    pub async fn open_file(&self, ino: u64, write: bool) -> Result<u64> {
+    /// Read entries of a directory with inode numbers allocated for each of them, along with
+    /// the TTL of each child. Every returned inode is acquired once, so it should be `forget` if
+    /// not sent to the kernel.
+    pub async fn read_dir_plus(
+        &self,
+        ino: u64,
+        fh: u64,
+        offset: u64,
+        count: usize,
+    ) -> Result<Vec<(u64, u64, DirEntry, Duration)>> {
+        let parent_id = self.id_pool.get_item_id(ino)?;
+        let ret = self
+            .inode_pool
+            .read_dir(fh, &parent_id, offset, count)?
+            .into_iter()
+            .map(|entry| {
+                let ino = self.id_pool.acquire_or_alloc(&entry.item_id);
+                let ttl = self.ttl(&entry.item_id);
+                (ino, self.id_pool.generation(ino), entry, ttl)
+            })
+            .collect();
+        log::trace!(target: "vfs::dir", "read_dir_plus: ino={} offset={}", ino, offset);
+        Ok(ret)
+    }
+
+    /// Open a file. `direct` reads always download from remote, bypassing all caches.
+    pub async fn open_file(&self, ino: u64, write: bool, direct: bool) -> Result<u64> {
+        if write {
+            self.check_writable()?;
+        }
         let item_id = self.id_pool.get_item_id(ino)?;
-        let fh = self.file_pool.open(&item_id, write).await?;
+        // `opendir` on files is rejected by `InodePool::open_dir`, and this is the other way.
+        let attr = self.inode_pool.get_attr(&item_id)?;
+        if attr.is_directory {
+            return Err(Error::IsADirectory);
+        }
+        if attr.package.is_some() {
+            return Err(Error::NotSupported);
+        }
+        // Fail fast instead of waiting for the download to time out.
+        if (direct || !self.file_pool.is_cached(&item_id).await) && self.is_offline() {
+            return Err(Error::Offline);
+        }
+        let fh = self.file_pool.open(&item_id, write, direct).await?;
+        // Newly cached files may be pinned.
+        self.refresh_pin(&item_id);
         log::trace!(target: "vfs::file", "open_file: ino={} fh={}", ino, fh);
         Ok(fh)
     }
@@ -219,24 +864,56 @@ impl Vfs {
         child_name: &OsStr,
         truncate: bool,
         exclusive: bool,
-    ) -> Result<(u64, u64, InodeAttr, Duration)> {
+    ) -> Result<(u64, u64, u64, InodeAttr, Duration)> {
+        self.check_writable()?;
         let parent_id = self.id_pool.get_item_id(parent_ino)?;
-        let child_name = cvt_filename(child_name)?;
-        if !truncate {
-            // FIXME: Not atomic.
-            match self.inode_pool.lookup(&parent_id, child_name) {
-                Ok(id) => {
-                    if exclusive {
-                        return Err(Error::FileExists);
+        let child_name = self.inode_pool.unescape_name(child_name);
+        let child_name = cvt_filename(&child_name)?;
+        // FIXME: Not atomic.
+        match self.inode_pool.lookup(&parent_id, child_name) {
+            Ok(id) => {
+                if exclusive {
+                    return Err(Error::FileExists);
+                }
+                let mut attr = self.inode_pool.get_attr(&id)?;
+                if attr.is_directory {
+                    return Err(Error::IsADirectory);
+                }
+                // Neither the handle nor the lookup count reaches the kernel on failures.
+                let ino = self.id_pool.acquire_or_alloc(&id);
+                let fh = match self.open_file(ino, true, false).await {
+                    Ok(fh) => fh,
+                    Err(err) => {
+                        self.id_pool.free(ino, 1)?;
+                        return Err(err);
+                    }
+                };
+                // Truncate the existing file in cache, to be uploaded along with later writes.
+                if truncate {
+                    match self.set_attr(ino, Some(0), None).await {
+                        Ok((new_attr, _)) => attr = new_attr,
+                        Err(err) => {
+                            if let Err(err) = self.file_pool.close(fh).await {
+                                log::warn!("Failed to close file {:?}: {}", id, err);
+                            }
+                            self.id_pool.free(ino, 1)?;
+                            return Err(err);
+                        }
                     }
-                    let attr = self.inode_pool.get_attr(&id)?;
-                    let ino = self.id_pool.acquire_or_alloc(&id);
-                    let fh = self.open_file(ino, true).await?;
-                    return Ok((ino, fh, attr, self.ttl()));
                 }
-                Err(Error::NotFound) => {}
-                Err(err) => return Err(err),
+                return Ok((ino, self.id_pool.generation(ino), fh, attr, self.ttl(&id)));
+            }
+            // OneDrive would replace the existing item whose name differs only in cases.
+            Err(Error::NotFound)
+                if self
+                    .inode_pool
+                    .lookup_ignore_case(&parent_id, child_name)
+                    .is_ok() =>
+            {
+                return Err(Error::FileExists);
             }
+            Err(Error::NotFound) => {}
+            Err(err) => return Err(err),
         }
         let (fh, item_id, attr) = self
             .file_pool
@@ -244,8 +921,110 @@ impl Vfs {
             .await?;
         self.inode_pool
             .insert_item(parent_id.clone(), child_name, item_id.clone(), attr.clone());
+        self.refresh_pin(&item_id);
         let ino = self.id_pool.acquire_or_alloc(&item_id);
-        Ok((ino, fh, attr, self.ttl()))
+        Ok((
+            ino,
+            self.id_pool.generation(ino),
+            fh,
+            attr,
+            self.ttl(&item_id),
+        ))
+    }
+
+    /// Create a symlink, stored as a marker file `<name><vfs.inode.symlink_suffix>` with
+    /// `inode::SYMLINK_MAGIC` followed by the target as its content.
+    pub async fn create_symlink(
+        &self,
+        parent_ino: u64,
+        name: &OsStr,
+        target: &[u8],
+    ) -> Result<(u64, u64, InodeAttr, Duration)> {
+        self.check_writable()?;
+        let parent_id = self.id_pool.get_item_id(parent_ino)?;
+        let name = self.inode_pool.unescape_name(name);
+        let name = cvt_filename(&name)?;
+        let content = [inode::SYMLINK_MAGIC, target].concat();
+        if inode::parse_symlink_marker(&content).is_none() {
+            return Err(Error::InvalidArgument(format!(
+                "Invalid symlink target length {}",
+                target.len()
+            )));
+        }
+        let marker_name = self.inode_pool.symlink_marker_name(name)?;
+        let marker_name = cvt_filename(OsStr::new(&marker_name))?;
+        // FIXME: Not atomic.
+        for name in [name, marker_name] {
+            if self.inode_pool.lookup_ignore_case(&parent_id, name).is_ok() {
+                return Err(Error::FileExists);
+            }
+        }
+        let (item_id, attr) = self
+            .file_pool
+            .create_small(
+                ItemLocation::child_of_id(&parent_id, marker_name),
+                content.into(),
+            )
+            .await?;
+        let c_tag = attr.c_tag.clone().expect("Files have c_tag");
+        self.inode_pool
+            .insert_item(parent_id.clone(), marker_name, item_id.clone(), attr);
+        self.inode_pool.set_symlink_target(&item_id, c_tag, target);
+        let attr = self.inode_pool.get_attr(&item_id)?;
+        let ino = self.id_pool.acquire_or_alloc(&item_id);
+        log::trace!(
+            target: "vfs::dir",
+            "create_symlink: parent_ino={} name={} ino={} attr={:?}",
+            parent_ino, name.as_str(), ino, attr,
+        );
+        Ok((ino, self.id_pool.generation(ino), attr, self.ttl(&item_id)))
+    }
+
+    /// Read the target of a symlink, which is kept in memory once verified.
+    pub async fn read_symlink(&self, ino: u64) -> Result<Arc<[u8]>> {
+        let item_id = self.id_pool.get_item_id(ino)?;
+        let target = self
+            .inode_pool
+            .symlink_target(&item_id)
+            .ok_or_else(|| Error::InvalidArgument(format!("Not a symlink: {:?}", item_id)))?;
+        log::trace!(target: "vfs::file", "read_symlink: ino={} target={:?}", ino, target);
+        Ok(target)
+    }
+
+    /// Fetch the content of possible marker files of symlinks, and expose valid ones as symlinks.
+    /// Ones failed to fetch are tried again on the next sync.
+    async fn verify_symlinks(&self, item_ids: Vec<ItemId>) {
+        let mut inval = inode::Invalidations::default();
+        for item_id in item_ids {
+            if self.is_offline() {
+                self.inode_pool.defer_unverified_symlink(item_id);
+                continue;
+            }
+            let (c_tag, data) = match self.file_pool.read_small(&item_id).await {
+                Ok(ret) => ret,
+                Err(Error::NotFound) => continue,
+                Err(err) => {
+                    log::warn!("Failed to fetch possible symlink {:?}: {}", item_id, err);
+                    self.inode_pool.defer_unverified_symlink(item_id);
+                    continue;
+                }
+            };
+            let Some(target) = inode::parse_symlink_marker(&data) else {
+                log::debug!("File {:?} is not a symlink marker", item_id);
+                continue;
+            };
+            log::debug!("Verified symlink {:?} to {:?}", item_id, target);
+            inval
+                .entries
+                .extend(self.inode_pool.set_symlink_target(&item_id, c_tag, target));
+            inval.items.push(item_id);
+        }
+        self.invalidate(inval);
+    }
+
+    /// Wait for pending uploads before exit, up to `vfs.file.upload.shutdown_timeout`.
+    pub async fn shutdown(&self) {
+        self.file_pool.flush_all().await;
     }
 
     pub async fn close_file(&self, ino: u64, fh: u64) -> Result<()> {
@@ -278,12 +1057,14 @@ impl Vfs {
         &self,
         parent_ino: u64,
         name: &OsStr,
-    ) -> Result<(u64, InodeAttr, Duration)> {
-        let name = cvt_filename(name)?;
+    ) -> Result<(u64, u64, InodeAttr, Duration)> {
+        self.check_writable()?;
+        let name = self.inode_pool.unescape_name(name);
+        let name = cvt_filename(&name)?;
         let parent_id = self.id_pool.get_item_id(parent_ino)?;
         let (id, attr) = self
             .inode_pool
-            .create_dir(&parent_id, name, &*self.onedrive().await)
+            .create_dir(&parent_id, name, &self.onedrive)
             .await?;
         let ino = self.id_pool.acquire_or_alloc(&id);
         log::trace!(
@@ -291,7 +1072,7 @@ impl Vfs {
             "create_dir: parent_id={:?} parent_ino={} name={} id={:?} ino={}",
             parent_id, parent_ino, name.as_str(), id, ino,
         );
-        Ok((ino, attr, self.ttl()))
+        Ok((ino, self.id_pool.generation(ino), attr, self.ttl(&id)))
     }
 
     pub async fn rename(
@@ -300,11 +1081,63 @@ impl Vfs {
         name: &OsStr,
         new_parent_ino: u64,
         new_name: &OsStr,
+        no_replace: bool,
     ) -> Result<()> {
-        let name = cvt_filename(name)?;
-        let new_name = cvt_filename(new_name)?;
+        self.check_writable()?;
+        let name = self.inode_pool.unescape_name(name);
+        let name = cvt_filename(&name)?;
+        let new_name = self.inode_pool.unescape_name(new_name);
+        let new_name = cvt_filename(&new_name)?;
         let parent_id = self.id_pool.get_item_id(parent_ino)?;
         let new_parent_id = self.id_pool.get_item_id(new_parent_ino)?;
+
+        // Symlinks are moved along with the suffix of their marker files. Replacing between
+        // a symlink and another item cannot be done in one move, since their names differ.
+        let marker_name = self.inode_pool.resolve_symlink(&parent_id, name);
+        let new_marker_name = self.inode_pool.resolve_symlink(&new_parent_id, new_name);
+        let replaced_other = match (&marker_name, &new_marker_name) {
+            (Some(_), None) => self
+                .inode_pool
+                .lookup(&new_parent_id, new_name)
+                .is_ok()
+                .then(|| new_name.as_str().to_owned()),
+            (None, Some(new_marker_name)) => {
+                let id = self.inode_pool.lookup(&parent_id, name)?;
+                if self.inode_pool.get_attr(&id)?.is_directory {
+                    return Err(Error::NotADirectory);
+                }
+                Some(new_marker_name.clone())
+            }
+            _ => None,
+        };
+        if let Some(replaced_name) = replaced_other {
+            if no_replace {
+                return Err(Error::FileExists);
+            }
+            let id = self
+                .inode_pool
+                .remove(
+                    &new_parent_id,
+                    cvt_filename(OsStr::new(&replaced_name))?,
+                    false,
+                    &self.onedrive,
+                )
+                .await?;
+            self.invalidate_file_cache(id).await;
+        }
+        let new_marker_name = marker_name
+            .as_ref()
+            .map(|_| self.inode_pool.symlink_marker_name(new_name))
+            .transpose()?;
+        let name = match &marker_name {
+            Some(marker_name) => cvt_filename(OsStr::new(marker_name))?,
+            None => name,
+        };
+        let new_name = match &new_marker_name {
+            Some(new_marker_name) => cvt_filename(OsStr::new(new_marker_name))?,
+            None => new_name,
+        };
+
         let replaced_item_id = self
             .inode_pool
             .rename(
@@ -312,15 +1145,13 @@ impl Vfs {
                 name,
                 &new_parent_id,
                 new_name,
-                &*self.onedrive().await,
+                no_replace,
+                &self.onedrive,
             )
             .await?;
         // If some item is replace, remove it from cache.
         if let Some(id) = replaced_item_id {
-            let mut mock_item = DriveItem::default();
-            mock_item.id = Some(id);
-            mock_item.deleted = Some(Box::new(serde_json::Value::Null));
-            self.file_pool.sync_items(&[mock_item]).await;
+            self.invalidate_file_cache(id).await;
         }
         log::trace!(
             target: "vfs::dir",
@@ -332,10 +1163,12 @@ impl Vfs {
     }
 
     pub async fn remove_dir(&self, parent_ino: u64, name: &OsStr) -> Result<()> {
-        let name = cvt_filename(name)?;
+        self.check_writable()?;
+        let name = self.inode_pool.unescape_name(name);
+        let name = cvt_filename(&name)?;
         let parent_id = self.id_pool.get_item_id(parent_ino)?;
         self.inode_pool
-            .remove(&parent_id, name, true, &*self.onedrive().await)
+            .remove(&parent_id, name, true, &self.onedrive)
             .await?;
         log::trace!(
             target: "vfs::dir",
@@ -345,12 +1178,25 @@ impl Vfs {
         Ok(())
     }
 
+    /// Remove a file.
+    ///
+    /// Unlike POSIX, the content is not kept for opened handles. Further reads and writes on
+    /// them fail with `Error::Invalidated`, the same as the file is removed remotely.
     pub async fn remove_file(&self, parent_ino: u64, name: &OsStr) -> Result<()> {
-        let name = cvt_filename(name)?;
+        self.check_writable()?;
+        let name = self.inode_pool.unescape_name(name);
+        let name = cvt_filename(&name)?;
         let parent_id = self.id_pool.get_item_id(parent_ino)?;
-        self.inode_pool
-            .remove(&parent_id, name, false, &*self.onedrive().await)
+        let marker_name = self.inode_pool.resolve_symlink(&parent_id, name);
+        let name = match &marker_name {
+            Some(marker_name) => cvt_filename(OsStr::new(marker_name))?,
+            None => name,
+        };
+        let id = self
+            .inode_pool
+            .remove(&parent_id, name, false, &self.onedrive)
             .await?;
+        self.invalidate_file_cache(id).await;
         log::trace!(
             target: "vfs::dir",
             "remove_file: parent_id={:?} parent_ino={} name={}",
@@ -359,12 +1205,34 @@ impl Vfs {
         Ok(())
     }
 
-    pub async fn write_file(&self, ino: u64, fh: u64, offset: u64, data: &[u8]) -> Result<()> {
-        let updated = self.file_pool.write(fh, offset, data).await?;
+    /// Drop cache of a removed item immediately, without waiting for the next sync.
+    async fn invalidate_file_cache(&self, item_id: ItemId) {
+        let mut mock_item = DriveItem::default();
+        mock_item.id = Some(item_id);
+        mock_item.deleted = Some(Box::new(serde_json::Value::Null));
+        mock_item.file = Some(Box::new(serde_json::Value::Null));
+        self.file_pool.sync_items(&[mock_item]).await;
+    }
+
+    /// Write at `offset`, or at the current end of file for handles opened with `O_APPEND`.
+    pub async fn write_file(
+        &self,
+        ino: u64,
+        fh: u64,
+        offset: u64,
+        data: &[u8],
+        append: bool,
+    ) -> Result<()> {
+        self.check_writable()?;
+        let updated = self
+            .file_pool
+            .write(fh, (!append).then_some(offset), data)
+            .await?;
         self.inode_pool
             .update_attr(&updated.item_id, |attr| InodeAttr {
                 size: updated.size,
                 mtime: updated.mtime,
+                hashes: Default::default(),
                 dirty: true,
                 ..attr
             });
@@ -382,11 +1250,15 @@ impl Vfs {
         size: Option<u64>,
         mtime: Option<SystemTime>,
     ) -> Result<(InodeAttr, Duration)> {
+        self.check_writable()?;
         let item_id = self.id_pool.get_item_id(ino)?;
         let old_attr = self.inode_pool.get_attr(&item_id)?;
         if size.is_some() && old_attr.is_directory {
             return Err(Error::IsADirectory);
         }
+        if old_attr.package.is_some() {
+            return Err(Error::NotSupported);
+        }
 
         let new_attr = match (size, mtime) {
             // Truncate.
@@ -399,25 +1271,50 @@ impl Vfs {
                     dirty: true,
                     size: new_size,
                     mtime,
+                    hashes: Default::default(),
                     ..attr
                 })
             }
-            // Touch mtime
+            // Touch mtime. Files with pending changes will be uploaded with it, otherwise the
+            // upload would override it.
             (_, Some(mtime)) => {
-                self.inode_pool
-                    .set_time(&item_id, mtime, &*self.onedrive().await)
-                    .await?
+                if self.file_pool.set_pending_mtime(&item_id, mtime).await? {
+                    self.inode_pool
+                        .update_attr(&item_id, |attr| InodeAttr { mtime, ..attr })
+                } else {
+                    self.inode_pool
+                        .set_time(&item_id, mtime, &self.onedrive)
+                        .await?
+                }
             }
-            // Do nothing.
+            // atime is not stored by OneDrive. Accept it as a no-op.
             (_, None) => self.inode_pool.get_attr(&item_id)?,
         };
+        let new_attr = InodeAttr {
+            is_symlink: old_attr.is_symlink,
+            ..new_attr
+        };
 
         log::trace!(
             target: "vfs::file",
             "truncate_file: ino={} id={:?} new_size={:?} new_mtime={:?} ret_attr={:?}",
             ino, item_id, size, mtime, new_attr,
         );
-        Ok((new_attr, self.ttl()))
+        Ok((new_attr, self.ttl(&item_id)))
+    }
+
+    pub async fn flush_file(&self, ino: u64) -> Result<()> {
+        if self.readonly {
+            return Ok(());
+        }
+        let item_id = self.id_pool.get_item_id(ino)?;
+        self.file_pool.flush_on_close(&item_id).await?;
+        log::trace!(
+            target: "vfs::file",
+            "flush_file: ino={} id={:?}",
+            ino, item_id,
+        );
+        Ok(())
     }
 
     pub async fn sync_file(&self, ino: u64) -> Result<()> {
@@ -435,6 +1332,65 @@ impl Vfs {
     }
 }
 
+async fn resolve_root_path(onedrive: &OneDrive, path: &str) -> anyhow::Result<ItemId> {
+    let loc =
+        ItemLocation::from_path(path).with_context(|| format!("Invalid root path: {:?}", path))?;
+    let item = onedrive
+        .get_item_with_option(
+            loc,
+            ObjectOption::new().select(&[DriveItemField::id, DriveItemField::folder]),
+        )
+        .await
+        .with_context(|| format!("Failed to resolve root path {:?}", path))?
+        .expect("No If-None-Match");
+    anyhow::ensure!(
+        item.folder.is_some(),
+        "Root path {:?} is not a directory",
+        path,
+    );
+    let id = item.id.context("Missing id")?;
+    log::info!("Using {:?} ({:?}) as root", path, id);
+    Ok(id)
+}
+
+/// Well-known names of special folders, independent of their localized display names.
+const SPECIAL_FOLDERS: &[&str] = &[
+    "documents",
+    "photos",
+    "cameraroll",
+    "approot",
+    "music",
+    "recordings",
+];
+
+async fn resolve_special_folder(onedrive: &OneDrive, name: &str) -> anyhow::Result<ItemId> {
+    let drive = onedrive
+        .get_drive_with_option(
+            ObjectOption::new()
+                .select(&[DriveField::id])
+                .expand(DriveField::special, Some(&["id", "specialFolder"])),
+        )
+        .await
+        .context("Failed to list special folders")?;
+    // Only existing special folders are listed. Some are created lazily on first use by
+    // other clients, and `approot` needs the app folder permission.
+    let id = drive
+        .special
+        .unwrap_or_default()
+        .into_iter()
+        .find(|item| {
+            item.special_folder
+                .as_ref()
+                .and_then(|v| v.get("name")?.as_str())
+                == Some(name)
+        })
+        .with_context(|| format!("Special folder {:?} does not exist in the drive", name))?
+        .id
+        .context("Missing id")?;
+    log::info!("Using special folder {:?} ({:?}) as root", name, id);
+    Ok(id)
+}
+
 fn cvt_filename(name: &OsStr) -> Result<&FileName> {
     name.to_str()
         .and_then(FileName::new)
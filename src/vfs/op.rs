@@ -0,0 +1,27 @@
+//! Correlation of API requests with the FUSE operation issuing them, for logging.
+use std::{fmt, future::Future};
+
+tokio::task_local! {
+    static CURRENT: u64;
+}
+
+/// Run `fut` as the FUSE operation with the kernel-assigned `unique` id.
+pub async fn scope<F: Future>(unique: u64, fut: F) -> F::Output {
+    CURRENT.scope(unique, fut).await
+}
+
+/// Displayed as `[op <unique>] ` inside a FUSE operation, or nothing in background tasks.
+pub fn tag() -> impl fmt::Display {
+    struct Tag(Option<u64>);
+
+    impl fmt::Display for Tag {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self.0 {
+                Some(unique) => write!(f, "[op {}] ", unique),
+                None => Ok(()),
+            }
+        }
+    }
+
+    Tag(CURRENT.try_with(|unique| *unique).ok())
+}
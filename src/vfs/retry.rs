@@ -0,0 +1,233 @@
+//! Retry of API requests on throttling, transient server errors and network failures.
+use crate::{
+    config::de_duration_sec,
+    vfs::{
+        error::{Error, Result},
+        metrics, op,
+    },
+};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::{
+    future::Future,
+    time::{Duration, Instant, SystemTime},
+};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Config {
+    max_attempts: usize,
+    #[serde(deserialize_with = "de_duration_sec")]
+    initial_delay: Duration,
+    #[serde(deserialize_with = "de_duration_sec")]
+    max_delay: Duration,
+}
+
+impl Config {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.max_attempts != 0,
+            "`vfs.retry.max_attempts` must be positive",
+        );
+        anyhow::ensure!(
+            self.initial_delay <= self.max_delay,
+            "`vfs.retry.initial_delay` must not be greater than `vfs.retry.max_delay`",
+        );
+        Ok(())
+    }
+}
+
+/// Run `f` and retry with jittered exponential backoff on throttling, transient server errors
+/// or network failures like timeouts, until it succeeds or `max_attempts` is reached.
+///
+/// `f` must be safe to be repeated, since a failed attempt may still take effect on the server.
+/// Use `retry_checked` otherwise.
+///
+/// `Retry-After` is not available from `onedrive_api` errors, so it's not honored.
+pub async fn retry<T, F, Fut>(config: &Config, what: &str, f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    retry_checked(config, what, f, || async { Ok(None) }).await
+}
+
+/// Like `retry`, but call `check` before each retry to see whether the previous failed attempt
+/// actually succeeded on the server. If it returns `Some`, it is taken as the result
+/// without retrying `f`.
+pub async fn retry_checked<T, F, Fut, C, CFut>(
+    config: &Config,
+    what: &str,
+    mut f: F,
+    mut check: C,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+    C: FnMut() -> CFut,
+    CFut: Future<Output = Result<Option<T>>>,
+{
+    let mut delay = config.initial_delay;
+    let mut attempt = 1;
+    loop {
+        if attempt > 1 {
+            match check().await {
+                Ok(Some(ret)) => {
+                    log::debug!("Previous attempt to {} succeeded", what);
+                    return Ok(ret);
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    log::debug!("Failed to check the previous attempt to {}: {}", what, err)
+                }
+            }
+        }
+        metrics::API_REQUESTS.inc();
+        let start = Instant::now();
+        let ret = f().await;
+        match &ret {
+            Ok(_) => {
+                metrics::record_api_success();
+                log::trace!(
+                    target: "vfs::api",
+                    "{}{}: ok in {:?}",
+                    op::tag(),
+                    what,
+                    start.elapsed(),
+                );
+            }
+            Err(err) => {
+                if is_network_failure(err) {
+                    metrics::record_network_failure();
+                }
+                log::trace!(
+                    target: "vfs::api",
+                    "{}{}: failed in {:?}: {}",
+                    op::tag(),
+                    what,
+                    start.elapsed(),
+                    err,
+                )
+            }
+        }
+        match ret {
+            Err(err) if attempt < config.max_attempts && is_transient(&err) => {
+                let total = metrics::API_RETRIES.inc();
+                let jittered = jitter(delay);
+                log::warn!(
+                    "Failed to {} (attempt {}/{}, {} retries in total), retry in {:?}: {}",
+                    what,
+                    attempt,
+                    config.max_attempts,
+                    total,
+                    jittered,
+                    err,
+                );
+                tokio::time::sleep(jittered).await;
+                delay = (delay * 2).min(config.max_delay);
+                attempt += 1;
+            }
+            ret => return ret,
+        }
+    }
+}
+
+fn is_transient(err: &Error) -> bool {
+    match err {
+        _ if is_network_failure(err) => true,
+        Error::Throttled(_) => true,
+        Error::Api(err) => matches!(
+            err.status_code(),
+            Some(
+                StatusCode::INTERNAL_SERVER_ERROR
+                    | StatusCode::BAD_GATEWAY
+                    | StatusCode::GATEWAY_TIMEOUT
+            )
+        ),
+        _ => false,
+    }
+}
+
+/// Whether the request failed to get any response, due to connection failures or timeouts.
+fn is_network_failure(err: &Error) -> bool {
+    match err {
+        Error::TimedOut(_) => true,
+        Error::Reqwest(err) => is_reqwest_network_failure(err),
+        Error::Api(err) => is_api_network_failure(err),
+        _ => false,
+    }
+}
+
+/// Whether the request failed without any response, due to connection failures or timeouts.
+/// Errors of unexpected or undecodable responses don't count, since the server is reachable.
+///
+/// `onedrive_api` 0.9 keeps the underlying `reqwest::Error` out of the `source` chain, so it
+/// falls back to the message of its request errors, which is `Request error: ` followed by the
+/// `reqwest` message of the error kind.
+pub fn is_api_network_failure(err: &onedrive_api::Error) -> bool {
+    if err.error_response().is_some() || err.status_code().is_some() {
+        return false;
+    }
+    is_reqwest_network_failure(err)
+        || err
+            .to_string()
+            .starts_with("Request error: error sending request")
+}
+
+/// Whether any `reqwest::Error` in the `source` chain is a connection failure or a timeout.
+fn is_reqwest_network_failure(err: &(dyn std::error::Error + 'static)) -> bool {
+    std::iter::successors(Some(err), |err| err.source())
+        .filter_map(|err| err.downcast_ref::<reqwest::Error>())
+        .any(|err| err.is_connect() || err.is_timeout())
+}
+
+/// Randomize the delay to 50% ~ 100%, so concurrent requests don't retry at the same time.
+fn jitter(delay: Duration) -> Duration {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let ratio = 0.5 + f64::from(nanos % 1000) / 2000.0;
+    delay.mul_f64(ratio)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    #[tokio::test]
+    async fn api_network_failure() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let resp = "HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nbad";
+                let _ = stream.write_all(resp.as_bytes()).await;
+            }
+        });
+        let client = reqwest::Client::new();
+
+        let err = client
+            .get(format!("http://{}", addr))
+            .send()
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap_err();
+        assert!(!is_reqwest_network_failure(&err));
+        assert!(!is_api_network_failure(&err.into()));
+
+        // Nothing listens on the port once it's dropped.
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+        let err = client
+            .get(format!("http://{}", closed_addr))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(is_reqwest_network_failure(&err));
+        assert!(is_api_network_failure(&err.into()));
+    }
+}
@@ -3,15 +3,23 @@ use crate::{
     login::ManagedOnedrive,
     vfs::error::{Error, Result},
 };
-use onedrive_api::OneDrive;
+use onedrive_api::{
+    option::ObjectOption,
+    resource::{Drive, DriveField},
+    OneDrive,
+};
 use serde::Deserialize;
 use std::{
     sync::{Arc, Mutex as SyncMutex, Weak},
     time::Duration,
 };
+use tokio::sync::Notify;
 
 pub struct Statfs {
     cache: Arc<SyncMutex<StatfsData>>,
+    refresh_notify: Arc<Notify>,
+    /// Id, type and owner of the drive, which never change.
+    drive_info: serde_json::Value,
 }
 
 #[derive(Debug, Deserialize)]
@@ -21,6 +29,16 @@ pub struct Config {
     refresh_period: Duration,
 }
 
+impl Config {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !self.enable_auto_refresh || !self.refresh_period.is_zero(),
+            "`vfs.statfs.refresh_period` must be positive",
+        );
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct StatfsData {
     pub total: u64,
@@ -29,26 +47,48 @@ pub struct StatfsData {
 
 impl Statfs {
     pub async fn new(onedrive: ManagedOnedrive, config: Config) -> Result<Self> {
-        let data = Self::statfs_raw(&*onedrive.get().await).await?;
+        let drive = onedrive
+            .get()
+            .await
+            .get_drive_with_option(ObjectOption::new().select(&[
+                DriveField::id,
+                DriveField::drive_type,
+                DriveField::owner,
+                DriveField::quota,
+            ]))
+            .await?;
+        let drive_info = serde_json::json!({
+            "id": drive.id.as_ref().map(|id| id.as_str()),
+            "driveType": drive.drive_type,
+            "owner": drive.owner,
+        });
+        let data = Self::parse_quota(drive)?;
         let cache = Arc::new(SyncMutex::new(data));
+        let refresh_notify = Arc::new(Notify::new());
         if config.enable_auto_refresh {
             tokio::spawn(Self::refresh_thread(
                 Arc::downgrade(&cache),
+                refresh_notify.clone(),
                 config.refresh_period,
                 onedrive,
             ));
         }
-        Ok(Self { cache })
+        Ok(Self {
+            cache,
+            refresh_notify,
+            drive_info,
+        })
     }
 
     async fn refresh_thread(
         this: Weak<SyncMutex<StatfsData>>,
+        refresh_notify: Arc<Notify>,
         period: Duration,
         onedrive: ManagedOnedrive,
     ) {
         loop {
             // We don't need to catch up.
-            tokio::time::sleep(period).await;
+            let _ = tokio::time::timeout(period, refresh_notify.notified()).await;
 
             let this = match this.upgrade() {
                 Some(arc) => arc,
@@ -70,24 +110,56 @@ impl Statfs {
         *self.cache.lock().unwrap()
     }
 
-    async fn statfs_raw(onedrive: &OneDrive) -> Result<StatfsData> {
-        use onedrive_api::{option::ObjectOption, resource::DriveField};
+    /// Refresh the quota as soon as possible, eg. after an upload.
+    /// Does nothing if auto-refresh is disabled.
+    pub fn request_refresh(&self) {
+        self.refresh_notify.notify_one();
+    }
 
-        #[derive(Debug, Deserialize)]
-        struct Quota {
-            total: u64,
-            remaining: u64,
-            // used: u64,
-        }
+    /// The id of the mounted drive, if returned by the server.
+    pub fn drive_id(&self) -> Option<&str> {
+        self.drive_info["id"].as_str()
+    }
+
+    /// Information of the mounted drive in JSON, along with the current quota and our version.
+    pub fn drive_info(&self) -> String {
+        let StatfsData { total, free } = self.statfs();
+        let mut info = self.drive_info.clone();
+        info["quota"] = serde_json::json!({ "total": total, "remaining": free });
+        info["version"] = env!("CARGO_PKG_VERSION").into();
+        info.to_string()
+    }
 
+    async fn statfs_raw(onedrive: &OneDrive) -> Result<StatfsData> {
         let drive = onedrive
             .get_drive_with_option(ObjectOption::new().select(&[DriveField::quota]))
             .await?;
-        let quota: Quota =
-            serde_json::from_value(*drive.quota.unwrap()).map_err(Error::Deserialize)?;
-        Ok(StatfsData {
-            total: quota.total,
-            free: quota.remaining,
-        })
+        Self::parse_quota(drive)
+    }
+
+    fn parse_quota(drive: Drive) -> Result<StatfsData> {
+        // Reported when the quota is unlimited or unknown.
+        const UNKNOWN_TOTAL: u64 = 1 << 50; // 1 PiB
+
+        #[derive(Debug, Default, Deserialize)]
+        struct Quota {
+            total: Option<u64>,
+            remaining: Option<u64>,
+            used: Option<u64>,
+        }
+
+        let quota: Quota = match drive.quota {
+            Some(quota) => serde_json::from_value(*quota).map_err(Error::Deserialize)?,
+            None => Quota::default(),
+        };
+        let total = quota
+            .total
+            .filter(|&total| total != 0)
+            .unwrap_or(UNKNOWN_TOTAL);
+        let free = quota
+            .remaining
+            .unwrap_or_else(|| total.saturating_sub(quota.used.unwrap_or(0)))
+            .min(total);
+        Ok(StatfsData { total, free })
     }
 }
@@ -0,0 +1,65 @@
+//! Bandwidth throttling of file transfers.
+use crate::config::de_byte_size;
+use serde::{de::Deserializer, Deserialize};
+use std::{
+    sync::{Arc, Mutex as SyncMutex},
+    time::{Duration, Instant},
+};
+
+/// Token bucket over byte counts, shared by all transfers in one direction.
+#[derive(Debug)]
+pub struct Throttle {
+    /// Bytes per second, or zero for unlimited. It is also the capacity of the bucket.
+    rate: u64,
+    /// Available bytes and the last refill time.
+    /// It goes negative after a chunk larger than available is consumed.
+    bucket: SyncMutex<(f64, Instant)>,
+}
+
+impl Throttle {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            bucket: SyncMutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    /// Wait until `bytes` are allowed to be transferred.
+    ///
+    /// Chunks larger than the capacity are allowed by going into debt and waiting it off,
+    /// so it never deadlocks.
+    pub async fn consume(&self, bytes: usize) {
+        if self.rate == 0 {
+            return;
+        }
+        let rate = self.rate as f64;
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let (tokens, last) = &mut *bucket;
+            let now = Instant::now();
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * rate).min(rate);
+            *last = now;
+            *tokens -= bytes as f64;
+            if *tokens < 0.0 {
+                Duration::from_secs_f64(-*tokens / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Deserialize a byte rate into a shared `Throttle`, so clones of the config share the limit.
+pub fn de_throttle<'de, D>(de: D) -> Result<Arc<Throttle>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Rate(#[serde(deserialize_with = "de_byte_size")] u64);
+
+    let Rate(rate) = Rate::deserialize(de)?;
+    Ok(Arc::new(Throttle::new(rate)))
+}
@@ -0,0 +1,139 @@
+//! Thumbnails generated by OneDrive.
+use crate::{
+    login::ManagedOnedrive,
+    vfs::{
+        error::{Error, Result},
+        retry::{self, retry},
+    },
+};
+use lru_cache::LruCache;
+use onedrive_api::{option::ObjectOption, resource::DriveItemField, ItemId, ItemLocation, Tag};
+use serde::Deserialize;
+use std::sync::{Arc, Mutex as SyncMutex};
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    enable: bool,
+    cache_count: usize,
+}
+
+impl Config {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !self.enable || self.cache_count != 0,
+            "`vfs.thumbnail.cache_count` must be positive",
+        );
+        Ok(())
+    }
+}
+
+// Thumbnails are keyed with CTag to be invalidated on content changes.
+type CacheKey = (ItemId, Option<Tag>, ThumbnailSize);
+
+pub struct ThumbnailPool {
+    cache: SyncMutex<LruCache<CacheKey, Arc<[u8]>>>,
+    onedrive: ManagedOnedrive,
+    client: reqwest::Client,
+    config: Config,
+    retry: retry::Config,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ThumbnailSize {
+    Small,
+    Medium,
+    Large,
+}
+
+impl ThumbnailSize {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "small" => Some(Self::Small),
+            "medium" => Some(Self::Medium),
+            "large" => Some(Self::Large),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Small => "small",
+            Self::Medium => "medium",
+            Self::Large => "large",
+        }
+    }
+}
+
+impl ThumbnailPool {
+    pub fn new(
+        onedrive: ManagedOnedrive,
+        client: reqwest::Client,
+        config: Config,
+        retry: retry::Config,
+    ) -> Self {
+        Self {
+            cache: SyncMutex::new(LruCache::new(config.cache_count)),
+            onedrive,
+            client,
+            config,
+            retry,
+        }
+    }
+
+    /// Get the JPEG thumbnail of an item, or `Error::NoAttribute` if there is none.
+    pub async fn get(
+        &self,
+        item_id: &ItemId,
+        c_tag: Option<&Tag>,
+        size: ThumbnailSize,
+    ) -> Result<Arc<[u8]>> {
+        if !self.config.enable {
+            return Err(Error::NoAttribute);
+        }
+
+        let key = (item_id.clone(), c_tag.cloned(), size);
+        if let Some(data) = self.cache.lock().unwrap().get_mut(&key) {
+            return Ok(data.clone());
+        }
+
+        let item = retry(&self.retry, "get thumbnail", || async {
+            Ok(self
+                .onedrive
+                .get()
+                .await
+                .get_item_with_option(
+                    ItemLocation::from_id(item_id),
+                    ObjectOption::new()
+                        .select(&[DriveItemField::id])
+                        .expand(DriveItemField::thumbnails, Some(&[size.as_str()])),
+                )
+                .await?)
+        })
+        .await?
+        .expect("No If-None-Match");
+        // Items without thumbnails have an empty thumbnail set list.
+        let url = item
+            .thumbnails
+            .as_ref()
+            .and_then(|sets| sets.get(0)?.get(size.as_str())?.get("url")?.as_str())
+            .ok_or(Error::NoAttribute)?
+            .to_owned();
+
+        log::debug!("Downloading {} thumbnail of {:?}", size.as_str(), item_id);
+        let data: Arc<[u8]> = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            // Thumbnail URLs may contain tokens.
+            .map_err(|err| err.without_url())?
+            .bytes()
+            .await
+            .map_err(|err| err.without_url())?
+            .as_ref()
+            .into();
+        self.cache.lock().unwrap().insert(key, data.clone());
+        Ok(data)
+    }
+}
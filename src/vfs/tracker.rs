@@ -1,17 +1,24 @@
-use crate::{config::de_duration_sec, login::ManagedOnedrive, vfs::UpdateEvent};
+use crate::{
+    config::de_duration_sec,
+    login::ManagedOnedrive,
+    vfs::{metrics, retry, UpdateEvent},
+};
 use onedrive_api::{
     option::CollectionOption,
     resource::{DriveItem, DriveItemField},
-    OneDrive,
 };
+use reqwest::StatusCode;
 use serde::Deserialize;
 use std::{
     collections::HashSet,
     num::NonZeroUsize,
-    sync::{Arc, Mutex as SyncMutex, Weak},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex as SyncMutex, Weak,
+    },
     time::{Duration, Instant},
 };
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Notify};
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
@@ -21,11 +28,46 @@ pub struct Config {
     fetch_page_size: NonZeroUsize,
 }
 
+impl Config {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !self.enable || !self.period.is_zero(),
+            "`vfs.tracker.period` must be positive",
+        );
+        Ok(())
+    }
+}
+
 pub struct Tracker {
     last_sync_time: Option<Arc<SyncMutex<Instant>>>,
+    sync_notify: Arc<Notify>,
+    state: Arc<SyncState>,
     config: Config,
 }
 
+/// States shared with the tracking thread for diagnostics.
+#[derive(Default)]
+struct SyncState {
+    /// Set to discard the delta URL before the next fetch.
+    resync: AtomicBool,
+    /// The current delta URL and the number of changes in the last fetch.
+    status: SyncMutex<(Option<String>, usize)>,
+    /// Remote changes are not fetched until then.
+    frozen_until: SyncMutex<Option<Instant>>,
+}
+
+impl SyncState {
+    /// The remaining time of freezing, if frozen.
+    fn frozen_left(&self) -> Option<Duration> {
+        let mut frozen_until = self.frozen_until.lock().unwrap();
+        let left = (*frozen_until)?.checked_duration_since(Instant::now());
+        if left.is_none() {
+            *frozen_until = None;
+        }
+        left
+    }
+}
+
 impl Tracker {
     pub async fn new(
         event_tx: mpsc::Sender<UpdateEvent>,
@@ -41,17 +83,22 @@ impl Tracker {
             }
         };
 
+        let sync_notify = Arc::new(Notify::new());
+        let state = Arc::new(SyncState::default());
         tokio::spawn(tracking_thread(
-            None,
             event_tx,
             select_fields,
             onedrive,
             weak,
+            sync_notify.clone(),
+            state.clone(),
             config.clone(),
         ));
 
         Ok(Self {
             last_sync_time,
+            sync_notify,
+            state,
             config,
         })
     }
@@ -61,26 +108,91 @@ impl Tracker {
         // Zero if time exceeded.
         Some(self.config.period.checked_sub(passed).unwrap_or_default())
     }
+
+    /// Fetch changes now instead of waiting for the next period.
+    pub fn request_sync(&self) {
+        log::info!("Immediate sync requested");
+        self.sync_notify.notify_one();
+    }
+
+    /// Discard the delta URL and fetch the whole tree now.
+    pub fn request_resync(&self) {
+        log::info!("Full re-sync requested");
+        self.state.resync.store(true, Ordering::Relaxed);
+        self.sync_notify.notify_one();
+    }
+
+    /// Stop fetching remote changes for `duration`, so traversals see a consistent tree.
+    /// Local changes still apply, and remote ones are fetched all at once after that.
+    /// Return `None` if `duration` is too long to represent.
+    pub fn freeze(&self, duration: Duration) -> Option<()> {
+        let until = Instant::now().checked_add(duration)?;
+        log::info!("Remote changes are frozen for {:?}", duration);
+        *self.state.frozen_until.lock().unwrap() = Some(until);
+        Some(())
+    }
+
+    /// Stop freezing and fetch remote changes now.
+    pub fn unfreeze(&self) {
+        log::info!("Remote changes are unfrozen");
+        *self.state.frozen_until.lock().unwrap() = None;
+        self.sync_notify.notify_one();
+    }
+
+    /// Current sync status in JSON.
+    pub fn status(&self) -> String {
+        let (delta_url, last_changes) = self.state.status.lock().unwrap().clone();
+        let delta_token = delta_url.as_deref().and_then(|url| {
+            let url = reqwest::Url::parse(url).ok()?;
+            let (_, token) = url.query_pairs().find(|(key, _)| key == "token")?;
+            Some(token.into_owned())
+        });
+        let last_sync = metrics::last_sync();
+        serde_json::json!({
+            "enable": self.config.enable,
+            "delta_token": delta_token,
+            "last_sync": last_sync.map(|time| humantime::format_rfc3339_seconds(time).to_string()),
+            "last_sync_age_secs": last_sync
+                .map(|time| time.elapsed().unwrap_or_default().as_secs()),
+            "last_sync_changes": last_changes,
+            "resync_pending": self.state.resync.load(Ordering::Relaxed),
+            "frozen_secs": self.state.frozen_left().map(|left| left.as_secs()),
+        })
+        .to_string()
+    }
 }
 
 async fn tracking_thread(
-    mut delta_url: Option<String>,
     event_tx: mpsc::Sender<UpdateEvent>,
     select_fields: Vec<DriveItemField>,
-    onedrive: ManagedOnedrive,
+    onedrive_mgr: ManagedOnedrive,
     last_sync_time: Weak<SyncMutex<Instant>>,
+    sync_notify: Arc<Notify>,
+    state: Arc<SyncState>,
     config: Config,
 ) {
     log::debug!("Tracking thread started");
 
+    let mut delta_url = None;
     loop {
+        // Also woken up by unfreezing.
+        if let Some(left) = state.frozen_left() {
+            let _ = tokio::time::timeout(left, sync_notify.notified()).await;
+            continue;
+        }
+
         // Do the first fetch immediately.
         let start_time = Instant::now();
+        if state.resync.swap(false, Ordering::Relaxed) {
+            delta_url = None;
+        }
 
-        let onedrive = onedrive.get().await;
+        let ret = fetch_changes(&mut delta_url, &select_fields, &onedrive_mgr, &config).await;
 
-        match fetch_changes(&mut delta_url, &select_fields, &onedrive, &config).await {
+        match ret {
             Ok(Some(changes)) => {
+                metrics::record_sync();
+                *state.status.lock().unwrap() = (delta_url.clone(), changes.len());
                 if event_tx
                     .send(UpdateEvent::BatchUpdate(changes))
                     .await
@@ -92,7 +204,15 @@ async fn tracking_thread(
             // Wait for the next scan.
             Ok(None) => continue,
             Err(err) => {
+                // Wait for the next scan instead of retrying immediately, or we may be throttled.
                 log::error!("Failed to fetch changes: {}", err);
+                if retry::is_api_network_failure(&err) {
+                    metrics::record_network_failure();
+                }
+                if err.status_code() == Some(StatusCode::UNAUTHORIZED) {
+                    onedrive_mgr.request_relogin();
+                }
+                tokio::time::sleep(config.period).await;
                 continue;
             }
         }
@@ -103,7 +223,7 @@ async fn tracking_thread(
         }
 
         // We don't need to catch up.
-        tokio::time::sleep(config.period).await;
+        let _ = tokio::time::timeout(config.period, sync_notify.notified()).await;
     }
 }
 
@@ -113,7 +233,7 @@ async fn tracking_thread(
 async fn fetch_changes(
     delta_url: &mut Option<String>,
     select_fields: &[DriveItemField],
-    onedrive: &OneDrive,
+    onedrive: &ManagedOnedrive,
     config: &Config,
 ) -> onedrive_api::Result<Option<Vec<DriveItem>>> {
     let mut fetcher = match delta_url {
@@ -125,15 +245,24 @@ async fn fetch_changes(
                 .select(&[DriveItemField::id])
                 .select(select_fields);
             onedrive
+                .get()
+                .await
                 .track_root_changes_from_initial_with_option(opt)
                 .await?
         }
         // Delta fetch.
         Some(url) => {
             log::debug!("Checking remote changes");
-            match onedrive.track_root_changes_from_delta_url(url).await {
+            let ret = onedrive
+                .get()
+                .await
+                .track_root_changes_from_delta_url(url)
+                .await;
+            match ret {
                 Ok(fetcher) => fetcher,
-                Err(err) if err.status_code().map_or(false, |st| st.is_client_error()) => {
+                // The token is expired, which doesn't mean the delta URL is gone.
+                Err(err) if err.status_code() == Some(StatusCode::UNAUTHORIZED) => return Err(err),
+                Err(err) if err.status_code().is_some_and(|st| st.is_client_error()) => {
                     log::info!("Re-sync required. Delta URL is gone: {}", err);
                     *delta_url = None;
                     return Ok(None);
@@ -147,7 +276,14 @@ async fn fetch_changes(
     let mut total_changes = 0usize;
     let mut ret = Vec::new();
     let mut seen_ids = HashSet::new();
-    while let Some(changes) = fetcher.fetch_next_page(onedrive).await? {
+    // Take the client for each page, so the rate limit applies and relogin is not blocked
+    // through a long initial fetch.
+    while let Some(changes) = {
+        let onedrive = onedrive.get().await;
+        fetcher.fetch_next_page(&onedrive).await?
+    } {
+        metrics::API_REQUESTS.inc();
+        metrics::record_api_success();
         total_changes += changes.len();
         page += 1;
 
@@ -156,7 +292,12 @@ async fn fetch_changes(
         ret.extend(
             changes
                 .into_iter()
-                .filter(|item| seen_ids.insert(item.id.clone().unwrap())),
+                // Items without id are kept to be reported and skipped when syncing.
+                .filter(|item| {
+                    item.id
+                        .as_ref()
+                        .is_none_or(|id| seen_ids.insert(id.clone()))
+                }),
         );
 
         if page >= 2 {